@@ -0,0 +1,273 @@
+use crate::auth::{CurrentUser, Session, SESSION_COOKIE};
+use crate::csrf;
+use crate::logging::request_id;
+use crate::spam::{Challenge, FormFields, Verdict};
+use crate::template::AppError;
+use crate::AppState;
+use askama::Template;
+use axum::extract::{Form, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
+use serde::Deserialize;
+use service::accounts::{AccountError, SESSION_LIFETIME};
+use service::audit::AuditContext;
+use service::data_access::DataAccess;
+use service::data_transfer_objects::{Permission, UserDTO};
+
+#[derive(Template)]
+#[template(path = "./login.html")]
+struct LoginTemplate<'a> {
+    csrf_token: &'a str,
+    next: &'a str,
+    username: &'a str,
+    error: Option<&'a str>,
+    session: Option<&'a Session>,
+}
+
+#[derive(Template)]
+#[template(path = "./register.html")]
+struct RegisterTemplate<'a> {
+    /// false when `features.registration` is switched off
+    open: bool,
+    csrf_token: &'a str,
+    challenge: &'a Challenge,
+    next: &'a str,
+    username: &'a str,
+    error: Option<String>,
+    session: Option<&'a Session>,
+}
+
+#[derive(Deserialize)]
+pub struct NextParams {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AccountForm {
+    username: String,
+    password: String,
+    #[serde(default)]
+    next: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterForm {
+    username: String,
+    password: String,
+    #[serde(default)]
+    next: String,
+    #[serde(default)]
+    form_token: String,
+    #[serde(default)]
+    answer: String,
+    /// the honeypot, see `spam`
+    #[serde(default)]
+    website: String,
+}
+
+#[axum::debug_handler]
+pub async fn get_login(
+    state: State<AppState>,
+    CurrentUser(session): CurrentUser,
+    jar: CookieJar,
+    Query(params): Query<NextParams>,
+) -> Result<Response, AppError> {
+    let secure = state.public_url.secure_cookies();
+    let (jar, csrf_token) = csrf::pre_session_token(jar, session.as_ref(), secure);
+    let login_template = LoginTemplate {
+        csrf_token: &csrf_token,
+        next: &safe_next(params.next.as_deref()),
+        username: "",
+        error: None,
+        session: session.as_ref(),
+    };
+
+    Ok((jar, Html(login_template.render()?)).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn post_login(
+    state: State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<AccountForm>,
+) -> Result<Response, AppError> {
+    let next = safe_next(Some(&form.next));
+
+    let Some(user) =
+        DataAccess::authenticate_user(&state.db_conn, form.username.trim(), &form.password).await?
+    else {
+        let secure = state.public_url.secure_cookies();
+        let (jar, csrf_token) = csrf::pre_session_token(jar, None, secure);
+        let login_template = LoginTemplate {
+            csrf_token: &csrf_token,
+            next: &next,
+            username: &form.username,
+            error: Some("Wrong username or password"),
+            session: None,
+        };
+
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            jar,
+            Html(login_template.render()?),
+        )
+            .into_response());
+    };
+
+    sign_in(&state, jar, &user, &next).await
+}
+
+#[axum::debug_handler]
+pub async fn get_register(
+    state: State<AppState>,
+    CurrentUser(session): CurrentUser,
+    jar: CookieJar,
+    Query(params): Query<NextParams>,
+) -> Result<Response, AppError> {
+    let next = safe_next(params.next.as_deref());
+
+    Ok(render_register(&state, jar, session.as_ref(), &next, "", None)?.into_response())
+}
+
+#[axum::debug_handler]
+pub async fn post_register(
+    state: State<AppState>,
+    headers: HeaderMap,
+    CurrentUser(session): CurrentUser,
+    jar: CookieJar,
+    Form(form): Form<RegisterForm>,
+) -> Result<Response, AppError> {
+    let next = safe_next(Some(&form.next));
+
+    if !state.features.registration {
+        let page = render_register(&state, jar, session.as_ref(), &next, "", None)?;
+        return Ok((StatusCode::FORBIDDEN, page).into_response());
+    }
+
+    // verify_csrf has already checked the form sent this token back
+    let secure = state.public_url.secure_cookies();
+    let (_, csrf_token) = csrf::pre_session_token(jar.clone(), session.as_ref(), secure);
+    let fields = FormFields {
+        form_token: &form.form_token,
+        answer: &form.answer,
+        honeypot: &form.website,
+    };
+
+    let refused = match state.spam_guard.check_form(&csrf_token, &fields) {
+        Verdict::Pass => None,
+        Verdict::Retry(message) => Some(message),
+        Verdict::Spam(reasons) => {
+            tracing::warn!(
+                "Spam checks refused a registration as {}: {}",
+                form.username,
+                reasons.join("; ")
+            );
+            Some("This looks automated, so the account wasn't created.")
+        }
+    };
+
+    if let Some(message) = refused {
+        let page = render_register(
+            &state,
+            jar,
+            session.as_ref(),
+            &next,
+            &form.username,
+            Some(message.to_owned()),
+        )?;
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+    }
+
+    let username = form.username.trim().to_owned();
+
+    // whoever registers is the one creating the account
+    let audit = AuditContext::new(format!("user:{}", username), request_id(&headers));
+
+    match DataAccess::register_user(&state.db_conn, &audit, username, form.password).await {
+        Ok(user) => sign_in(&state, jar, &user, &next).await,
+        Err(
+            e @ (AccountError::InvalidUsername
+            | AccountError::WeakPassword
+            | AccountError::UsernameTaken),
+        ) => {
+            let page = render_register(
+                &state,
+                jar,
+                session.as_ref(),
+                &next,
+                &form.username,
+                Some(e.to_string()),
+            )?;
+            Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// every render asks a new question, tied to the form's CSRF token so it is
+// only good in the browser it was served to
+fn render_register(
+    state: &AppState,
+    jar: CookieJar,
+    session: Option<&Session>,
+    next: &str,
+    username: &str,
+    error: Option<String>,
+) -> Result<(CookieJar, Html<String>), AppError> {
+    let secure = state.public_url.secure_cookies();
+    let (jar, csrf_token) = csrf::pre_session_token(jar, session, secure);
+    let challenge = state.spam_guard.challenge(&csrf_token);
+
+    let register_template = RegisterTemplate {
+        open: state.features.registration,
+        csrf_token: &csrf_token,
+        challenge: &challenge,
+        next,
+        username,
+        error,
+        session,
+    };
+
+    Ok((jar, Html(register_template.render()?)))
+}
+
+#[axum::debug_handler]
+pub async fn post_logout(state: State<AppState>, jar: CookieJar) -> Result<Response, AppError> {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        DataAccess::delete_session(&state.db_conn, cookie.value()).await?;
+    }
+
+    let jar = jar.remove(Cookie::build(SESSION_COOKIE).path("/"));
+
+    Ok((jar, Redirect::to("/quotes")).into_response())
+}
+
+async fn sign_in(
+    state: &AppState,
+    jar: CookieJar,
+    user: &UserDTO,
+    next: &str,
+) -> Result<Response, AppError> {
+    let token = DataAccess::create_session(&state.db_conn, user.id).await?;
+
+    let cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(state.public_url.secure_cookies())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(SESSION_LIFETIME.num_seconds()));
+
+    Ok((jar.add(cookie), Redirect::to(next)).into_response())
+}
+
+// only follow redirects back into this site
+fn safe_next(next: Option<&str>) -> String {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') => {
+            next.to_owned()
+        }
+        _ => "/quotes".to_owned(),
+    }
+}
@@ -0,0 +1,316 @@
+use crate::permission::{Authorized, ViewAuditLog};
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use service::data_access::DataAccess;
+use service::data_transfer_objects::AuditEntryDTO;
+use utoipa::IntoParams;
+
+#[derive(Deserialize, Serialize, IntoParams)]
+pub struct AuditParams {
+    /// Only entries for this kind of entity: quote, author, tag, user or api_key
+    pub entity: Option<String>,
+    /// Only entries by this actor, e.g. `user:alice`, `api_key:3` or `cli`
+    pub actor: Option<String>,
+    /// Only entries at or after this RFC 3339 timestamp or `YYYY-MM-DD` date
+    pub since: Option<String>,
+    /// At most this many entries
+    pub limit: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/audit",
+    params(AuditParams),
+    responses(
+        (status = 200, description = "Audit entries, oldest first", body = [AuditEntryDTO]),
+        (status = 400, description = "Unreadable `since`"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_audit_log(
+    state: State<AppState>,
+    _authorized: Authorized<ViewAuditLog>,
+    Query(params): Query<AuditParams>,
+) -> Response {
+    match find_entries(&state, params).await {
+        Ok(entries) => (StatusCode::OK, Json(json!(entries))).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/audit.ndjson",
+    params(AuditParams),
+    responses(
+        (status = 200, description = "Audit entries as newline delimited JSON, oldest first", body = String, content_type = "application/x-ndjson"),
+        (status = 400, description = "Unreadable `since`"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_audit_log_ndjson(
+    state: State<AppState>,
+    _authorized: Authorized<ViewAuditLog>,
+    Query(params): Query<AuditParams>,
+) -> Response {
+    let entries = match find_entries(&state, params).await {
+        Ok(entries) => entries,
+        Err(response) => return response,
+    };
+
+    let mut body = String::new();
+    for entry in entries {
+        body.push_str(&json!(entry).to_string());
+        body.push('\n');
+    }
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        body,
+    )
+        .into_response()
+}
+
+async fn find_entries(
+    state: &AppState,
+    params: AuditParams,
+) -> Result<Vec<AuditEntryDTO>, Response> {
+    // `?entity=&actor=` means no filter rather than an empty name
+    let non_empty = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+
+    let since = match non_empty(params.since) {
+        Some(since) => Some(parse_since(&since).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Can't read '{}' as a date or time", since) })),
+            )
+                .into_response()
+        })?),
+        None => None,
+    };
+
+    DataAccess::get_audit_log(
+        &state.db_conn,
+        non_empty(params.entity),
+        non_empty(params.actor),
+        since,
+        params.limit,
+    )
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        )
+            .into_response()
+    })
+}
+
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Some(time.with_timezone(&Utc));
+    }
+
+    NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::{RateLimiter, RateLimits};
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::{header, StatusCode};
+    use axum::Router;
+    use serde_json::Value;
+    use service::audit::AuditContext;
+    use service::data_access::DataAccess;
+    use service::data_transfer_objects::{QuoteCreateDTO, QuoteStatus, Scope};
+    use tower::ServiceExt;
+
+    // an admin key (audited as `cli`) and a quote by alice, which also
+    // creates its author
+    async fn app() -> (Router, String) {
+        let state = crate::test_state().await;
+        let db = state.db_conn.clone();
+
+        let (_, key) = DataAccess::create_api_key(
+            &db,
+            &AuditContext::cli(),
+            "audit".to_owned(),
+            vec![Scope::Admin],
+        )
+        .await
+        .unwrap();
+        DataAccess::create_quote(
+            &db,
+            &AuditContext::new("user:alice", Some("req-1".to_owned())),
+            QuoteCreateDTO {
+                quote: "Simplicity is the ultimate sophistication.".to_owned(),
+                related_tags: Vec::new(),
+                author_name: "Leonardo da Vinci".to_owned(),
+                source: None,
+                submitted_by: None,
+                status: QuoteStatus::Approved,
+                flagged_reason: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let budget = "1000/min".parse().unwrap();
+        let limiter = RateLimiter::new(
+            RateLimits {
+                reads: budget,
+                writes: budget,
+            },
+            Vec::new(),
+        );
+
+        (
+            crate::json_router(state.clone(), limiter).with_state(state),
+            key,
+        )
+    }
+
+    async fn get(app: &Router, key: &str, uri: &str) -> (StatusCode, Option<String>, String) {
+        let request = Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_owned());
+        let bytes = axum::body::to_bytes(response.into_body(), 1 << 20)
+            .await
+            .unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(bytes.to_vec()).unwrap(),
+        )
+    }
+
+    async fn entries(app: &Router, key: &str, query: &str) -> Vec<(String, String)> {
+        let (status, _, body) = get(app, key, &format!("/audit?{}", query)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let entries: Vec<Value> = serde_json::from_str(&body).unwrap();
+        entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["entity"].as_str().unwrap().to_owned(),
+                    entry["actor"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(entity, actor)| (entity.to_string(), actor.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn entries_filter_by_entity_and_actor() {
+        let (app, key) = app().await;
+
+        assert_eq!(
+            entries(&app, &key, "").await,
+            pairs(&[
+                ("api_key", "cli"),
+                ("author", "user:alice"),
+                ("quote", "user:alice")
+            ])
+        );
+        assert_eq!(
+            entries(&app, &key, "entity=quote").await,
+            pairs(&[("quote", "user:alice")])
+        );
+        assert_eq!(
+            entries(&app, &key, "actor=user:alice").await,
+            pairs(&[("author", "user:alice"), ("quote", "user:alice")])
+        );
+        assert_eq!(
+            entries(&app, &key, "entity=api_key&actor=user:alice").await,
+            Vec::new()
+        );
+        // empty values are no filter at all
+        assert_eq!(entries(&app, &key, "entity=&actor=").await.len(), 3);
+        assert_eq!(
+            entries(&app, &key, "limit=1").await,
+            pairs(&[("api_key", "cli")])
+        );
+    }
+
+    #[tokio::test]
+    async fn since_takes_a_date_or_a_timestamp() {
+        let (app, key) = app().await;
+
+        assert_eq!(entries(&app, &key, "since=2000-01-01").await.len(), 3);
+        assert_eq!(
+            entries(&app, &key, "since=2000-01-01T00:00:00%2B02:00")
+                .await
+                .len(),
+            3
+        );
+        assert!(entries(&app, &key, "since=9999-12-31").await.is_empty());
+
+        let (status, _, _) = get(&app, &key, "/audit?since=yesterday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn ndjson_has_one_entry_per_line() {
+        let (app, key) = app().await;
+
+        let (status, content_type, body) = get(&app, &key, "/audit.ndjson?actor=user:alice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("application/x-ndjson"));
+        assert!(body.ends_with('\n'));
+
+        let lines: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["entity"], "quote");
+        assert_eq!(lines[1]["action"], "create");
+        assert_eq!(lines[1]["request_id"], "req-1");
+        assert_eq!(
+            lines[1]["after"]["quote"],
+            "Simplicity is the ultimate sophistication."
+        );
+        assert!(lines[1]["before"].is_null());
+    }
+
+    #[tokio::test]
+    async fn the_log_needs_a_key_that_may_read_it() {
+        let (app, _) = app().await;
+
+        let (status, _, _) = get(&app, "qs_not_a_key", "/audit").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
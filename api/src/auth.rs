@@ -0,0 +1,151 @@
+use crate::csrf;
+use crate::logging;
+use crate::permission::Actor;
+use crate::template::AppError;
+use crate::AppState;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde_json::json;
+use service::data_access::DataAccess;
+use service::data_transfer_objects::{Permission, QuoteDTO, Scope, UserDTO};
+
+/// Authenticates the `Authorization: Bearer` key when one is sent, and
+/// requires one with the `write` scope for every method that isn't safe.
+///
+/// The authenticated key is stored in the request extensions as an
+/// `ApiKeyDTO` for handlers that care who is calling.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = match bearer_token(request.headers()) {
+        Some(token) => match DataAccess::authenticate_api_key(&state.db_conn, token).await {
+            Ok(Some(key)) => Some(key),
+            Ok(None) => return unauthorized("Invalid or revoked API key"),
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": format!("Internal error: {}", e) })),
+                )
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    if let Some(key) = &key {
+        logging::record_actor(&Actor::ApiKey(key.clone()));
+    }
+
+    if let Some(scope) = required_scope(request.method()) {
+        match &key {
+            None => return unauthorized("An API key is required"),
+            Some(key) if !key.allows(scope) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({
+                        "error": format!("This API key lacks the '{}' scope", scope.as_str())
+                    })),
+                )
+                    .into_response()
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(key) = key {
+        request.extensions_mut().insert(key);
+    }
+
+    next.run(request).await
+}
+
+fn required_scope(method: &Method) -> Option<Scope> {
+    if method.is_safe() {
+        None
+    } else {
+        Some(Scope::Write)
+    }
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response =
+        (StatusCode::UNAUTHORIZED, Json(json!({ "error": message }))).into_response();
+
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Bearer realm=\"quote-server\""),
+    );
+
+    response
+}
+
+/// Name of the cookie carrying the login session token.
+pub const SESSION_COOKIE: &str = "session";
+
+/// A signed-in user of the html interface, with the token their forms must
+/// send back (see `csrf`).
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user: UserDTO,
+    pub csrf_token: String,
+}
+
+impl Session {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.user.role.allows(permission)
+    }
+
+    pub fn can_edit(&self, quote: &QuoteDTO) -> bool {
+        quote.editable_by(self.user.role, Some(self.user.id))
+    }
+}
+
+/// The session of the user signed in to the html interface, if any.
+pub struct CurrentUser(pub Option<Session>);
+
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+
+        let Some(cookie) = jar.get(SESSION_COOKIE) else {
+            return Ok(CurrentUser(None));
+        };
+
+        let session = DataAccess::get_session_user(&state.db_conn, cookie.value())
+            .await?
+            .map(|user| Session {
+                user,
+                csrf_token: csrf::token_for(cookie.value()),
+            });
+
+        Ok(CurrentUser(session))
+    }
+}
+
+pub(crate) fn login_redirect(next: &str) -> Redirect {
+    match serde_urlencoded::to_string([("next", next)]) {
+        Ok(query) => Redirect::to(&format!("/login?{}", query)),
+        Err(_) => Redirect::to("/login"),
+    }
+}
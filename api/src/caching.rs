@@ -0,0 +1,413 @@
+use crate::auth::SESSION_COOKIE;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/*
+Conditional requests. A cached read route hashes the body it is about to send
+into a strong ETag, so the tag changes with the format, the page and anything
+else that changes the bytes. A matching `If-None-Match`, or an
+`If-Modified-Since` no older than the response's `Last-Modified`, gets a 304
+without the body. Handlers only set `Last-Modified` where they know it: a
+list's newest `updated_at` says nothing about a quote deleted from it.
+
+Writes to a quote take `If-Match` with the ETag of its JSON representation
+and answer 412 when the quote has changed since, so two editors can't
+overwrite each other.
+*/
+
+pub(crate) const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// How long a route's responses may be reused without asking again.
+#[derive(Clone, Copy, Debug)]
+pub enum CachePolicy {
+    /// shared caches may keep anonymous responses for this many seconds
+    Public(u32),
+    /// never stored: moderation queues, user lists and the like
+    NoStore,
+}
+
+impl CachePolicy {
+    // a signed in user's pages carry their name and CSRF token, so only
+    // their own browser may keep them, and it has to check back every time
+    pub(crate) fn header_value(self, signed_in: bool) -> HeaderValue {
+        match self {
+            CachePolicy::Public(_) if signed_in => HeaderValue::from_static("private, no-cache"),
+            CachePolicy::Public(max_age) => {
+                HeaderValue::from_str(&format!("public, max-age={}", max_age))
+                    .unwrap_or(HeaderValue::from_static("no-cache"))
+            }
+            CachePolicy::NoStore => HeaderValue::from_static("no-store"),
+        }
+    }
+}
+
+/// A strong ETag for a response body.
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(body))
+}
+
+/// The ETag of `value` sent as JSON, the same one a GET for it carries.
+pub fn json_etag<T: Serialize>(value: &T) -> String {
+    etag(json!(value).to_string().as_bytes())
+}
+
+/// Whether a GET can be answered with 304. `If-None-Match` wins over
+/// `If-Modified-Since` when a client sends both.
+pub fn is_not_modified(
+    headers: &HeaderMap,
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+    {
+        // GETs compare weakly, so a tag a proxy marked W/ still counts
+        return if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim().trim_start_matches("W/"))
+            .any(|candidate| candidate == "*" || candidate == etag);
+    }
+
+    let Some(last_modified) = last_modified else {
+        return false;
+    };
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp())
+}
+
+/// Whether a write's `If-Match` precondition holds for a resource whose
+/// current ETag is `etag`. No header means the client didn't ask.
+pub fn if_match(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(if_match) = headers.get(header::IF_MATCH) else {
+        return true;
+    };
+    let Ok(if_match) = if_match.to_str() else {
+        return false;
+    };
+
+    // writes compare strongly: a weak tag never matches
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+/// Sets the caching headers on a response that already has its body.
+pub fn set_headers(
+    response: &mut Response,
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+    cache_control: HeaderValue,
+) {
+    let headers = response.headers_mut();
+
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, value);
+    }
+    if let Some(last_modified) = last_modified {
+        if let Ok(value) = HeaderValue::from_str(&last_modified.format(HTTP_DATE).to_string()) {
+            headers.insert(header::LAST_MODIFIED, value);
+        }
+    }
+    headers.insert(header::CACHE_CONTROL, cache_control);
+}
+
+/// Middleware for a cached read route: tags successful GETs and answers
+/// conditional ones with 304.
+pub async fn conditional_get(
+    State(policy): State<CachePolicy>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+
+    let request_headers = request.headers().clone();
+    let signed_in = is_signed_in(&request_headers);
+
+    let response = next.run(request).await;
+    let cache_control = policy.header_value(signed_in);
+
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("couldn't read response body: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let etag = etag(&body);
+    let last_modified = parts
+        .headers
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .map(|value| value.with_timezone(&Utc));
+
+    // the same page differs between anonymous and signed in visitors
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("cookie"));
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("authorization"));
+
+    let mut response = if is_not_modified(&request_headers, &etag, last_modified) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Body::empty())
+    } else {
+        Response::from_parts(parts, Body::from(body))
+    };
+
+    set_headers(&mut response, &etag, last_modified, cache_control);
+    response
+}
+
+/// Marks a response as not to be stored anywhere.
+pub async fn no_store(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        CachePolicy::NoStore.header_value(false),
+    );
+    response
+}
+
+fn is_signed_in(headers: &HeaderMap) -> bool {
+    let has_session = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .any(|cookie| {
+            cookie
+                .trim()
+                .split_once('=')
+                .is_some_and(|(name, _)| name == SESSION_COOKIE)
+        });
+
+    has_session || headers.contains_key(header::AUTHORIZATION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::{RateLimiter, RateLimits};
+    use axum::Router;
+    use chrono::TimeZone;
+    use service::audit::AuditContext;
+    use service::data_access::DataAccess;
+    use service::data_transfer_objects::{QuoteCreateDTO, QuoteStatus};
+    use tower::ServiceExt;
+
+    const TAG: &str = "\"abc\"";
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 19, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn etags_are_quoted_hashes_of_the_bytes() {
+        let tag = etag(b"hello");
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(tag.len(), 64 + 2);
+        assert_eq!(tag, etag(b"hello"));
+        assert_ne!(tag, etag(b"hello "));
+
+        // the same tag a GET of the JSON would carry
+        let value = json!({ "id": 1, "quote": "Be kind." });
+        assert_eq!(json_etag(&value), etag(value.to_string().as_bytes()));
+    }
+
+    #[test]
+    fn if_none_match_takes_lists_weak_tags_and_stars() {
+        let matches =
+            |value: &str| is_not_modified(&headers(&[(header::IF_NONE_MATCH, value)]), TAG, None);
+
+        assert!(matches(TAG));
+        assert!(matches("\"other\", \"abc\""));
+        assert!(matches("W/\"abc\""));
+        assert!(matches("*"));
+        assert!(!matches("\"other\""));
+        assert!(!matches("abc"));
+
+        // no validators at all
+        assert!(!is_not_modified(&HeaderMap::new(), TAG, Some(noon())));
+    }
+
+    #[test]
+    fn if_modified_since_counts_only_without_if_none_match() {
+        let since = |value: &str| headers(&[(header::IF_MODIFIED_SINCE, value)]);
+
+        assert!(is_not_modified(
+            &since("Mon, 19 Oct 2026 12:00:00 GMT"),
+            TAG,
+            Some(noon())
+        ));
+        assert!(!is_not_modified(
+            &since("Mon, 19 Oct 2026 11:59:59 GMT"),
+            TAG,
+            Some(noon())
+        ));
+        assert!(!is_not_modified(&since("yesterday"), TAG, Some(noon())));
+        // a response without Last-Modified can't be compared by date
+        assert!(!is_not_modified(
+            &since("Mon, 19 Oct 2026 12:00:00 GMT"),
+            TAG,
+            None
+        ));
+
+        // If-None-Match decides when both are sent
+        let both = headers(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, "Mon, 19 Oct 2026 12:00:00 GMT"),
+        ]);
+        assert!(!is_not_modified(&both, TAG, Some(noon())));
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let holds = |value: &str| if_match(&headers(&[(header::IF_MATCH, value)]), TAG);
+
+        assert!(if_match(&HeaderMap::new(), TAG));
+        assert!(holds(TAG));
+        assert!(holds("\"other\", \"abc\""));
+        assert!(holds("*"));
+        assert!(!holds("W/\"abc\""));
+        assert!(!holds("\"other\""));
+    }
+
+    #[test]
+    fn signed_in_pages_are_private() {
+        let public = CachePolicy::Public(60);
+        assert_eq!(public.header_value(false), "public, max-age=60");
+        assert_eq!(public.header_value(true), "private, no-cache");
+        assert_eq!(CachePolicy::NoStore.header_value(false), "no-store");
+        assert_eq!(CachePolicy::NoStore.header_value(true), "no-store");
+
+        assert!(!is_signed_in(&headers(&[(header::COOKIE, "theme=dark")])));
+        assert!(!is_signed_in(&headers(&[(
+            header::COOKIE,
+            "not_session=1"
+        )])));
+        assert!(is_signed_in(&headers(&[(
+            header::COOKIE,
+            "theme=dark; session=abc"
+        )])));
+        assert!(is_signed_in(&headers(&[(
+            header::AUTHORIZATION,
+            "Bearer key"
+        )])));
+    }
+
+    // the html routes, a quote, and a session cookie for a signed in user
+    async fn app() -> (Router, i32, String) {
+        let state = crate::test_state().await;
+        let db = state.db_conn.clone();
+        let ctx = AuditContext::cli();
+
+        let quote = DataAccess::create_quote(
+            &db,
+            &ctx,
+            QuoteCreateDTO {
+                quote: "Well done is better than well said.".to_owned(),
+                related_tags: Vec::new(),
+                author_name: "Benjamin Franklin".to_owned(),
+                source: None,
+                submitted_by: None,
+                status: QuoteStatus::Approved,
+                flagged_reason: None,
+            },
+        )
+        .await
+        .unwrap();
+        let user =
+            DataAccess::register_user(&db, &ctx, "ada".to_owned(), "correct horse".to_owned())
+                .await
+                .unwrap();
+        let session = DataAccess::create_session(&db, user.id).await.unwrap();
+
+        let budget = "1000/min".parse().unwrap();
+        let limiter = RateLimiter::new(
+            RateLimits {
+                reads: budget,
+                writes: budget,
+            },
+            Vec::new(),
+        );
+
+        (
+            crate::template_router(state.clone(), limiter).with_state(state),
+            quote.id,
+            session,
+        )
+    }
+
+    async fn get(app: &Router, uri: &str, pairs: &[(header::HeaderName, &str)]) -> Response {
+        let mut request = Request::get(uri).body(Body::empty()).unwrap();
+        *request.headers_mut() = headers(pairs);
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    fn header_of(response: &Response, name: header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_matching_etag_gets_304_and_a_session_makes_it_private() {
+        let (app, quote_id, session) = app().await;
+        let uri = format!("/quotes/{}", quote_id);
+
+        let first = get(&app, &uri, &[]).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(
+            header_of(&first, header::CACHE_CONTROL),
+            "public, max-age=300"
+        );
+        let tag = header_of(&first, header::ETAG).to_owned();
+
+        let again = get(&app, &uri, &[(header::IF_NONE_MATCH, &tag)]).await;
+        assert_eq!(again.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header_of(&again, header::ETAG), tag);
+        let body = axum::body::to_bytes(again.into_body(), 1 << 20)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+
+        let cookie = format!("session={}", session);
+        let signed_in = get(&app, &uri, &[(header::COOKIE, &cookie)]).await;
+        assert_eq!(signed_in.status(), StatusCode::OK);
+        assert_eq!(
+            header_of(&signed_in, header::CACHE_CONTROL),
+            "private, no-cache"
+        );
+        // the page names who is signed in, so it isn't the anonymous copy
+        assert_ne!(header_of(&signed_in, header::ETAG), tag);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::auth::{Session, SESSION_COOKIE};
+use crate::template::AppError;
+use crate::AppState;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
+use service::data_access::DataAccess;
+use sha2::{Digest, Sha256};
+
+/*
+Html forms can only GET or POST, and a signed-in browser sends its session
+cookie with any form another site makes it submit.
+
+- `method_override` turns a POST carrying `_method=DELETE` (or PUT, PATCH)
+  into that method. It has to wrap the whole app, since routing picks the
+  handler by method.
+- `verify_csrf` rejects unsafe requests made with a live session unless they
+  send the session's token back, as the `_csrf` form field or the
+  `X-CSRF-Token` header. Templates embed the token from `auth::Session`.
+
+Requests without a session aren't checked; they have no one to act as. The
+exception is the login and registration forms: another site could otherwise
+sign the browser in to an account of its choosing and watch what is done
+there. Before there is a session, those forms are tied to the browser by a
+random `pre_session` cookie, which `pre_session_token` hands out.
+*/
+
+/// Form field carrying the token.
+pub const CSRF_FIELD: &str = "_csrf";
+/// Header carrying the token, for scripts.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Form field naming the method a POST stands in for.
+pub const METHOD_FIELD: &str = "_method";
+/// Cookie the login and registration forms' token is derived from until the
+/// browser has a session.
+pub const PRE_SESSION_COOKIE: &str = "pre_session";
+
+// forms checked even without a session
+const PRE_SESSION_PATHS: [&str; 2] = ["/login", "/register"];
+
+// same as axum's default body limit
+const FORM_LIMIT: usize = 2 * 1024 * 1024;
+
+/// The CSRF token for a session. It is derived from the session cookie, so it
+/// changes whenever the user signs in again and needs no storage.
+pub fn token_for(session_token: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("csrf:{}", session_token)))
+}
+
+/// The token for the login and registration forms. That's the session's when
+/// there is one, otherwise it comes from the pre-session cookie, which is
+/// added to `jar` if the browser doesn't have one yet.
+pub fn pre_session_token(
+    jar: CookieJar,
+    session: Option<&Session>,
+    secure: bool,
+) -> (CookieJar, String) {
+    if let Some(session) = session {
+        return (jar, session.csrf_token.clone());
+    }
+
+    if let Some(cookie) = jar.get(PRE_SESSION_COOKIE) {
+        let token = token_for(cookie.value());
+        return (jar, token);
+    }
+
+    let bytes: [u8; 32] = rand::random();
+    let value: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let token = token_for(&value);
+
+    let cookie = Cookie::build((PRE_SESSION_COOKIE, value))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax);
+
+    (jar.add(cookie), token)
+}
+
+pub async fn method_override(request: Request, next: Next) -> Response {
+    if request.method() != Method::POST || !is_form(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, FORM_LIMIT).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+
+    let method = form_field(&bytes, METHOD_FIELD).and_then(|method| {
+        match method.to_ascii_uppercase().as_str() {
+            "DELETE" => Some(Method::DELETE),
+            "PUT" => Some(Method::PUT),
+            "PATCH" => Some(Method::PATCH),
+            _ => None,
+        }
+    });
+
+    if let Some(method) = method {
+        parts.method = method;
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+pub async fn verify_csrf(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+
+    let jar = CookieJar::from_headers(request.headers());
+    let session_token = match jar.get(SESSION_COOKIE) {
+        Some(cookie) => match DataAccess::get_session_user(&state.db_conn, cookie.value()).await {
+            Ok(Some(_)) => Some(cookie.value().to_owned()),
+            // an expired or unknown cookie means the request is anonymous
+            Ok(None) => None,
+            Err(e) => return AppError::Database(e).into_response(),
+        },
+        None => None,
+    };
+
+    let expected = match session_token {
+        Some(session_token) => Some(token_for(&session_token)),
+        None if PRE_SESSION_PATHS.contains(&request.uri().path()) => jar
+            .get(PRE_SESSION_COOKIE)
+            .map(|cookie| token_for(cookie.value())),
+        None => return next.run(request).await,
+    };
+
+    let header_token = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    let (request, submitted) = match header_token {
+        Some(token) => (request, Some(token)),
+        None if is_form(request.headers()) => {
+            let (parts, body) = request.into_parts();
+            let bytes = match to_bytes(body, FORM_LIMIT).await {
+                Ok(bytes) => bytes,
+                Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            };
+            let token = form_field(&bytes, CSRF_FIELD);
+            (Request::from_parts(parts, Body::from(bytes)), token)
+        }
+        None => (request, None),
+    };
+
+    match submitted {
+        Some(token)
+            if expected.as_ref().is_some_and(|expected| {
+                constant_time_eq(token.as_bytes(), expected.as_bytes())
+            }) =>
+        {
+            next.run(request).await
+        }
+        _ => {
+            tracing::warn!(
+                "Rejected {} {} without a valid CSRF token",
+                request.method(),
+                request.uri()
+            );
+            AppError::Forbidden.into_response()
+        }
+    }
+}
+
+fn is_form(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+}
+
+fn form_field(body: &Bytes, name: &str) -> Option<String> {
+    serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+        .ok()?
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::{delete, post};
+    use axum::{middleware, Router};
+    use service::audit::AuditContext;
+    use tower::{Layer, ServiceExt};
+
+    async fn app() -> (Router, AppState) {
+        let state = crate::test_state().await;
+        let router = Router::new()
+            .route("/login", post(|| async { "signed in" }))
+            .route("/quotes", post(|| async { "created" }))
+            .layer(middleware::from_fn_with_state(state.clone(), verify_csrf));
+
+        (router, state)
+    }
+
+    fn form(path: &str, cookie: Option<String>, body: &str) -> Request {
+        let mut request = Request::post(path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        if let Some(cookie) = cookie {
+            request
+                .headers_mut()
+                .insert(header::COOKIE, cookie.parse().unwrap());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn anonymous_writes_pass_except_the_login_forms() {
+        let (app, _) = app().await;
+
+        let response = app
+            .clone()
+            .oneshot(form("/quotes", None, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(form("/login", None, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn the_login_form_needs_the_token_of_its_pre_session_cookie() {
+        let (app, _) = app().await;
+        let cookie = Some(format!("{}=abc", PRE_SESSION_COOKIE));
+
+        let body = format!("{}={}", CSRF_FIELD, token_for("abc"));
+        let response = app
+            .clone()
+            .oneshot(form("/login", cookie.clone(), &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = format!("{}={}", CSRF_FIELD, token_for("someone else's"));
+        let response = app.oneshot(form("/login", cookie, &body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn pre_session_tokens_come_from_the_cookie_they_set() {
+        let (jar, token) = pre_session_token(CookieJar::new(), None, false);
+        let cookie = jar.get(PRE_SESSION_COOKIE).unwrap();
+        assert_eq!(token, token_for(cookie.value()));
+        assert_ne!(cookie.secure(), Some(true));
+
+        // a browser that already has the cookie keeps it
+        let (_, again) = pre_session_token(jar.clone(), None, false);
+        assert_eq!(again, token);
+
+        let (jar, _) = pre_session_token(CookieJar::new(), None, true);
+        assert_eq!(jar.get(PRE_SESSION_COOKIE).unwrap().secure(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn signed_in_writes_need_the_session_token() {
+        let (app, state) = app().await;
+        let user = DataAccess::register_user(
+            &state.db_conn,
+            &AuditContext::cli(),
+            "ann".to_owned(),
+            "correct horse".to_owned(),
+        )
+        .await
+        .unwrap();
+        let session = DataAccess::create_session(&state.db_conn, user.id)
+            .await
+            .unwrap();
+        let cookie = Some(format!("{}={}", SESSION_COOKIE, session));
+
+        let response = app
+            .clone()
+            .oneshot(form("/quotes", cookie.clone(), ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut request = form("/quotes", cookie, "");
+        request
+            .headers_mut()
+            .insert(CSRF_HEADER, token_for(&session).parse().unwrap());
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn method_override_routes_a_form_post_as_the_named_method() {
+        let router = Router::new().route(
+            "/quotes/1",
+            delete(|| async { "deleted" }).post(|| async { "posted" }),
+        );
+        let app = middleware::from_fn(method_override).layer(router);
+
+        let response = app
+            .clone()
+            .oneshot(form("/quotes/1", None, "_method=delete"))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"deleted");
+
+        // only the methods a form can't send are overridden
+        let response = app
+            .oneshot(form("/quotes/1", None, "_method=GET"))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"posted");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::json;
+use crate::permission::{Authorized, ModerateQuotes};
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use service::data_access::{DataAccess, UpdateOutcome};
+use service::data_transfer_objects::{DuplicateClusterDTO, QuoteDTO};
+use utoipa::{IntoParams, ToSchema};
+
+/*
+Reviewing duplicates already in the collection. New quotes are checked when
+they are created (see service::duplicates and the `[duplicates]` settings);
+these endpoints find the ones that got in before, or past a `warn`, and fold
+each cluster into the quote a moderator picks.
+*/
+
+#[derive(Deserialize, IntoParams)]
+pub struct DuplicateParams {
+    /// similarity from which quotes count as near duplicates, 0 to 1;
+    /// defaults to the configured threshold
+    pub threshold: Option<f64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Merge {
+    /// the quote that stays
+    pub keep: i32,
+    /// the quotes folded into it and deleted
+    pub merge: Vec<i32>,
+    /// the version of `keep` the moderator saw; the merge is refused if it
+    /// has changed since. Needed unless the request has an `If-Match` header
+    /// for `keep`
+    pub version: Option<i32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/quotes/duplicates",
+    params(DuplicateParams),
+    responses(
+        (status = 200, description = "Clusters of duplicate quotes, by lowest id", body = [DuplicateClusterDTO]),
+        (status = 400, description = "Threshold isn't between 0 and 1"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_duplicates(
+    state: State<AppState>,
+    _authorized: Authorized<ModerateQuotes>,
+    Query(params): Query<DuplicateParams>,
+) -> impl IntoResponse {
+    let threshold = params.threshold.unwrap_or(state.duplicates.threshold);
+    if !(0.0..=1.0).contains(&threshold) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "threshold must be between 0 and 1" })),
+        );
+    }
+
+    match DataAccess::get_duplicate_clusters(&state.db_conn, threshold).await {
+        Ok(clusters) => (StatusCode::OK, Json(json!(clusters))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/quotes/duplicates/merge",
+    request_body = Merge,
+    responses(
+        (status = 200, description = "The kept quote, with the tags of the merged ones", body = QuoteDTO),
+        (status = 400, description = "Nothing to merge"),
+        (status = 404, description = "One of the quotes doesn't exist"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 409, description = "The kept quote has a newer version, returned as `current`"),
+        (status = 412, description = "If-Match doesn't match the kept quote's current ETag"),
+        (status = 428, description = "Neither a version nor If-Match was sent"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn merge_duplicates(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+    headers: HeaderMap,
+    Json(merge): Json<Merge>,
+) -> impl IntoResponse {
+    if merge.merge.iter().all(|&id| id == merge.keep) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "merge needs at least one quote other than keep" })),
+        );
+    }
+
+    let version = match json::check_unchanged(&state, merge.keep, &headers, |_| true)
+        .await
+        .and_then(|quote| json::expected_version(&quote, merge.version, &headers))
+    {
+        Ok(version) => version,
+        Err(refused) => return refused,
+    };
+
+    match DataAccess::merge_quotes(
+        &state.db_conn,
+        &authorized.audit(),
+        merge.keep,
+        &merge.merge,
+        version,
+    )
+    .await
+    {
+        Ok(Some(UpdateOutcome::Updated(quote_dto))) => (StatusCode::OK, Json(json!(quote_dto))),
+        Ok(Some(UpdateOutcome::Conflict(current))) => json::conflict(&current),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Quote not found" })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        ),
+    }
+}
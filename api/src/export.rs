@@ -0,0 +1,119 @@
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use service::data_access::DataAccess;
+use service::{anki, fortune};
+use utoipa::IntoParams;
+
+#[derive(Deserialize, Serialize, IntoParams)]
+pub struct ExportParams {
+    /// Only export quotes with this tag
+    pub tag: Option<String>,
+    /// Only export quotes by this author
+    pub author: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/export/fortune",
+    params(ExportParams),
+    responses(
+        (status = 200, description = "Quotes as a fortune(6) file", body = String, content_type = "text/plain"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_fortune_file(
+    state: State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    match DataAccess::get_quotes_filtered(&state.db_conn, params.tag, params.author).await {
+        Ok(quotes) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            fortune::render_fortunes(&quotes),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/export/fortune.dat",
+    params(ExportParams),
+    responses(
+        (status = 200, description = "strfile(1) index for the matching fortune file", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_fortune_index(
+    state: State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    match DataAccess::get_quotes_filtered(&state.db_conn, params.tag, params.author).await {
+        Ok(quotes) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            fortune::strfile_index(&fortune::render_fortunes(&quotes)),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/export/anki",
+    params(ExportParams),
+    responses(
+        (status = 200, description = "Anki deck with one note per quote", body = Vec<u8>, content_type = "application/apkg"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_anki_package(
+    state: State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    let deck_name = match &params.tag {
+        Some(tag) => format!("Quotes::{}", tag),
+        None => "Quotes".to_owned(),
+    };
+
+    let package =
+        match DataAccess::get_quotes_filtered(&state.db_conn, params.tag, params.author).await {
+            Ok(quotes) => anki::build_package(&quotes, &deck_name).await,
+            Err(e) => Err(e.into()),
+        };
+
+    match package {
+        Ok(package) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "application/apkg"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"quotes.apkg\"",
+                ),
+            ],
+            package,
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,404 @@
+use crate::caching::{self, CachePolicy};
+use crate::template::AppError;
+use crate::AppState;
+use askama::Template;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use service::data_access::DataAccess;
+use service::data_transfer_objects::QuoteDTO;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+// how many of the newest quotes each feed carries
+const FEED_LENGTH: u64 = 20;
+
+/// Where the server is reached from outside. The absolute links in feeds
+/// point there; feeds are cached by shared caches, so the links can't come
+/// from anything a client could make up. Over https, cookies are `Secure`.
+#[derive(Clone, Debug, Default)]
+pub struct PublicUrl {
+    // e.g. `https://quotes.example.com`, without a trailing slash
+    base: Option<String>,
+    trusted_proxies: Arc<[IpAddr]>,
+    secure_cookies: bool,
+}
+
+impl PublicUrl {
+    /// `base` is the url the server is reached at, or empty to take the host
+    /// from each request; `trusted_proxies` may say whether that was https.
+    /// `secure_cookies` marks cookies `Secure` even when `base` isn't https.
+    pub fn new(base: &str, trusted_proxies: Vec<IpAddr>, secure_cookies: bool) -> Self {
+        let base = base.trim().trim_end_matches('/');
+
+        PublicUrl {
+            base: Some(base.to_owned()).filter(|base| !base.is_empty()),
+            trusted_proxies: trusted_proxies.into(),
+            secure_cookies,
+        }
+    }
+
+    /// Whether cookies may only travel over https.
+    pub fn secure_cookies(&self) -> bool {
+        self.secure_cookies
+            || self
+                .base
+                .as_deref()
+                .is_some_and(|base| base.to_ascii_lowercase().starts_with("https://"))
+    }
+
+    fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> String {
+        if let Some(base) = &self.base {
+            return base.clone();
+        }
+
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .filter(|host| is_host(host))
+            .unwrap_or("localhost:3000");
+
+        let behind_proxy = peer.is_some_and(|peer| self.trusted_proxies.contains(&peer));
+        let scheme = headers
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| behind_proxy)
+            .filter(|scheme| matches!(*scheme, "http" | "https"))
+            .unwrap_or("http");
+
+        format!("{}://{}", scheme, host)
+    }
+}
+
+// a host name or address with an optional port, and nothing else
+fn is_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
+
+/// The scheme and host feed links start with.
+pub struct BaseUrl(String);
+
+impl FromRequestParts<AppState> for BaseUrl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip());
+
+        Ok(BaseUrl(state.public_url.resolve(peer, &parts.headers)))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "atom",
+            FeedFormat::Rss => "rss",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+}
+
+/// A path segment like `12.atom`; the router can't match a parameter followed
+/// by a static suffix so the extension is split off here.
+struct FeedFile {
+    id: i32,
+    format: FeedFormat,
+}
+
+impl FeedFile {
+    fn parse(segment: &str) -> Option<Self> {
+        let (id, extension) = segment.split_once('.')?;
+        let format = match extension {
+            "atom" => FeedFormat::Atom,
+            "rss" => FeedFormat::Rss,
+            _ => return None,
+        };
+
+        Some(FeedFile {
+            id: id.parse().ok()?,
+            format,
+        })
+    }
+}
+
+struct FeedEntry {
+    id: String,
+    title: String,
+    url: String,
+    quote: QuoteDTO,
+}
+
+#[derive(Template)]
+#[template(path = "./atom.xml")]
+struct AtomTemplate<'a> {
+    title: &'a str,
+    feed_id: &'a str,
+    self_url: &'a str,
+    alternate_url: &'a str,
+    base_url: &'a str,
+    updated: DateTime<Utc>,
+    entries: &'a [FeedEntry],
+}
+
+#[derive(Template)]
+#[template(path = "./rss.xml")]
+struct RssTemplate<'a> {
+    title: &'a str,
+    self_url: &'a str,
+    alternate_url: &'a str,
+    updated: DateTime<Utc>,
+    entries: &'a [FeedEntry],
+}
+
+struct Feed {
+    title: String,
+    feed_id: String,
+    path: String,
+    alternate_path: String,
+    quotes: Vec<QuoteDTO>,
+}
+
+#[axum::debug_handler]
+pub async fn get_quotes_atom(
+    state: State<AppState>,
+    base_url: BaseUrl,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    get_quotes_feed(state, base_url, headers, FeedFormat::Atom).await
+}
+
+#[axum::debug_handler]
+pub async fn get_quotes_rss(
+    state: State<AppState>,
+    base_url: BaseUrl,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    get_quotes_feed(state, base_url, headers, FeedFormat::Rss).await
+}
+
+async fn get_quotes_feed(
+    state: State<AppState>,
+    base_url: BaseUrl,
+    headers: HeaderMap,
+    format: FeedFormat,
+) -> Result<Response, AppError> {
+    let quotes = DataAccess::get_latest_quotes(&state.db_conn, None, None, FEED_LENGTH).await?;
+
+    let feed = Feed {
+        title: "New quotes - Sarah's Quote Server".to_owned(),
+        feed_id: "urn:quote-server:feed:quotes".to_owned(),
+        path: "/feeds/quotes".to_owned(),
+        alternate_path: "/quotes".to_owned(),
+        quotes,
+    };
+
+    render_feed(base_url, &headers, format, feed)
+}
+
+#[axum::debug_handler]
+pub async fn get_tag_feed(
+    state: State<AppState>,
+    base_url: BaseUrl,
+    headers: HeaderMap,
+    Path(file): Path<String>,
+) -> Result<Response, AppError> {
+    let file = FeedFile::parse(&file).ok_or(AppError::NotFound)?;
+    let tag = DataAccess::get_tag(&state.db_conn, file.id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let quotes =
+        DataAccess::get_latest_quotes(&state.db_conn, Some(tag.id), None, FEED_LENGTH).await?;
+
+    let feed = Feed {
+        title: format!("Quotes tagged {} - Sarah's Quote Server", tag.tag),
+        feed_id: format!("urn:quote-server:feed:tag:{}", tag.id),
+        path: format!("/feeds/tags/{}", tag.id),
+        alternate_path: format!("/tags/{}", tag.id),
+        quotes,
+    };
+
+    render_feed(base_url, &headers, file.format, feed)
+}
+
+#[axum::debug_handler]
+pub async fn get_author_feed(
+    state: State<AppState>,
+    base_url: BaseUrl,
+    headers: HeaderMap,
+    Path(file): Path<String>,
+) -> Result<Response, AppError> {
+    let file = FeedFile::parse(&file).ok_or(AppError::NotFound)?;
+    let author = DataAccess::get_author(&state.db_conn, file.id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let quotes =
+        DataAccess::get_latest_quotes(&state.db_conn, None, Some(author.id), FEED_LENGTH).await?;
+
+    let feed = Feed {
+        title: format!("Quotes by {} - Sarah's Quote Server", author.name),
+        feed_id: format!("urn:quote-server:feed:author:{}", author.id),
+        path: format!("/feeds/authors/{}", author.id),
+        alternate_path: format!("/authors/{}", author.id),
+        quotes,
+    };
+
+    render_feed(base_url, &headers, file.format, feed)
+}
+
+fn render_feed(
+    BaseUrl(base_url): BaseUrl,
+    headers: &HeaderMap,
+    format: FeedFormat,
+    feed: Feed,
+) -> Result<Response, AppError> {
+    let self_url = format!("{}{}.{}", base_url, feed.path, format.extension());
+    let alternate_url = format!("{}{}", base_url, feed.alternate_path);
+
+    let updated = feed
+        .quotes
+        .iter()
+        .map(|quote| quote.updated_at)
+        .max()
+        .unwrap_or(DateTime::UNIX_EPOCH);
+
+    let entries: Vec<FeedEntry> = feed
+        .quotes
+        .into_iter()
+        .map(|quote| FeedEntry {
+            id: format!("urn:quote-server:quote:{}", quote.id),
+            title: entry_title(&quote),
+            url: format!("{}/quotes/{}", base_url, quote.id),
+            quote,
+        })
+        .collect();
+
+    let body = match format {
+        FeedFormat::Atom => AtomTemplate {
+            title: &feed.title,
+            feed_id: &feed.feed_id,
+            self_url: &self_url,
+            alternate_url: &alternate_url,
+            base_url: &base_url,
+            updated,
+            entries: &entries,
+        }
+        .render()?,
+        FeedFormat::Rss => RssTemplate {
+            title: &feed.title,
+            self_url: &self_url,
+            alternate_url: &alternate_url,
+            updated,
+            entries: &entries,
+        }
+        .render()?,
+    };
+
+    let etag = caching::etag(body.as_bytes());
+
+    let mut response = if caching::is_not_modified(headers, &etag, Some(updated)) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        ([(header::CONTENT_TYPE, format.content_type())], body).into_response()
+    };
+
+    caching::set_headers(
+        &mut response,
+        &etag,
+        Some(updated),
+        CachePolicy::Public(300).header_value(false),
+    );
+
+    Ok(response)
+}
+
+fn entry_title(quote: &QuoteDTO) -> String {
+    let first_line = quote.quote.lines().next().unwrap_or_default();
+    let mut title: String = first_line.chars().take(80).collect();
+
+    if first_line.chars().count() > 80 || quote.quote.lines().count() > 1 {
+        title.push('…');
+    }
+
+    format!("{} — {}", title, quote.author.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(host: &str, proto: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        headers.insert("x-forwarded-proto", HeaderValue::from_str(proto).unwrap());
+        headers
+    }
+
+    #[test]
+    fn a_configured_url_ignores_the_request() {
+        let public_url = PublicUrl::new("https://quotes.example.com/", Vec::new(), false);
+
+        assert_eq!(
+            public_url.resolve(None, &headers("evil.example", "http")),
+            "https://quotes.example.com"
+        );
+    }
+
+    #[test]
+    fn forwarded_proto_is_only_believed_from_a_trusted_proxy() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let public_url = PublicUrl::new("", vec![proxy], false);
+        let headers = headers("quotes.example.com", "https");
+
+        assert_eq!(
+            public_url.resolve(Some(proxy), &headers),
+            "https://quotes.example.com"
+        );
+        assert_eq!(
+            public_url.resolve(Some("203.0.113.9".parse().unwrap()), &headers),
+            "http://quotes.example.com"
+        );
+    }
+
+    #[test]
+    fn cookies_are_secure_behind_an_https_url_or_when_asked() {
+        assert!(PublicUrl::new("HTTPS://quotes.example.com", Vec::new(), false).secure_cookies());
+        assert!(!PublicUrl::new("http://quotes.example.com", Vec::new(), false).secure_cookies());
+        assert!(!PublicUrl::new("", Vec::new(), false).secure_cookies());
+        assert!(PublicUrl::new("", Vec::new(), true).secure_cookies());
+    }
+
+    #[test]
+    fn hosts_that_could_break_out_of_a_link_are_ignored() {
+        let public_url = PublicUrl::new("", Vec::new(), false);
+
+        assert_eq!(
+            public_url.resolve(None, &headers("evil.example/\"><x", "http")),
+            "http://localhost:3000"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use migration::{Migrator, MigratorTrait};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+/*
+Probes for whatever runs the server. `/healthz` only says the process is up
+and answering, so a failing database doesn't get the process restarted;
+`/readyz` says whether it can serve requests right now.
+*/
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_COMMIT: &str = env!("GIT_COMMIT");
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub status: String,
+    /// migrations the database is still missing
+    pub pending_migrations: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct VersionResponse {
+    pub version: String,
+    pub commit: String,
+    /// the newest migration applied to the database
+    pub schema_version: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The process is up", body = HealthResponse)
+    )
+)]
+pub async fn get_healthz() -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok".to_owned(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The database is reachable and fully migrated", body = ReadinessResponse),
+        (status = 503, description = "The database is unreachable or has pending migrations", body = ReadinessResponse)
+    )
+)]
+pub async fn get_readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if let Err(e) = state.db_conn.ping().await {
+        tracing::warn!("readiness check could not reach the database: {}", e);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "unavailable", "error": "database unreachable"})),
+        );
+    }
+
+    match Migrator::get_pending_migrations(&state.db_conn).await {
+        Ok(pending) if pending.is_empty() => (
+            StatusCode::OK,
+            Json(json!(ReadinessResponse {
+                status: "ready".to_owned(),
+                pending_migrations: Vec::new(),
+            })),
+        ),
+        Ok(pending) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!(ReadinessResponse {
+                status: "unavailable".to_owned(),
+                pending_migrations: pending.iter().map(|m| m.name().to_owned()).collect(),
+            })),
+        ),
+        Err(e) => {
+            tracing::warn!("readiness check could not read migrations: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"status": "unavailable", "error": "could not read migrations"})),
+            )
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses(
+        (status = 200, description = "Build and schema version", body = VersionResponse)
+    )
+)]
+pub async fn get_version(State(state): State<AppState>) -> Json<VersionResponse> {
+    // reported as unknown rather than failing, so the build is still visible
+    // when the database isn't
+    let schema_version = match Migrator::get_applied_migrations(&state.db_conn).await {
+        Ok(applied) => applied.last().map(|m| m.name().to_owned()),
+        Err(e) => {
+            tracing::warn!("could not read applied migrations: {}", e);
+            None
+        }
+    };
+
+    Json(VersionResponse {
+        version: VERSION.to_owned(),
+        commit: GIT_COMMIT.to_owned(),
+        schema_version,
+    })
+}
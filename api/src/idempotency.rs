@@ -0,0 +1,344 @@
+use crate::permission::Actor;
+use crate::AppState;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use service::data_access::{DataAccess, IdempotencyClaim};
+use service::data_transfer_objects::{ApiKeyDTO, IdempotencyRecordDTO};
+use sha2::{Digest, Sha256};
+
+/*
+`Idempotency-Key` on API POSTs, so a client that retries after a timeout
+doesn't create the same quote twice. The first request with a key claims it
+and its response is stored; a retry with the same key and the same request
+gets that response back, marked `Idempotent-Replayed: true`. The same key
+with a different method, path or body is a client bug and gets 422.
+
+Keys belong to the API key that sent them and are kept for `window` seconds.
+A retry that arrives while the first attempt is still running gets 409.
+Server errors aren't stored, so the client can retry them with the same key.
+An attempt that never finishes, because the client hung up or the handler
+panicked, holds its key for `lease` seconds; after that a retry takes over.
+*/
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+// larger request bodies aren't accepted with a key
+const MAX_BODY: usize = 2 * 1024 * 1024;
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long a key and its response are remembered.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Idempotency {
+    /// seconds
+    pub window: u64,
+    /// seconds a request may run before a retry with its key takes over
+    pub lease: u64,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Idempotency {
+            window: 24 * 60 * 60,
+            lease: 60,
+        }
+    }
+}
+
+/// Middleware for the `/api` routes, inside `auth::require_api_key`.
+pub async fn idempotent(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+
+    let key = match key.to_str() {
+        Ok(key) if !key.trim().is_empty() && key.len() <= MAX_KEY_LENGTH => key.trim().to_owned(),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Idempotency-Key must be 1 to {} visible ASCII characters",
+                    MAX_KEY_LENGTH
+                ),
+            )
+        }
+    };
+
+    // every POST needs a key, so require_api_key has always stored one
+    let Some(api_key) = request.extensions().get::<ApiKeyDTO>() else {
+        return next.run(request).await;
+    };
+    let actor = Actor::ApiKey(api_key.clone()).audit_label();
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_BODY).await else {
+        return error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body is too large to use with an Idempotency-Key".to_owned(),
+        );
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(b"\n");
+    hasher.update(parts.uri.to_string());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    let request_hash = format!("{:x}", hasher.finalize());
+
+    let seconds =
+        |seconds: u64| chrono::Duration::seconds(i64::try_from(seconds).unwrap_or(i64::MAX));
+    let window = seconds(state.idempotency.window);
+    let lease = seconds(state.idempotency.lease);
+
+    match DataAccess::claim_idempotency_key(
+        &state.db_conn,
+        &actor,
+        &key,
+        &request_hash,
+        window,
+        lease,
+    )
+    .await
+    {
+        Ok(IdempotencyClaim::Claimed) => {}
+        Ok(IdempotencyClaim::Existing(record)) if record.request_hash != request_hash => {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency-Key was already used with a different request".to_owned(),
+            )
+        }
+        Ok(IdempotencyClaim::Existing(record)) => return replay(record),
+        Err(e) => return internal_error(e),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    if response.status().is_server_error() {
+        release(&state, &actor, &key).await;
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("couldn't read response body: {}", e);
+            release(&state, &actor, &key).await;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    match std::str::from_utf8(&body) {
+        Ok(text) => {
+            let content_type = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+
+            if let Err(e) = DataAccess::store_idempotent_response(
+                &state.db_conn,
+                &actor,
+                &key,
+                parts.status.as_u16(),
+                content_type,
+                text.to_owned(),
+            )
+            .await
+            {
+                tracing::error!("couldn't store idempotent response: {}", e);
+                release(&state, &actor, &key).await;
+            }
+        }
+        // every /api write answers in JSON; anything else can't be stored,
+        // so a retry runs again rather than waiting for a response forever
+        Err(_) => release(&state, &actor, &key).await,
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn replay(record: IdempotencyRecordDTO) -> Response {
+    let (Some(status), Some(body)) = (record.status, record.body) else {
+        return error(
+            StatusCode::CONFLICT,
+            "A request with this Idempotency-Key is still being processed".to_owned(),
+        );
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+
+    let headers = response.headers_mut();
+    if let Some(content_type) = record
+        .content_type
+        .and_then(|value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+
+    response
+}
+
+async fn release(state: &AppState, actor: &str, key: &str) {
+    if let Err(e) = DataAccess::release_idempotency_key(&state.db_conn, actor, key).await {
+        tracing::error!("couldn't release idempotency key: {}", e);
+    }
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn internal_error(e: sea_orm::DbErr) -> Response {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Internal error: {}", e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use axum::{middleware, Router};
+    use chrono::Utc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    // counts the quotes it "creates"; a request marked `x-hang` never answers
+    fn app(state: AppState, created: Arc<AtomicUsize>) -> Router {
+        Router::new()
+            .route(
+                "/quotes",
+                post(move |headers: HeaderMap| async move {
+                    if headers.contains_key("x-hang") {
+                        std::future::pending::<()>().await;
+                    }
+                    let id = created.fetch_add(1, Ordering::SeqCst) + 1;
+                    (StatusCode::CREATED, Json(json!({ "id": id })))
+                }),
+            )
+            .layer(middleware::from_fn_with_state(state, idempotent))
+    }
+
+    fn post_quote(key: &str, body: &str, hang: bool) -> Request {
+        let mut request = Request::post("/quotes").header(IDEMPOTENCY_KEY, key);
+        if hang {
+            request = request.header("x-hang", "1");
+        }
+        let mut request = request.body(Body::from(body.to_owned())).unwrap();
+        request.extensions_mut().insert(ApiKeyDTO {
+            id: 1,
+            name: "ingest".to_owned(),
+            prefix: "qs_ingest".to_owned(),
+            scopes: Vec::new(),
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        });
+        request
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_retry_gets_the_stored_response() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let app = app(crate::test_state().await, created.clone());
+
+        let first = app
+            .clone()
+            .oneshot(post_quote("k1", "a", false))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(body(first).await, r#"{"id":1}"#);
+
+        let retry = app.oneshot(post_quote("k1", "a", false)).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(body(retry).await, r#"{"id":1}"#);
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reusing_a_key_for_another_request_is_refused() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let app = app(crate::test_state().await, created.clone());
+
+        app.clone()
+            .oneshot(post_quote("k1", "a", false))
+            .await
+            .unwrap();
+        let reused = app.oneshot(post_quote("k1", "b", false)).await.unwrap();
+
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn a_retry_while_the_first_attempt_runs_gets_409() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let app = app(crate::test_state().await, created.clone());
+
+        let first = tokio::spawn(app.clone().oneshot(post_quote("k1", "a", true)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let retry = app.oneshot(post_quote("k1", "a", false)).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+        assert_eq!(created.load(Ordering::SeqCst), 0);
+        first.abort();
+    }
+
+    #[tokio::test]
+    async fn a_retry_takes_over_a_dropped_attempt_once_its_lease_runs_out() {
+        let mut state = crate::test_state().await;
+        state.idempotency.lease = 0;
+        let created = Arc::new(AtomicUsize::new(0));
+        let app = app(state, created.clone());
+
+        // the client gives up, and its request's future is dropped with it
+        let dropped = tokio::time::timeout(
+            Duration::from_millis(100),
+            app.clone().oneshot(post_quote("k1", "a", true)),
+        )
+        .await;
+        assert!(dropped.is_err());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let retry = app
+            .clone()
+            .oneshot(post_quote("k1", "a", false))
+            .await
+            .unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(body(retry).await, r#"{"id":1}"#);
+
+        // and what it stored is what later retries get
+        let replay = app.oneshot(post_quote("k1", "a", false)).await.unwrap();
+        assert_eq!(replay.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,13 +1,17 @@
-use crate::AppState;
-use axum::extract::{Query, State};
-use axum::http::StatusCode;
+use crate::caching;
+use crate::permission::{Actor, Authorized, DeleteTags, EditOwnQuotes, ManageUsers, SubmitQuotes};
+use crate::{AppState, Pagination};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use service::data_access::DataAccess;
-use service::data_transfer_objects::{AuthorDTO, QuoteCreateDTO, QuoteDTO, TagCreateDTO, TagDTO};
-use utoipa::{IntoParams, OpenApi, ToSchema};
+use serde_json::{json, Value};
+use service::data_access::{DataAccess, UpdateOutcome};
+use service::data_transfer_objects::{AuthorDTO, QuoteCreateDTO, QuoteDTO, Role, TagDTO, UserDTO};
+use service::duplicates::DuplicateVerdict;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
 
 #[derive(Deserialize, Serialize, IntoParams)]
 pub struct Params {
@@ -15,6 +19,19 @@ pub struct Params {
     pub page_size: Option<u64>,
 }
 
+impl Params {
+    /// The page and page size asked for, kept within the configured limits.
+    pub fn resolve(&self, pagination: &Pagination) -> (u64, u64) {
+        let page = self.page.unwrap_or(1).max(1);
+        let page_size = self
+            .page_size
+            .unwrap_or(pagination.default_page_size)
+            .clamp(1, pagination.max_page_size.max(1));
+
+        (page, page_size)
+    }
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct QuoteResponse {
     pub quotes: Vec<QuoteDTO>,
@@ -51,134 +68,109 @@ pub struct AuthorAndAssociatedQuotesResponse {
 #[derive(OpenApi)]
 #[openapi(paths(
     openapi,
-    get_authors,
-    get_quotes,
+    crate::resource::get_authors,
+    crate::resource::get_quotes,
     post_quote,
-    get_tags,
-    get_single_quote,
+    crate::resource::get_tags,
+    crate::resource::get_single_quote,
     delete_quote,
-    get_tag_and_associated_quotes,
+    crate::resource::get_tag_and_associated_quotes,
     delete_tag,
-    get_author_and_associated_quotes,
-    patch_quote_with_new_tag
-))]
+    crate::resource::get_author_and_associated_quotes,
+    patch_quote_with_new_tag,
+    get_users,
+    patch_user_role,
+    crate::moderation::get_pending_quotes,
+    crate::moderation::approve_quote,
+    crate::moderation::reject_quote,
+    crate::duplicates::get_duplicates,
+    crate::duplicates::merge_duplicates,
+    crate::audit::get_audit_log,
+    crate::audit::get_audit_log_ndjson,
+    crate::export::get_anki_package,
+    crate::export::get_fortune_file,
+    crate::export::get_fortune_index,
+    crate::health::get_healthz,
+    crate::health::get_readyz,
+    crate::health::get_version,
+    crate::metrics::get_metrics
+), modifiers(&SecurityAddon))]
 pub struct ApiDoc;
 
-#[utoipa::path(
-    get,
-    path = "/api/api-docs/openapi.json",
-    responses(
-        (status = 200, description = "JSON file", body = ())
-    )
-)]
-pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
-    Json(ApiDoc::openapi())
-}
+// source: https://github.com/juhaku/utoipa/blob/master/examples/todo-axum/src/main.rs
+struct SecurityAddon;
 
-#[utoipa::path(
-    get,
-    path = "/api/authors",
-    params(Params),
-    responses(
-        (status = 200, description = "List of authors", body = AuthorResponse),
-        (status = 404, description = "No authors found"),
-        (status = 500, description = "Internal server error")
-))]
-pub async fn get_authors(
-    state: State<AppState>,
-    Query(params): Query<Params>,
-) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_authors_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((authors, pages))) => (
-            StatusCode::OK,
-            Json(json!(AuthorResponse { authors, pages })),
-        ),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "No authors found" })),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": format!("Internal error: {}", e) })),
-        ),
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            )
+        }
     }
 }
 
 #[utoipa::path(
     get,
-    path = "/api/quotes",
-    params(Params),
+    path = "/api/api-docs/openapi.json",
     responses(
-        (status = 200, description = "List of quotes", body = QuoteResponse),
-        (status = 404, description = "No quotes found"),
-        (status = 500, description = "Internal server error")
+        (status = 200, description = "JSON file", body = ())
     )
 )]
-pub async fn get_quotes(state: State<AppState>, Query(params): Query<Params>) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_quotes_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((quotes, pages))) => (StatusCode::OK, Json(json!(QuoteResponse { quotes, pages }))),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "No quotes found" })),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": format!("Internal error: {}", e) })),
-        ),
-    }
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
 }
 
 #[utoipa::path(
     post,
     path = "/api/quotes",
     request_body = QuoteCreateDTO,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first response back instead of creating another quote")
+    ),
     responses(
-        (status = 201, description = "Quote created", body = QuoteDTO),
+        (status = 201, description = "Quote created, pending moderation unless the caller can moderate", body = QuoteDTO),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 409, description = "The quote is already stored, listed as `duplicates`, or a request with this Idempotency-Key is still being processed"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
 pub async fn post_quote(
     state: State<AppState>,
-    Json(quote_create_dto): Json<QuoteCreateDTO>,
+    authorized: Authorized<SubmitQuotes>,
+    Json(mut quote_create_dto): Json<QuoteCreateDTO>,
 ) -> impl IntoResponse {
-    match DataAccess::create_quote(&state.db_conn, quote_create_dto).await {
-        Ok(quote_dto) => (StatusCode::CREATED, Json(json!(quote_dto))),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": format!("Internal error: {}", e) })),
-        ),
-    }
-}
+    quote_create_dto.submitted_by = authorized.actor.user_id();
+    quote_create_dto.status = authorized.actor.submission_status();
 
-#[utoipa::path(
-    get,
-    path = "/api/tags",
-    params(Params),
-    responses(
-        (status = 200, description = "List of tags", body = TagResponse),
-        (status = 404, description = "No tags found"),
-        (status = 500, description = "Internal server error")
-    )
-)]
-pub async fn get_tags(state: State<AppState>, Query(params): Query<Params>) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
+    match DataAccess::check_duplicates(&state.db_conn, &state.duplicates, &quote_create_dto.quote)
+        .await
+    {
+        Ok(DuplicateVerdict::Unique) => {}
+        Ok(DuplicateVerdict::Flag(reason)) => quote_create_dto.flagged_reason = Some(reason),
+        Ok(DuplicateVerdict::Reject(duplicates)) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "This quote is already stored",
+                    "duplicates": duplicates,
+                })),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("Internal error: {}", e) })),
+            )
+        }
+    }
 
-    match DataAccess::get_tags_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((tags, pages))) => (
-            StatusCode::OK,
-            Json(json!({ "tags": tags, "pages": pages })),
-        ),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "No tags found" })),
-        ),
+    match DataAccess::create_quote(&state.db_conn, &authorized.audit(), quote_create_dto).await {
+        Ok(quote_dto) => (StatusCode::CREATED, Json(json!(quote_dto))),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": format!("Internal error: {}", e) })),
@@ -187,24 +179,29 @@ pub async fn get_tags(state: State<AppState>, Query(params): Query<Params>) -> i
 }
 
 #[utoipa::path(
-    get,
+    delete,
     path = "/api/quotes/{quote_id}",
     responses(
-        (status = 200, description = "Single quote", body = QuoteDTO),
-        (status = 404, description = "Quote not found"),
+        (status = 204, description = "Quote deleted"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 412, description = "If-Match doesn't match the quote's current ETag"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
-pub async fn get_single_quote(
+pub async fn delete_quote(
     state: State<AppState>,
+    authorized: Authorized<EditOwnQuotes>,
     axum::extract::Path(quote_id): axum::extract::Path<i32>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match DataAccess::get_quote(&state.db_conn, quote_id).await {
-        Ok(Some(quote_dto)) => (StatusCode::OK, Json(json!(quote_dto))),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "Quote not found" })),
-        ),
+    if let Err(denied) = check_can_edit(&state, &authorized.actor, quote_id, &headers).await {
+        return denied;
+    }
+
+    match DataAccess::delete_quote(&state.db_conn, &authorized.audit(), quote_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json(json!({}))),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": format!("Internal error: {}", e) })),
@@ -214,17 +211,21 @@ pub async fn get_single_quote(
 
 #[utoipa::path(
     delete,
-    path = "/api/quotes/{quote_id}",
+    path = "/api/tags/{tag_id}",
     responses(
-        (status = 204, description = "Quote deleted"),
+        (status = 204, description = "Tag deleted"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
-pub async fn delete_quote(
+pub async fn delete_tag(
     state: State<AppState>,
-    axum::extract::Path(quote_id): axum::extract::Path<i32>,
+    authorized: Authorized<DeleteTags>,
+    axum::extract::Path(tag_id): axum::extract::Path<i32>,
 ) -> impl IntoResponse {
-    match DataAccess::delete_quote(&state.db_conn, quote_id).await {
+    match DataAccess::delete_tag(&state.db_conn, &authorized.audit(), tag_id).await {
         Ok(_) => (StatusCode::NO_CONTENT, Json(json!({}))),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -233,32 +234,62 @@ pub async fn delete_quote(
     }
 }
 
+/// A tag to add to a quote, and the version of the quote it was added to.
+#[derive(Deserialize, ToSchema)]
+pub struct QuoteTagUpdate {
+    pub tag: String,
+    /// the quote's `version` when it was fetched; may be left out when the
+    /// request has an `If-Match` header instead
+    pub version: Option<i32>,
+}
+
 #[utoipa::path(
-    get,
-    path = "/api/tags/{tag_id}",
-    params(Params),
+    patch,
+    path = "/api/quotes/{quote_id}",
+    request_body = QuoteTagUpdate,
     responses(
-        (status = 200, description = "Tag and associated quotes", body = TagAndRelatedQuotesResponse),
-        (status = 404, description = "Tag not found"),
+        (status = 200, description = "Quote updated with new tag", body = QuoteDTO),
+        (status = 404, description = "Quote not found"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 409, description = "The quote has a newer version, returned as `current`"),
+        (status = 412, description = "If-Match doesn't match the quote's current ETag"),
+        (status = 428, description = "Neither a version nor If-Match was sent"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
-pub async fn get_tag_and_associated_quotes(
+pub async fn patch_quote_with_new_tag(
     state: State<AppState>,
-    axum::extract::Path(tag_id): axum::extract::Path<i32>,
-    Query(params): Query<Params>,
+    authorized: Authorized<EditOwnQuotes>,
+    axum::extract::Path(quote_id): axum::extract::Path<i32>,
+    headers: HeaderMap,
+    Json(update): Json<QuoteTagUpdate>,
 ) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
+    let quote = match check_can_edit(&state, &authorized.actor, quote_id, &headers).await {
+        Ok(quote) => quote,
+        Err(denied) => return denied,
+    };
 
-    match DataAccess::get_tag_with_related_quotes(&state.db_conn, tag_id, page, page_size).await {
-        Ok(Some((tag, quotes, pages))) => (
-            StatusCode::OK,
-            Json(json!(TagAndRelatedQuotesResponse { tag, quotes, pages })),
-        ),
+    let expected_version = match expected_version(&quote, update.version, &headers) {
+        Ok(version) => version,
+        Err(missing) => return missing,
+    };
+
+    match DataAccess::update_quote_with_new_tag(
+        &state.db_conn,
+        &authorized.audit(),
+        quote_id,
+        update.tag,
+        expected_version,
+    )
+    .await
+    {
+        Ok(Some(UpdateOutcome::Updated(quote_dto))) => (StatusCode::OK, Json(json!(quote_dto))),
+        Ok(Some(UpdateOutcome::Conflict(current))) => conflict(&current),
         Ok(None) => (
             StatusCode::NOT_FOUND,
-            Json(json!({ "error": "Tag not found" })),
+            Json(json!({"error": "Quote not found!"})),
         ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -267,60 +298,94 @@ pub async fn get_tag_and_associated_quotes(
     }
 }
 
-#[utoipa::path(
-    delete,
-    path = "/api/tags/{tag_id}",
-    responses(
-        (status = 204, description = "Tag deleted"),
-        (status = 500, description = "Internal server error")
+/// The version of `quote` a write was made against: the one sent with it, or
+/// the one it has now when the request's `If-Match` (already checked) names
+/// this copy. A write with neither would apply blindly, so it gets 428.
+pub(crate) fn expected_version(
+    quote: &QuoteDTO,
+    sent: Option<i32>,
+    headers: &HeaderMap,
+) -> Result<i32, (StatusCode, Json<Value>)> {
+    sent.or(headers
+        .contains_key(header::IF_MATCH)
+        .then_some(quote.version))
+        .ok_or((
+            StatusCode::PRECONDITION_REQUIRED,
+            Json(json!({ "error": "Send the quote's version, in the body or as If-Match" })),
+        ))
+}
+
+/// The answer to an edit made against an old version: 409, with the quote as
+/// it is now so the client can redo the edit on top of it.
+pub(crate) fn conflict(current: &QuoteDTO) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::CONFLICT,
+        Json(json!({
+            "error": "Quote was changed by someone else",
+            "current": current,
+        })),
     )
-)]
-pub async fn delete_tag(
-    state: State<AppState>,
-    axum::extract::Path(tag_id): axum::extract::Path<i32>,
-) -> impl IntoResponse {
-    match DataAccess::delete_tag(&state.db_conn, tag_id).await {
-        Ok(_) => (StatusCode::NO_CONTENT, Json(json!({}))),
-        Err(e) => (
+}
+
+// contributors may only change the quotes they submitted
+async fn check_can_edit(
+    state: &AppState,
+    actor: &Actor,
+    quote_id: i32,
+    headers: &HeaderMap,
+) -> Result<QuoteDTO, (StatusCode, Json<Value>)> {
+    check_unchanged(state, quote_id, headers, |quote| actor.can_edit(quote)).await
+}
+
+/// The quote a write is about, unless it moved on since the ETag sent in
+/// `If-Match` (412) or `allowed` says no (403).
+pub(crate) async fn check_unchanged(
+    state: &AppState,
+    quote_id: i32,
+    headers: &HeaderMap,
+    allowed: impl Fn(&QuoteDTO) -> bool,
+) -> Result<QuoteDTO, (StatusCode, Json<Value>)> {
+    match DataAccess::get_quote(&state.db_conn, quote_id).await {
+        Ok(Some(quote)) if !allowed(&quote) => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Contributors can only change their own quotes" })),
+        )),
+        Ok(Some(quote)) if !caching::if_match(headers, &caching::json_etag(&quote)) => Err((
+            StatusCode::PRECONDITION_FAILED,
+            Json(json!({
+                "error": "Quote has changed since it was fetched",
+                "current": quote,
+            })),
+        )),
+        Ok(Some(quote)) => Ok(quote),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Quote not found!"})),
+        )),
+        Err(e) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": format!("Internal error: {}", e) })),
-        ),
+        )),
     }
 }
 
 #[utoipa::path(
     get,
-    path = "/api/authors/{author_id}",
-    params(Params),
+    path = "/api/users",
     responses(
-        (status = 200, description = "Author and associated quotes", body = AuthorAndAssociatedQuotesResponse),
-        (status = 404, description = "Author not found"),
+        (status = 200, description = "List of users", body = [UserDTO]),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
-pub async fn get_author_and_associated_quotes(
+pub async fn get_users(
     state: State<AppState>,
-    axum::extract::Path(author_id): axum::extract::Path<i32>,
-    Query(params): Query<Params>,
+    _authorized: Authorized<ManageUsers>,
 ) -> impl IntoResponse {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_author_with_related_quotes(&state.db_conn, author_id, page, page_size)
-        .await
-    {
-        Ok(Some((author, quotes, pages))) => (
-            StatusCode::OK,
-            Json(json!(AuthorAndAssociatedQuotesResponse {
-                author,
-                quotes,
-                pages
-            })),
-        ),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(json!({ "error": "Author not found" })),
-        ),
+    match DataAccess::get_users(&state.db_conn).await {
+        Ok(users) => (StatusCode::OK, Json(json!(users))),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": format!("Internal error: {}", e) })),
@@ -328,26 +393,36 @@ pub async fn get_author_and_associated_quotes(
     }
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
 #[utoipa::path(
     patch,
-    path = "/api/quotes/{quote_id}",
-    request_body = TagCreateDTO,
+    path = "/api/users/{user_id}",
+    request_body = RoleUpdate,
     responses(
-        (status = 200, description = "Quote updated with new tag", body = QuoteDTO),
-        (status = 404, description = "Quote not found"),
+        (status = 200, description = "User with the new role", body = UserDTO),
+        (status = 404, description = "User not found"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
         (status = 500, description = "Internal server error")
-    )
+    ),
+    security(("api_key" = []))
 )]
-pub async fn patch_quote_with_new_tag(
+pub async fn patch_user_role(
     state: State<AppState>,
-    axum::extract::Path(quote_id): axum::extract::Path<i32>,
-    Json(tag): Json<TagCreateDTO>,
+    authorized: Authorized<ManageUsers>,
+    axum::extract::Path(user_id): axum::extract::Path<i32>,
+    Json(update): Json<RoleUpdate>,
 ) -> impl IntoResponse {
-    match DataAccess::update_quote_with_new_tag(&state.db_conn, quote_id, tag.tag).await {
-        Ok(Some(quote_dto)) => (StatusCode::OK, Json(json!(quote_dto))),
+    match DataAccess::set_user_role(&state.db_conn, &authorized.audit(), user_id, update.role).await
+    {
+        Ok(Some(user)) => (StatusCode::OK, Json(json!(user))),
         Ok(None) => (
             StatusCode::NOT_FOUND,
-            Json(json!({"error": "Quote not found!"})),
+            Json(json!({"error": "User not found!"})),
         ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -1,77 +1,329 @@
+pub mod account;
+pub mod audit;
+pub mod auth;
+pub mod caching;
+pub mod csrf;
+pub mod duplicates;
+pub mod export;
+pub mod feeds;
+pub mod health;
+pub mod idempotency;
 pub mod json;
+pub mod logging;
+pub mod metrics;
+pub mod moderation;
+pub mod negotiate;
+pub mod permission;
+pub mod rate_limit;
+pub mod resource;
+pub mod spam;
+pub mod telemetry;
 pub mod template;
 
 use sea_orm::DatabaseConnection;
-use tower_http::trace;
+use serde::{Deserialize, Serialize};
 
-use axum::routing::{get, Router};
+use axum::handler::Handler;
+use axum::routing::{delete, get, patch, post, MethodRouter, Router};
+use axum::{middleware, Extension};
+use caching::CachePolicy;
+use feeds::PublicUrl;
+use idempotency::Idempotency;
+use metrics::Metrics;
+use negotiate::{Format, FormatPolicy};
+use rate_limit::RateLimiter;
+use service::duplicates::DuplicatesConfig;
+use spam::SpamGuard;
 
 #[derive(Clone)]
 pub struct AppState {
     db_conn: DatabaseConnection,
+    spam_guard: SpamGuard,
+    pagination: Pagination,
+    features: Features,
+    idempotency: Idempotency,
+    duplicates: DuplicatesConfig,
+    public_url: PublicUrl,
+    metrics: Metrics,
 }
 
 impl AppState {
-    pub fn new(db_conn: DatabaseConnection) -> Self {
-        AppState { db_conn }
+    pub fn new(
+        mut db_conn: DatabaseConnection,
+        spam_guard: SpamGuard,
+        pagination: Pagination,
+        features: Features,
+        idempotency: Idempotency,
+        duplicates: DuplicatesConfig,
+        public_url: PublicUrl,
+    ) -> Self {
+        let metrics = Metrics::new();
+
+        // sea-orm takes a single callback, which is handed every query made
+        // through this connection (and its clones made from here on)
+        let observer = metrics.clone();
+        db_conn.set_metric_callback(move |info| {
+            observer.observe_query(info);
+            telemetry::export_query(info);
+        });
+
+        let observer = metrics.clone();
+        service::cache::set_observer(move |kind, hit| observer.observe_cache(kind, hit));
+
+        AppState {
+            db_conn,
+            spam_guard,
+            pagination,
+            features,
+            idempotency,
+            duplicates,
+            public_url,
+            metrics,
+        }
     }
 }
 
-pub fn template_router() -> Router<AppState> {
-    let trace_layer = trace::TraceLayer::new_for_http()
-        .make_span_with(trace::DefaultMakeSpan::new().level(tracing::Level::INFO))
-        .on_response(trace::DefaultOnResponse::new().level(tracing::Level::INFO));
+/// Page size used when a listing doesn't ask for one, and the largest it may
+/// ask for.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pagination {
+    pub default_page_size: u64,
+    pub max_page_size: u64,
+}
 
-    Router::new()
+impl Default for Pagination {
+    fn default() -> Self {
+        Pagination {
+            default_page_size: 10,
+            max_page_size: 100,
+        }
+    }
+}
+
+/// Parts of the server that can be switched off. Everything is on by default.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// anyone can create an account at `/register`
+    pub registration: bool,
+    /// atom and rss feeds under `/feeds`
+    pub feeds: bool,
+    /// fortune and anki exports under `/api/export`
+    pub exports: bool,
+    /// the swagger ui at `/swagger-ui`
+    pub swagger_ui: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            registration: true,
+            feeds: true,
+            exports: true,
+            swagger_ui: true,
+        }
+    }
+}
+
+pub fn template_router(state: AppState, limiter: RateLimiter) -> Router<AppState> {
+    let mut router = resource_router()
         .route("/", get(template::get_root))
+        .route("/quotes", post(template::post_quote_form))
+        .route("/tags/{tag_id}", delete(template::delete_tag))
+        .route("/authors/{author_id}", delete(template::delete_author))
+        .route("/submitQuote", get(template::get_quote_form))
+        .route("/login", get(account::get_login).post(account::post_login))
         .route(
-            "/quotes",
-            get(template::get_quotes).post(template::post_quote_form),
+            "/register",
+            get(account::get_register).post(account::post_register),
         )
+        .route("/logout", post(account::post_logout))
+        .route("/moderation", uncached(moderation::get_moderation_queue))
         .route(
-            "/tags/{tag_id}",
-            get(template::get_tag_and_associated_quotes).delete(template::delete_tag),
+            "/moderation/{quote_id}/approve",
+            post(moderation::post_approve_form),
         )
         .route(
-            "/authors/{author_id}",
-            get(template::get_author_and_associated_quotes).delete(template::delete_author),
+            "/moderation/{quote_id}/reject",
+            post(moderation::post_reject_form),
+        )
+        .route("/users", uncached(template::get_users))
+        .route("/users/{user_id}/role", post(template::post_user_role))
+        .route("/quotes/{quote_id}", delete(template::delete_quote));
+
+    if state.features.feeds {
+        router = router
+            .route("/feeds/quotes.atom", get(feeds::get_quotes_atom))
+            .route("/feeds/quotes.rss", get(feeds::get_quotes_rss))
+            .route("/feeds/tags/{file}", get(feeds::get_tag_feed))
+            .route("/feeds/authors/{file}", get(feeds::get_author_feed));
+    }
+
+    let metrics = state.metrics.clone();
+
+    router
+        .layer(middleware::from_fn_with_state(state, csrf::verify_csrf))
+        .layer(middleware::from_fn_with_state(limiter, rate_limit::limit))
+        .layer(middleware::from_fn_with_state(metrics, metrics::track))
+        .layer(logging::trace_layer())
+}
+
+pub fn json_router(state: AppState, limiter: RateLimiter) -> Router<AppState> {
+    let mut router = resource_router()
+        .route("/quotes", post(json::post_quote))
+        .route("/quotes/duplicates", uncached(duplicates::get_duplicates))
+        .route(
+            "/quotes/duplicates/merge",
+            post(duplicates::merge_duplicates),
         )
-        .route("/authors", get(template::get_authors))
-        .route("/tags", get(template::get_tags))
-        .route("/submitQuote", get(template::get_quote_form))
         .route(
             "/quotes/{quote_id}",
-            get(template::get_single_quote).delete(template::delete_quote),
+            //         put(json::put_single_quote)
+            patch(json::patch_quote_with_new_tag).delete(json::delete_quote),
         )
-        .layer(trace_layer)
-}
+        .route(
+            "/tags/{tag_id}",
+            //         put(json::put_single_tag)
+            delete(json::delete_tag),
+        )
+        //         .route("/authors/{author_id}", put(json::put_single_author).delete(json::delete_author))
+        .route("/moderation", uncached(moderation::get_pending_quotes))
+        .route(
+            "/moderation/{quote_id}/approve",
+            post(moderation::approve_quote),
+        )
+        .route(
+            "/moderation/{quote_id}/reject",
+            post(moderation::reject_quote),
+        )
+        .route("/users", uncached(json::get_users))
+        .route("/users/{user_id}", patch(json::patch_user_role))
+        .route("/audit", uncached(audit::get_audit_log))
+        .route("/audit.ndjson", uncached(audit::get_audit_log_ndjson));
 
-pub fn json_router() -> Router<AppState> {
-    let trace_layer = trace::TraceLayer::new_for_http()
-        .make_span_with(trace::DefaultMakeSpan::new().level(tracing::Level::INFO))
-        .on_response(trace::DefaultOnResponse::new().level(tracing::Level::INFO));
+    if state.features.exports {
+        router = router
+            .route("/export/anki", get(export::get_anki_package))
+            .route("/export/fortune", get(export::get_fortune_file))
+            .route("/export/fortune.dat", get(export::get_fortune_index));
+    }
+
+    let metrics = state.metrics.clone();
+
+    router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::idempotent,
+        ))
+        // inside require_api_key, so clients with a key are limited by key
+        .layer(middleware::from_fn_with_state(limiter, rate_limit::limit))
+        .layer(middleware::from_fn_with_state(state, auth::require_api_key))
+        .layer(Extension(FormatPolicy {
+            default: Format::Json,
+            honor_accept: false,
+        }))
+        .layer(middleware::from_fn_with_state(metrics, metrics::track))
+        .layer(logging::trace_layer())
+}
 
+/// Probes for the orchestrator and metrics for monitoring. They sit outside
+/// the other routers, so they aren't rate limited, traced or counted: they
+/// are polled every few seconds.
+pub fn health_router() -> Router<AppState> {
     Router::new()
-        .route("/quotes", get(json::get_quotes).post(json::post_quote))
-        .route("/tags", get(json::get_tags))
-        .route("/authors", get(json::get_authors))
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .route("/version", get(health::get_version))
+        .route("/metrics", get(metrics::get_metrics))
+}
+
+/// The read-only resource routes shared by the HTML site and the `/api`
+/// alias. Item routes take their format suffix in the id segment
+/// (`/quotes/12.json`); collections get a route per suffix.
+fn resource_router() -> Router<AppState> {
+    let mut router = Router::new()
+        .route("/quotes", cached(resource::get_quotes, LIST_MAX_AGE))
         .route(
             "/quotes/{quote_id}",
-            get(json::get_single_quote)
-                //         .put(json::put_single_quote)
-                .patch(json::patch_quote_with_new_tag)
-                .delete(json::delete_quote),
+            cached(resource::get_single_quote, ITEM_MAX_AGE),
         )
+        .route("/tags", cached(resource::get_tags, ITEM_MAX_AGE))
         .route(
             "/tags/{tag_id}",
-            get(json::get_tag_and_associated_quotes)
-                //         .put(json::put_single_tag)
-                .delete(json::delete_tag),
+            cached(resource::get_tag_and_associated_quotes, LIST_MAX_AGE),
         )
+        .route("/authors", cached(resource::get_authors, ITEM_MAX_AGE))
         .route(
             "/authors/{author_id}",
-            get(json::get_author_and_associated_quotes), //         .put(json::put_single_author)
-                                                         //         .delete(json::delete_author),
-        )
-        .layer(trace_layer)
+            cached(resource::get_author_and_associated_quotes, LIST_MAX_AGE),
+        );
+
+    for suffix in negotiate::SUFFIXES {
+        router = router
+            .route(
+                &format!("/quotes.{}", suffix),
+                cached(resource::get_quotes, LIST_MAX_AGE),
+            )
+            .route(
+                &format!("/tags.{}", suffix),
+                cached(resource::get_tags, ITEM_MAX_AGE),
+            )
+            .route(
+                &format!("/authors.{}", suffix),
+                cached(resource::get_authors, ITEM_MAX_AGE),
+            );
+    }
+
+    router
+}
+
+// seconds shared caches may keep anonymous pages: lists of quotes change
+// whenever one is added, single quotes and the tag and author indexes rarely
+const LIST_MAX_AGE: u32 = 60;
+const ITEM_MAX_AGE: u32 = 300;
+
+/// A GET route answering conditional requests (see caching.rs).
+fn cached<H, T>(handler: H, max_age: u32) -> MethodRouter<AppState>
+where
+    H: Handler<T, AppState>,
+    T: 'static,
+{
+    get(handler).layer(middleware::from_fn_with_state(
+        CachePolicy::Public(max_age),
+        caching::conditional_get,
+    ))
+}
+
+/// A GET route whose responses are never stored, for pages only staff see.
+fn uncached<H, T>(handler: H) -> MethodRouter<AppState>
+where
+    H: Handler<T, AppState>,
+    T: 'static,
+{
+    get(handler).layer(middleware::map_response(caching::no_store))
+}
+
+/// State over a fresh in-memory database, for tests that go through the
+/// middleware.
+#[cfg(test)]
+pub(crate) async fn test_state() -> AppState {
+    use migration::MigratorTrait;
+
+    let db_conn = sea_orm::Database::connect("sqlite::memory:")
+        .await
+        .expect("in-memory database");
+    migration::Migrator::up(&db_conn, None)
+        .await
+        .expect("migrations");
+
+    AppState::new(
+        db_conn,
+        SpamGuard::new(spam::SpamAction::default()),
+        Pagination::default(),
+        Features::default(),
+        Idempotency::default(),
+        DuplicatesConfig::default(),
+        PublicUrl::default(),
+    )
 }
@@ -0,0 +1,125 @@
+use crate::permission::Actor;
+use crate::telemetry;
+use axum::extract::{MatchedPath, OriginalUri, Request};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_http::trace::{
+    DefaultOnRequest, DefaultOnResponse, HttpMakeClassifier, MakeSpan, TraceLayer,
+};
+use tracing::field::Empty;
+use tracing::Span;
+
+/*
+Every request gets an id, taken from `X-Request-Id` when the client or a
+proxy in front of the server sent a usable one and made up otherwise. It is
+sent back on the response, stored with any audit entries the request causes
+and attached to its log span along with the route, the quote it is about and,
+once known, the actor.
+*/
+
+/// Header a client (or a proxy in front of the server) can set to tie logs
+/// and audit entries back to the request that caused them.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// ids from outside are logged and stored, so they are kept short and plain
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+pub(crate) fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_owned)
+}
+
+fn usable_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Makes sure the request has a usable `X-Request-Id` and echoes it on the
+/// response.
+pub async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let id = request_id(request.headers())
+        .filter(|id| usable_request_id(id))
+        .unwrap_or_else(|| {
+            let bytes: [u8; 16] = rand::random();
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        });
+
+    // only ascii made it this far
+    let value = HeaderValue::from_str(&id).expect("request ids are visible ascii");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, value.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+/// The span each request is logged under. `actor` is filled in by the
+/// `Actor` extractor; a `traceparent` header makes it part of the caller's
+/// trace.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        // the uri of a nested router has its prefix stripped, the matched
+        // path doesn't
+        let uri = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri(), |original| &original.0);
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %uri,
+            route,
+            request_id = request_id(request.headers()),
+            quote_id = route.and_then(|route| path_param(route, uri.path(), "{quote_id}")),
+            actor = Empty,
+            otel.name = format!("{} {}", request.method(), route.unwrap_or(uri.path())),
+            otel.kind = "server",
+        );
+        telemetry::set_remote_parent(&span, request.headers());
+
+        span
+    }
+}
+
+/// Logs each request under a `RequestSpan`, and its response at info.
+pub(crate) fn trace_layer(
+) -> TraceLayer<HttpMakeClassifier, RequestSpan, DefaultOnRequest, DefaultOnResponse> {
+    TraceLayer::new_for_http()
+        .make_span_with(RequestSpan)
+        .on_response(DefaultOnResponse::new().level(tracing::Level::INFO))
+}
+
+pub(crate) fn record_actor(actor: &Actor) {
+    Span::current().record("actor", actor.audit_label());
+}
+
+// The numeric id in the path segment matching `param` in the route, so
+// `/quotes/{quote_id}` and `/quotes/12.json` give 12.
+fn path_param(route: &str, path: &str, param: &str) -> Option<i32> {
+    let (_, segment) = route
+        .split('/')
+        .zip(path.split('/'))
+        .find(|(pattern, _)| *pattern == param)?;
+
+    let digits = segment.split('.').next()?;
+    digits.parse().ok()
+}
@@ -0,0 +1,234 @@
+use crate::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use sea_orm::metric::Info;
+use sea_orm::{ConnectionTrait, DbBackend};
+use service::cache;
+use service::data_access::DataAccess;
+use std::time::Instant;
+
+/*
+Prometheus metrics, served as text from `/metrics`:
+
+- quote_server_http_requests_total and
+  quote_server_http_request_duration_seconds, by method, route and status
+- quote_server_db_query_duration_seconds, by statement kind and outcome
+- quote_server_db_pool_connections, idle and in use
+- quote_server_quotes (by status), quote_server_authors, quote_server_tags
+- quote_server_cache_lookups_total, by kind of entry and hit or miss, and
+  quote_server_cache_entries
+
+Requests are labelled with the route pattern (`/quotes/{quote_id}`) rather
+than the path, so there is one series per route instead of one per quote.
+The pool, domain and cache size gauges are read when scraped.
+*/
+
+// queries mostly take well under a millisecond, so the http buckets are too
+// coarse for them
+const QUERY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    query_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    quotes: IntGaugeVec,
+    authors: IntGauge,
+    tags: IntGauge,
+    cache_lookups: IntCounterVec,
+    cache_entries: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let labels = ["method", "route", "status"];
+
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("quote_server".to_owned()), None)
+                .expect("the prefix is a valid metric name"),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &labels,
+            )
+            .expect("valid metric"),
+            http_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to answer HTTP requests",
+                ),
+                &labels,
+            )
+            .expect("valid metric"),
+            query_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "db_query_duration_seconds",
+                    "Time taken by database queries",
+                )
+                .buckets(QUERY_BUCKETS.to_vec()),
+                &["statement", "outcome"],
+            )
+            .expect("valid metric"),
+            pool_connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Database pool connections"),
+                &["state"],
+            )
+            .expect("valid metric"),
+            quotes: IntGaugeVec::new(Opts::new("quotes", "Quotes by status"), &["status"])
+                .expect("valid metric"),
+            authors: IntGauge::new("authors", "Authors").expect("valid metric"),
+            tags: IntGauge::new("tags", "Tags").expect("valid metric"),
+            cache_lookups: IntCounterVec::new(
+                Opts::new("cache_lookups_total", "Read cache lookups"),
+                &["kind", "result"],
+            )
+            .expect("valid metric"),
+            cache_entries: IntGauge::new("cache_entries", "Entries in the read cache")
+                .expect("valid metric"),
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_duration.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.pool_connections.clone()),
+            Box::new(metrics.quotes.clone()),
+            Box::new(metrics.authors.clone()),
+            Box::new(metrics.tags.clone()),
+            Box::new(metrics.cache_lookups.clone()),
+            Box::new(metrics.cache_entries.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// Adds a finished query to the query histogram.
+    pub fn observe_query(&self, info: &Info<'_>) {
+        let outcome = if info.failed { "error" } else { "ok" };
+
+        self.query_duration
+            .with_label_values(&[statement_kind(&info.statement.sql), outcome])
+            .observe(info.elapsed.as_secs_f64());
+    }
+
+    /// Counts a read cache lookup.
+    pub fn observe_cache(&self, kind: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+
+        self.cache_lookups.with_label_values(&[kind, result]).inc();
+    }
+}
+
+/// `select`, `insert`, `update`, `delete` or `other`, from the statement's
+/// first word.
+pub(crate) fn statement_kind(sql: &str) -> &'static str {
+    let first_word = sql.split_whitespace().next();
+
+    ["select", "insert", "update", "delete"]
+        .into_iter()
+        .find(|kind| first_word.is_some_and(|word| word.eq_ignore_ascii_case(kind)))
+        .unwrap_or("other")
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts and times each request against the route it matched.
+pub async fn track(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_owned(), |path| path.as_str().to_owned());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics
+        .http_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain; version=0.0.4"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_metrics(State(state): State<AppState>) -> Response {
+    let metrics = &state.metrics;
+
+    if state.db_conn.get_database_backend() == DbBackend::Sqlite {
+        let pool = state.db_conn.get_sqlite_connection_pool();
+        let idle = pool.num_idle() as i64;
+        metrics
+            .pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        metrics
+            .pool_connections
+            .with_label_values(&["in_use"])
+            .set(i64::from(pool.size()) - idle);
+    }
+
+    // on failure the gauges keep their last values; the query metrics show
+    // the error
+    match DataAccess::get_counts(&state.db_conn).await {
+        Ok(counts) => {
+            for (status, count) in [
+                ("approved", counts.approved_quotes),
+                ("pending", counts.pending_quotes),
+                ("rejected", counts.rejected_quotes),
+            ] {
+                metrics
+                    .quotes
+                    .with_label_values(&[status])
+                    .set(count as i64);
+            }
+            metrics.authors.set(counts.authors as i64);
+            metrics.tags.set(counts.tags as i64);
+        }
+        Err(e) => tracing::warn!("could not count quotes for metrics: {}", e),
+    }
+
+    metrics.cache_entries.set(cache::len() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        tracing::error!("could not encode metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+        .into_response()
+}
@@ -0,0 +1,363 @@
+use crate::auth::Session;
+use crate::json;
+use crate::permission::{Authorized, ModerateQuotes};
+use crate::template::AppError;
+use crate::AppState;
+use askama::Template;
+use axum::extract::{Form, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use service::data_access::{DataAccess, UpdateOutcome};
+use service::data_transfer_objects::{Permission, QuoteDTO, QuoteEditDTO};
+use utoipa::ToSchema;
+
+/*
+The moderation queue: quotes submitted by users who can't moderate wait as
+`pending` until a moderator approves them (optionally editing them first) or
+rejects them with a reason. The html page and the `/api/moderation` endpoints
+share the service calls.
+*/
+
+#[derive(Template)]
+#[template(path = "./moderation.html")]
+struct ModerationTemplate<'a> {
+    quotes: &'a [QuoteDTO],
+    session: Option<&'a Session>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Rejection {
+    pub reason: String,
+    /// the version the moderator saw; the rejection is refused if the quote
+    /// has changed since. Needed unless the request has an `If-Match` header
+    pub version: Option<i32>,
+}
+
+#[axum::debug_handler]
+pub async fn get_moderation_queue(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+) -> Result<impl IntoResponse, AppError> {
+    let quotes = DataAccess::get_pending_quotes(&state.db_conn).await?;
+
+    let moderation_template = ModerationTemplate {
+        quotes: &quotes,
+        session: authorized.actor.session(),
+    };
+
+    Ok(Html(moderation_template.render()?))
+}
+
+#[axum::debug_handler]
+pub async fn post_approve_form(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+    Path(quote_id): Path<i32>,
+    Form(edits): Form<QuoteEditDTO>,
+) -> Result<impl IntoResponse, AppError> {
+    let version = edits.version.ok_or(AppError::PreconditionRequired)?;
+
+    let outcome = DataAccess::approve_quote(
+        &state.db_conn,
+        &authorized.audit(),
+        quote_id,
+        authorized.actor.user_id(),
+        edits,
+        version,
+    )
+    .await?
+    .ok_or(AppError::NotFound)?;
+
+    match outcome {
+        UpdateOutcome::Updated(_) => Ok(Redirect::to("/moderation")),
+        UpdateOutcome::Conflict(_) => Err(AppError::Conflict),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn post_reject_form(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+    Path(quote_id): Path<i32>,
+    Form(rejection): Form<Rejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let version = rejection.version.ok_or(AppError::PreconditionRequired)?;
+
+    let outcome = DataAccess::reject_quote(
+        &state.db_conn,
+        &authorized.audit(),
+        quote_id,
+        authorized.actor.user_id(),
+        rejection.reason,
+        version,
+    )
+    .await?
+    .ok_or(AppError::NotFound)?;
+
+    match outcome {
+        UpdateOutcome::Updated(_) => Ok(Redirect::to("/moderation")),
+        UpdateOutcome::Conflict(_) => Err(AppError::Conflict),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/moderation",
+    responses(
+        (status = 200, description = "Quotes waiting for moderation, oldest first", body = [QuoteDTO]),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_pending_quotes(
+    state: State<AppState>,
+    _authorized: Authorized<ModerateQuotes>,
+) -> impl IntoResponse {
+    match DataAccess::get_pending_quotes(&state.db_conn).await {
+        Ok(quotes) => (StatusCode::OK, Json(json!(quotes))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/moderation/{quote_id}/approve",
+    request_body(content = QuoteEditDTO, description = "Optional edits to apply before publishing"),
+    responses(
+        (status = 200, description = "Quote approved", body = QuoteDTO),
+        (status = 404, description = "No pending quote with this id"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 409, description = "The quote has a newer version, returned as `current`"),
+        (status = 412, description = "If-Match doesn't match the quote's current ETag"),
+        (status = 428, description = "Neither a version nor If-Match was sent"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn approve_quote(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+    Path(quote_id): Path<i32>,
+    headers: HeaderMap,
+    edits: Option<Json<QuoteEditDTO>>,
+) -> impl IntoResponse {
+    let edits = edits.map(|Json(edits)| edits).unwrap_or_default();
+    let version = match json::check_unchanged(&state, quote_id, &headers, |_| true)
+        .await
+        .and_then(|quote| json::expected_version(&quote, edits.version, &headers))
+    {
+        Ok(version) => version,
+        Err(refused) => return refused,
+    };
+
+    match DataAccess::approve_quote(
+        &state.db_conn,
+        &authorized.audit(),
+        quote_id,
+        authorized.actor.user_id(),
+        edits,
+        version,
+    )
+    .await
+    {
+        Ok(Some(UpdateOutcome::Updated(quote_dto))) => (StatusCode::OK, Json(json!(quote_dto))),
+        Ok(Some(UpdateOutcome::Conflict(current))) => json::conflict(&current),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "No pending quote with this id"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/moderation/{quote_id}/reject",
+    request_body = Rejection,
+    responses(
+        (status = 200, description = "Quote rejected", body = QuoteDTO),
+        (status = 404, description = "No pending quote with this id"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "Role does not allow this"),
+        (status = 409, description = "The quote has a newer version, returned as `current`"),
+        (status = 412, description = "If-Match doesn't match the quote's current ETag"),
+        (status = 428, description = "Neither a version nor If-Match was sent"),
+        (status = 500, description = "Internal server error")
+    ),
+    security(("api_key" = []))
+)]
+pub async fn reject_quote(
+    state: State<AppState>,
+    authorized: Authorized<ModerateQuotes>,
+    Path(quote_id): Path<i32>,
+    headers: HeaderMap,
+    Json(rejection): Json<Rejection>,
+) -> impl IntoResponse {
+    let version = match json::check_unchanged(&state, quote_id, &headers, |_| true)
+        .await
+        .and_then(|quote| json::expected_version(&quote, rejection.version, &headers))
+    {
+        Ok(version) => version,
+        Err(refused) => return refused,
+    };
+
+    match DataAccess::reject_quote(
+        &state.db_conn,
+        &authorized.audit(),
+        quote_id,
+        authorized.actor.user_id(),
+        rejection.reason,
+        version,
+    )
+    .await
+    {
+        Ok(Some(UpdateOutcome::Updated(quote_dto))) => (StatusCode::OK, Json(json!(quote_dto))),
+        Ok(Some(UpdateOutcome::Conflict(current))) => json::conflict(&current),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "No pending quote with this id"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Internal error: {}", e) })),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::{RateLimiter, RateLimits};
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::{header, StatusCode};
+    use axum::Router;
+    use serde_json::{json, Value};
+    use service::audit::AuditContext;
+    use service::data_access::DataAccess;
+    use service::data_transfer_objects::{QuoteCreateDTO, QuoteDTO, QuoteStatus, Scope};
+    use tower::ServiceExt;
+
+    // a moderator's key and a quote waiting for moderation
+    async fn app() -> (Router, String, QuoteDTO) {
+        let state = crate::test_state().await;
+        let db = state.db_conn.clone();
+        let ctx = AuditContext::cli();
+
+        let (_, key) =
+            DataAccess::create_api_key(&db, &ctx, "mod".to_owned(), vec![Scope::Moderate])
+                .await
+                .unwrap();
+        let created = DataAccess::create_quote(
+            &db,
+            &ctx,
+            QuoteCreateDTO {
+                quote: "Well done is better than well said.".to_owned(),
+                related_tags: Vec::new(),
+                author_name: "Benjamin Franklin".to_owned(),
+                source: None,
+                submitted_by: None,
+                status: QuoteStatus::Pending,
+                flagged_reason: None,
+            },
+        )
+        .await
+        .unwrap();
+        let pending = DataAccess::get_quote(&db, created.id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let budget = "1000/min".parse().unwrap();
+        let limiter = RateLimiter::new(
+            RateLimits {
+                reads: budget,
+                writes: budget,
+            },
+            Vec::new(),
+        );
+
+        (
+            crate::json_router(state.clone(), limiter).with_state(state),
+            key,
+            pending,
+        )
+    }
+
+    async fn post(
+        app: &Router,
+        key: &str,
+        uri: &str,
+        if_match: Option<&str>,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::post(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(etag) = if_match {
+            request = request.header(header::IF_MATCH, etag);
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), 1 << 20)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_decision_without_a_version_is_refused() {
+        let (app, key, pending) = app().await;
+        let approve = format!("/moderation/{}/approve", pending.id);
+        let reject = format!("/moderation/{}/reject", pending.id);
+
+        let (status, _) = post(&app, &key, &approve, None, json!({})).await;
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+        let (status, _) = post(&app, &key, &reject, None, json!({ "reason": "spam" })).await;
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+
+        // neither went through
+        let (status, body) = post(
+            &app,
+            &key,
+            &reject,
+            None,
+            json!({ "reason": "spam", "version": pending.version }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "rejected");
+        assert_eq!(body["version"], pending.version + 1);
+    }
+
+    #[tokio::test]
+    async fn if_match_stands_in_for_the_version() {
+        let (app, key, pending) = app().await;
+        let approve = format!("/moderation/{}/approve", pending.id);
+
+        let (status, body) = post(&app, &key, &approve, Some("\"stale\""), json!({})).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["current"]["version"], pending.version);
+
+        let etag = crate::caching::json_etag(&pending);
+        let (status, body) = post(&app, &key, &approve, Some(&etag), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "approved");
+    }
+}
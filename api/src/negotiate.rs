@@ -0,0 +1,428 @@
+use crate::auth::Session;
+use crate::caching::HTTP_DATE;
+use crate::template::AppError;
+use axum::extract::{FromRequestParts, Path};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+
+/// Path suffixes that pick a format regardless of the `Accept` header.
+pub const SUFFIXES: [&str; 4] = ["html", "json", "csv", "txt"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+    Csv,
+    Text,
+}
+
+impl Format {
+    fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "html" => Some(Format::Html),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "txt" => Some(Format::Text),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "text/html" | "application/xhtml+xml" => Some(Format::Html),
+            "application/json" => Some(Format::Json),
+            "text/csv" => Some(Format::Csv),
+            "text/plain" => Some(Format::Text),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Renders a service result in this format. `Ok(None)` becomes a 404
+    /// carrying `not_found` as its message. `session` is only used by
+    /// the html pages.
+    pub fn respond<T: Representation>(
+        self,
+        result: Result<Option<T>, sea_orm::DbErr>,
+        not_found: &str,
+        session: Option<&Session>,
+    ) -> Response {
+        let mut response = match result {
+            Ok(Some(representation)) => {
+                let mut response = self.render(&representation, session);
+                let last_modified = representation
+                    .last_modified()
+                    .map(|updated| updated.format(HTTP_DATE).to_string())
+                    .and_then(|updated| HeaderValue::from_str(&updated).ok());
+                if let Some(value) = last_modified {
+                    response.headers_mut().insert(header::LAST_MODIFIED, value);
+                }
+                response
+            }
+            Ok(None) => self.error(AppError::NotFound, not_found),
+            Err(e) => {
+                let message = format!("Internal error: {}", e);
+                self.error(AppError::Database(e), &message)
+            }
+        };
+
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept"));
+        response
+    }
+
+    fn render<T: Representation>(self, representation: &T, session: Option<&Session>) -> Response {
+        let body = match self {
+            Format::Html => match representation.html(session) {
+                Ok(body) => body,
+                Err(e) => return AppError::Render(e).into_response(),
+            },
+            Format::Json => return Json(json!(representation)).into_response(),
+            Format::Csv => representation.csv(),
+            Format::Text => representation.text(),
+        };
+
+        match self {
+            Format::Html => Html(body).into_response(),
+            _ => ([(header::CONTENT_TYPE, self.content_type())], body).into_response(),
+        }
+    }
+
+    pub(crate) fn error(self, error: AppError, message: &str) -> Response {
+        let status = match error {
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Conflict => StatusCode::CONFLICT,
+            AppError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        match self {
+            Format::Html => error.into_response(),
+            Format::Json => (status, Json(json!({ "error": message }))).into_response(),
+            Format::Csv | Format::Text => (
+                status,
+                [(header::CONTENT_TYPE, Format::Text.content_type())],
+                message.to_owned(),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// How a router picks a format when the path has no suffix.
+#[derive(Clone, Copy)]
+pub struct FormatPolicy {
+    pub default: Format,
+    /// `/api/*` ignores `Accept` so that it keeps answering in JSON
+    pub honor_accept: bool,
+}
+
+impl Default for FormatPolicy {
+    fn default() -> Self {
+        FormatPolicy {
+            default: Format::Html,
+            honor_accept: true,
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Format
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(format) = suffix_of(parts.uri.path()).and_then(Format::from_suffix) {
+            return Ok(format);
+        }
+
+        let policy = parts
+            .extensions
+            .get::<FormatPolicy>()
+            .copied()
+            .unwrap_or_default();
+
+        let accept = parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok());
+
+        match accept.filter(|_| policy.honor_accept).map(preferred_format) {
+            Some(Accepted::Format(format)) => Ok(format),
+            Some(Accepted::Nothing) => Err((
+                StatusCode::NOT_ACCEPTABLE,
+                [(header::VARY, "accept")],
+                "Available as text/html, application/json, text/csv or text/plain",
+            )
+                .into_response()),
+            Some(Accepted::Anything) | None => Ok(policy.default),
+        }
+    }
+}
+
+/// The id segment of an item route, with any format suffix (`12.json`)
+/// stripped off.
+pub struct ResourceId(pub i32);
+
+impl<S> FromRequestParts<S> for ResourceId
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(segment) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let id = match segment.rsplit_once('.') {
+            Some((id, suffix)) if Format::from_suffix(suffix).is_some() => id,
+            _ => segment.as_str(),
+        };
+
+        id.parse()
+            .map(ResourceId)
+            .map_err(|_| AppError::NotFound.into_response())
+    }
+}
+
+fn suffix_of(path: &str) -> Option<&str> {
+    let last_segment = path.rsplit('/').next()?;
+    last_segment.rsplit_once('.').map(|(_, suffix)| suffix)
+}
+
+/// What an `Accept` header leaves to choose from.
+#[derive(Debug, PartialEq)]
+enum Accepted {
+    Format(Format),
+    /// only wildcards like `*/*`, so the router's default
+    Anything,
+    /// none of the formats; answered with 406
+    Nothing,
+}
+
+// picks the supported media type with the highest q value; wildcards fall
+// through to the router's default
+fn preferred_format(accept: &str) -> Accepted {
+    let mut best: Option<(Format, f32)> = None;
+    let mut wildcard = accept.trim().is_empty();
+
+    for range in accept.split(',') {
+        let mut parameters = range.split(';');
+        let media_type = parameters.next().unwrap_or_default().trim();
+
+        let quality = parameters
+            .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality <= 0.0 {
+            continue;
+        }
+
+        match Format::from_media_type(&media_type.to_ascii_lowercase()) {
+            Some(format) => {
+                if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                    best = Some((format, quality));
+                }
+            }
+            None => wildcard |= media_type.ends_with("/*"),
+        }
+    }
+
+    match best {
+        Some((format, _)) => Accepted::Format(format),
+        None if wildcard => Accepted::Anything,
+        None => Accepted::Nothing,
+    }
+}
+
+/// A response body that can be rendered in every `Format`.
+pub trait Representation: Serialize {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error>;
+    fn csv(&self) -> String;
+    fn text(&self) -> String;
+
+    /// When this last changed, if that can be known; sent as `Last-Modified`.
+    fn last_modified(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or line break.
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[test]
+    fn the_highest_q_value_wins() {
+        assert_eq!(
+            preferred_format("text/html;q=0.5, application/json"),
+            Accepted::Format(Format::Json)
+        );
+        assert_eq!(
+            preferred_format("text/csv; charset=utf-8; q=0.9, text/plain;q=0.8"),
+            Accepted::Format(Format::Csv)
+        );
+        // the first of equals is kept
+        assert_eq!(
+            preferred_format("text/plain, text/csv"),
+            Accepted::Format(Format::Text)
+        );
+        assert_eq!(
+            preferred_format("TEXT/HTML"),
+            Accepted::Format(Format::Html)
+        );
+    }
+
+    #[test]
+    fn refused_and_unknown_types_are_skipped() {
+        assert_eq!(
+            preferred_format("application/json;q=0, text/plain;q=0.1"),
+            Accepted::Format(Format::Text)
+        );
+        assert_eq!(
+            preferred_format("image/png, text/csv;q=0.2"),
+            Accepted::Format(Format::Csv)
+        );
+        assert_eq!(
+            preferred_format("application/json;q=nope"),
+            Accepted::Format(Format::Json)
+        );
+    }
+
+    #[test]
+    fn wildcards_leave_the_choice_to_the_router() {
+        assert_eq!(preferred_format("*/*"), Accepted::Anything);
+        assert_eq!(
+            preferred_format("image/png, text/*;q=0.1"),
+            Accepted::Anything
+        );
+        assert_eq!(preferred_format(""), Accepted::Anything);
+        // a supported type still beats a wildcard with a higher q
+        assert_eq!(
+            preferred_format("*/*, application/json;q=0.1"),
+            Accepted::Format(Format::Json)
+        );
+    }
+
+    #[test]
+    fn nothing_supported_is_nothing() {
+        assert_eq!(preferred_format("image/png"), Accepted::Nothing);
+        assert_eq!(preferred_format("application/json;q=0"), Accepted::Nothing);
+        assert_eq!(preferred_format("*/*;q=0"), Accepted::Nothing);
+    }
+
+    #[test]
+    fn suffixes_come_from_the_last_segment() {
+        assert_eq!(suffix_of("/quotes/3.json"), Some("json"));
+        assert_eq!(suffix_of("/tags.csv"), Some("csv"));
+        assert_eq!(suffix_of("/quotes"), None);
+        assert_eq!(suffix_of("/v1.2/quotes"), None);
+    }
+
+    fn app(policy: FormatPolicy) -> Router {
+        Router::new()
+            .route(
+                "/quotes/{id}",
+                get(|format: Format, ResourceId(id): ResourceId| async move {
+                    format!("{:?} {}", format, id)
+                }),
+            )
+            .layer(axum::Extension(policy))
+    }
+
+    async fn negotiated(app: Router, path: &str, accept: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::get(path);
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_suffix_beats_the_accept_header() {
+        let app = app(FormatPolicy::default());
+
+        assert_eq!(
+            negotiated(app.clone(), "/quotes/3.txt", Some("application/json")).await,
+            (StatusCode::OK, "Text 3".to_owned())
+        );
+        assert_eq!(
+            negotiated(app.clone(), "/quotes/3", Some("text/csv")).await,
+            (StatusCode::OK, "Csv 3".to_owned())
+        );
+        assert_eq!(
+            negotiated(app.clone(), "/quotes/3", None).await,
+            (StatusCode::OK, "Html 3".to_owned())
+        );
+        // a suffix that isn't a format is part of the id
+        assert_eq!(
+            negotiated(app, "/quotes/3.xml", None).await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unsatisfiable_accept_header_gets_406() {
+        let (status, _) =
+            negotiated(app(FormatPolicy::default()), "/quotes/3", Some("image/png")).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn the_api_ignores_accept() {
+        let api = app(FormatPolicy {
+            default: Format::Json,
+            honor_accept: false,
+        });
+
+        assert_eq!(
+            negotiated(api.clone(), "/quotes/3", Some("image/png")).await,
+            (StatusCode::OK, "Json 3".to_owned())
+        );
+        assert_eq!(
+            negotiated(api, "/quotes/3.csv", None).await,
+            (StatusCode::OK, "Csv 3".to_owned())
+        );
+    }
+}
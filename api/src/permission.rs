@@ -0,0 +1,249 @@
+use crate::auth::{login_redirect, CurrentUser, Session};
+use crate::logging;
+use crate::logging::request_id;
+use crate::negotiate::Format;
+use crate::template::AppError;
+use crate::AppState;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use service::audit::AuditContext;
+use service::data_transfer_objects::{ApiKeyDTO, Permission, QuoteDTO, QuoteStatus, Role, UserDTO};
+use std::marker::PhantomData;
+
+/// Whoever is making a request: a user signed in to the html interface or an
+/// API key sent to `/api`.
+#[derive(Clone, Debug)]
+pub enum Actor {
+    User(Session),
+    ApiKey(ApiKeyDTO),
+}
+
+impl Actor {
+    pub fn role(&self) -> Role {
+        match self {
+            Actor::User(session) => session.user.role,
+            Actor::ApiKey(key) => key
+                .scopes
+                .iter()
+                .copied()
+                .max()
+                .map_or(Role::Viewer, Role::from),
+        }
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        match self {
+            Actor::User(session) => Some(session),
+            Actor::ApiKey(_) => None,
+        }
+    }
+
+    pub fn user(&self) -> Option<&UserDTO> {
+        self.session().map(|session| &session.user)
+    }
+
+    pub fn user_id(&self) -> Option<i32> {
+        self.user().map(|user| user.id)
+    }
+
+    /// How the actor appears in the audit log.
+    pub fn audit_label(&self) -> String {
+        match self {
+            Actor::User(session) => format!("user:{}", session.user.username),
+            Actor::ApiKey(key) => format!("api_key:{}", key.id),
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role().allows(permission)
+    }
+
+    pub fn can_edit(&self, quote: &QuoteDTO) -> bool {
+        quote.editable_by(self.role(), self.user_id())
+    }
+
+    pub fn can_view(&self, quote: &QuoteDTO) -> bool {
+        quote.visible_to(self.role(), self.user_id())
+    }
+
+    /// Submissions go to the moderation queue unless the submitter could
+    /// approve them anyway.
+    pub fn submission_status(&self) -> QuoteStatus {
+        if self.can(Permission::ModerateQuotes) {
+            QuoteStatus::Approved
+        } else {
+            QuoteStatus::Pending
+        }
+    }
+}
+
+/// `Option<Actor>` is `None` for anonymous requests.
+impl OptionalFromRequestParts<AppState> for Actor {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        // set by auth::require_api_key on the /api routes
+        let actor = match parts.extensions.get::<ApiKeyDTO>() {
+            Some(key) => Some(Actor::ApiKey(key.clone())),
+            None => {
+                let CurrentUser(session) = CurrentUser::from_request_parts(parts, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                session.map(Actor::User)
+            }
+        };
+
+        if let Some(actor) = &actor {
+            logging::record_actor(actor);
+        }
+
+        Ok(actor)
+    }
+}
+
+/// Ties a marker type to the permission `Authorized` checks for.
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+pub struct SubmitQuotes;
+pub struct EditOwnQuotes;
+pub struct ModerateQuotes;
+pub struct DeleteTags;
+pub struct DeleteAuthors;
+pub struct ManageUsers;
+pub struct ViewAuditLog;
+
+impl RequiredPermission for SubmitQuotes {
+    const PERMISSION: Permission = Permission::SubmitQuotes;
+}
+
+impl RequiredPermission for EditOwnQuotes {
+    const PERMISSION: Permission = Permission::EditOwnQuotes;
+}
+
+impl RequiredPermission for ModerateQuotes {
+    const PERMISSION: Permission = Permission::ModerateQuotes;
+}
+
+impl RequiredPermission for DeleteTags {
+    const PERMISSION: Permission = Permission::DeleteTags;
+}
+
+impl RequiredPermission for DeleteAuthors {
+    const PERMISSION: Permission = Permission::DeleteAuthors;
+}
+
+impl RequiredPermission for ManageUsers {
+    const PERMISSION: Permission = Permission::ManageUsers;
+}
+
+impl RequiredPermission for ViewAuditLog {
+    const PERMISSION: Permission = Permission::ViewAuditLog;
+}
+
+/// Extracts the `Actor` and rejects the request unless its role grants `P`.
+///
+/// Html requests without a session are sent to the login page; everything
+/// else gets a 401 or 403 in the negotiated format.
+pub struct Authorized<P> {
+    pub actor: Actor,
+    request_id: Option<String>,
+    permission: PhantomData<P>,
+}
+
+impl<P> Authorized<P> {
+    /// Who to record in the audit log for changes made by this request.
+    pub fn audit(&self) -> AuditContext {
+        AuditContext::new(self.actor.audit_label(), self.request_id.clone())
+    }
+}
+
+impl<P: RequiredPermission> FromRequestParts<AppState> for Authorized<P> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let format = Format::from_request_parts(parts, state).await?;
+
+        let actor =
+            <Actor as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state).await?;
+
+        let Some(actor) = actor else {
+            return Err(match format {
+                Format::Html => login_redirect(parts.uri.path()).into_response(),
+                _ => format.error(AppError::Unauthorized, "Sign in or send an API key"),
+            });
+        };
+
+        if !actor.can(P::PERMISSION) {
+            return Err(forbidden(format, P::PERMISSION.minimum_role()));
+        }
+
+        Ok(Authorized {
+            actor,
+            request_id: request_id(&parts.headers),
+            permission: PhantomData,
+        })
+    }
+}
+
+pub(crate) fn forbidden(format: Format, needed: Role) -> Response {
+    format.error(
+        AppError::Forbidden,
+        &format!("This needs the {} role", needed.as_str()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use service::data_transfer_objects::Scope;
+
+    fn key(scopes: &[Scope]) -> Actor {
+        Actor::ApiKey(ApiKeyDTO {
+            id: 1,
+            name: "test".to_owned(),
+            prefix: "qs_test".to_owned(),
+            scopes: scopes.to_vec(),
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        })
+    }
+
+    #[test]
+    fn write_keys_contribute_and_their_submissions_wait_for_moderation() {
+        let writer = key(&[Scope::Read, Scope::Write]);
+
+        assert_eq!(writer.role(), Role::Contributor);
+        assert!(!writer.can(Permission::DeleteTags));
+        assert_eq!(writer.submission_status(), QuoteStatus::Pending);
+    }
+
+    #[test]
+    fn moderate_and_admin_keys_publish_straight_away() {
+        assert_eq!(key(&[Scope::Moderate]).role(), Role::Moderator);
+        assert_eq!(key(&[Scope::Admin]).role(), Role::Admin);
+        assert_eq!(
+            key(&[Scope::Moderate]).submission_status(),
+            QuoteStatus::Approved
+        );
+        assert_eq!(
+            key(&[Scope::Admin]).submission_status(),
+            QuoteStatus::Approved
+        );
+    }
+
+    #[test]
+    fn keys_without_scopes_only_view() {
+        assert_eq!(key(&[]).role(), Role::Viewer);
+    }
+}
@@ -0,0 +1,458 @@
+use crate::negotiate::Format;
+use crate::template::AppError;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use service::data_transfer_objects::ApiKeyDTO;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+Token bucket rate limiting. Every client gets one bucket for reads (GET,
+HEAD, OPTIONS) and one for writes per route group; a bucket holds up to
+`requests` tokens and refills evenly over `period`. Clients are told where
+they stand with the `RateLimit-*` headers, and get a 429 with `Retry-After`
+once a bucket is empty.
+
+Buckets are forgotten by when they were last used, not by how full they are,
+so a client spreading requests over many addresses can't grow the map: one
+left alone for its whole period is full again and goes in the next sweep, and
+past `MAX_TRACKED_BUCKETS` the least recently used are dropped whatever they
+hold.
+*/
+
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+// how often buckets idle for their whole period are swept out
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many requests a client may make in a period, written like `60/min`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Budget {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Budget {
+    fn per_second(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for Budget {
+    type Err = String;
+
+    fn from_str(budget: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a budget like '60/min', got '{}'", budget);
+
+        let (requests, period) = budget.trim().split_once('/').ok_or_else(invalid)?;
+
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 {
+            return Err(invalid());
+        }
+
+        let period = match period.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Budget { requests, period })
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.period.as_secs() {
+            60 => write!(f, "{}/min", self.requests),
+            3600 => write!(f, "{}/hour", self.requests),
+            _ => write!(f, "{}/sec", self.requests),
+        }
+    }
+}
+
+impl TryFrom<String> for Budget {
+    type Error = String;
+
+    fn try_from(budget: String) -> Result<Self, Self::Error> {
+        budget.parse()
+    }
+}
+
+impl From<Budget> for String {
+    fn from(budget: Budget) -> Self {
+        budget.to_string()
+    }
+}
+
+/// The read and write budgets for one group of routes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimits {
+    pub reads: Budget,
+    pub writes: Budget,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    ApiKey(i32),
+    Ip(IpAddr),
+    // no connection info, e.g. when the router is driven without a listener
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Read,
+    Write,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: &Budget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second()).min(f64::from(budget.requests));
+        self.updated = now;
+    }
+}
+
+struct Decision {
+    allowed: bool,
+    budget: Budget,
+    remaining: u32,
+    // seconds until the bucket is full again
+    reset: u64,
+    // seconds until the next request would be let through
+    retry_after: u64,
+}
+
+struct Buckets {
+    buckets: HashMap<(Client, Kind), Bucket>,
+    last_sweep: Instant,
+}
+
+/// Shared state for the rate limiting middleware of one route group.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    trusted_proxies: Arc<[IpAddr]>,
+    capacity: usize,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// `trusted_proxies` are the addresses of reverse proxies whose
+    /// `X-Forwarded-For` header names the real client.
+    pub fn new(limits: RateLimits, trusted_proxies: Vec<IpAddr>) -> Self {
+        RateLimiter {
+            limits,
+            trusted_proxies: trusted_proxies.into(),
+            capacity: MAX_TRACKED_BUCKETS,
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    fn budget(&self, kind: Kind) -> Budget {
+        match kind {
+            Kind::Read => self.limits.reads,
+            Kind::Write => self.limits.writes,
+        }
+    }
+
+    fn take(&self, client: Client, kind: Kind) -> Decision {
+        self.take_at(client, kind, Instant::now())
+    }
+
+    fn take_at(&self, client: Client, kind: Kind, now: Instant) -> Decision {
+        let budget = self.budget(kind);
+
+        let mut guard = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Buckets {
+            buckets,
+            last_sweep,
+        } = &mut *guard;
+
+        if now.duration_since(*last_sweep) >= SWEEP_INTERVAL {
+            buckets.retain(|(_, kind), bucket| {
+                now.duration_since(bucket.updated) < self.budget(*kind).period
+            });
+            *last_sweep = now;
+        }
+
+        if buckets.len() >= self.capacity && !buckets.contains_key(&(client, kind)) {
+            // a tenth at a time, so the scan is paid for by the inserts that
+            // fill the room it makes
+            let mut by_age: Vec<((Client, Kind), Instant)> = buckets
+                .iter()
+                .map(|(key, bucket)| (*key, bucket.updated))
+                .collect();
+            let evict = (self.capacity / 10).clamp(1, by_age.len());
+            by_age.select_nth_unstable_by_key(evict - 1, |(_, updated)| *updated);
+            for (key, _) in &by_age[..evict] {
+                buckets.remove(key);
+            }
+        }
+
+        let bucket = buckets.entry((client, kind)).or_insert(Bucket {
+            tokens: f64::from(budget.requests),
+            updated: now,
+        });
+        bucket.refill(&budget, now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let seconds_for = |tokens: f64| (tokens.max(0.0) / budget.per_second()).ceil() as u64;
+
+        Decision {
+            allowed,
+            budget,
+            remaining: bucket.tokens.floor() as u32,
+            reset: seconds_for(f64::from(budget.requests) - bucket.tokens),
+            retry_after: seconds_for(1.0 - bucket.tokens).max(1),
+        }
+    }
+
+    fn client(&self, request: &Request) -> Client {
+        // set by auth::require_api_key, which runs first on the /api routes
+        if let Some(key) = request.extensions().get::<ApiKeyDTO>() {
+            return Client::ApiKey(key.id);
+        }
+
+        match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(peer)) => Client::Ip(self.client_ip(peer.ip(), request.headers())),
+            None => Client::Unknown,
+        }
+    }
+
+    // Only a trusted proxy gets to say who the client is. Walking the
+    // X-Forwarded-For hops from the nearest one back, the first address that
+    // isn't one of our proxies is the client; anything before it could have
+    // been made up by the client itself.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.trusted_proxies.contains(&ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// Takes a token from the caller's read or write bucket, answering `429`
+/// when there is none left.
+pub async fn limit(State(limiter): State<RateLimiter>, request: Request, next: Next) -> Response {
+    let kind = if request.method().is_safe() {
+        Kind::Read
+    } else {
+        Kind::Write
+    };
+
+    let decision = limiter.take(limiter.client(&request), kind);
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        let (mut parts, _) = request.into_parts();
+        // the 429 goes out even to a client that accepts none of the formats
+        let format = Format::from_request_parts(&mut parts, &())
+            .await
+            .unwrap_or(Format::Text);
+
+        let mut response = format.error(
+            AppError::TooManyRequests,
+            &format!("Too many requests, try again in {}s", decision.retry_after),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(decision.retry_after));
+        response
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        "ratelimit-limit",
+        HeaderValue::from(decision.budget.requests),
+    );
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset));
+    if let Ok(policy) = HeaderValue::from_str(&format!(
+        "{};w={}",
+        decision.budget.requests,
+        decision.budget.period.as_secs()
+    )) {
+        headers.insert("ratelimit-policy", policy);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests: u32, trusted_proxies: Vec<IpAddr>) -> RateLimiter {
+        let budget = Budget {
+            requests,
+            period: Duration::from_secs(60),
+        };
+
+        RateLimiter::new(
+            RateLimits {
+                reads: budget,
+                writes: budget,
+            },
+            trusted_proxies,
+        )
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn budgets_parse_and_print_the_same_way() {
+        let budget: Budget = "20/min".parse().unwrap();
+        assert_eq!(budget.requests, 20);
+        assert_eq!(budget.to_string(), "20/min");
+
+        assert!("0/min".parse::<Budget>().is_err());
+        assert!("20/fortnight".parse::<Budget>().is_err());
+    }
+
+    #[test]
+    fn empty_buckets_refill_over_the_period() {
+        let limiter = limiter(2, Vec::new());
+        let client = Client::Ip(ip("203.0.113.9"));
+        let start = Instant::now();
+
+        assert!(limiter.take_at(client, Kind::Write, start).allowed);
+        assert!(limiter.take_at(client, Kind::Write, start).allowed);
+
+        let refused = limiter.take_at(client, Kind::Write, start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, 30);
+
+        // reads have a bucket of their own
+        assert!(limiter.take_at(client, Kind::Read, start).allowed);
+
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.take_at(client, Kind::Write, later).allowed);
+        assert!(!limiter.take_at(client, Kind::Write, later).allowed);
+    }
+
+    #[test]
+    fn the_least_recently_used_buckets_go_first_whatever_they_hold() {
+        let mut limiter = limiter(10, Vec::new());
+        limiter.capacity = 10;
+        let start = Instant::now();
+
+        // every bucket is partly drained, so none is idle in the old sense
+        for i in 0..10 {
+            let client = Client::Ip(IpAddr::from([10, 0, 0, i]));
+            limiter.take_at(
+                client,
+                Kind::Read,
+                start + Duration::from_millis(u64::from(i)),
+            );
+        }
+        let newcomer = Client::Ip(ip("10.0.1.1"));
+        limiter.take_at(newcomer, Kind::Read, start + Duration::from_millis(20));
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 10);
+        assert!(!buckets
+            .buckets
+            .contains_key(&(Client::Ip(ip("10.0.0.0")), Kind::Read)));
+        assert!(buckets.buckets.contains_key(&(newcomer, Kind::Read)));
+    }
+
+    #[test]
+    fn buckets_idle_for_a_period_are_swept() {
+        let limiter = limiter(10, Vec::new());
+        let start = Instant::now();
+
+        limiter.take_at(Client::Ip(ip("10.0.0.1")), Kind::Read, start);
+        limiter.take_at(
+            Client::Ip(ip("10.0.0.2")),
+            Kind::Read,
+            start + Duration::from_secs(30),
+        );
+        limiter.take_at(
+            Client::Ip(ip("10.0.0.3")),
+            Kind::Read,
+            start + Duration::from_secs(61),
+        );
+
+        let buckets = limiter.buckets.lock().unwrap();
+        let clients: Vec<Client> = buckets.buckets.keys().map(|(client, _)| *client).collect();
+        assert_eq!(clients.len(), 2);
+        assert!(!clients.contains(&Client::Ip(ip("10.0.0.1"))));
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_a_trusted_proxy() {
+        let limiter = limiter(10, Vec::new());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.7"));
+
+        assert_eq!(
+            limiter.client_ip(ip("203.0.113.9"), &headers),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn a_trusted_proxy_names_the_first_untrusted_hop() {
+        let limiter = limiter(10, vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        let mut headers = HeaderMap::new();
+        // the client made up the first entry; 10.0.0.2 added the real one
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.2.3.4, 198.51.100.7, 10.0.0.2"),
+        );
+
+        assert_eq!(
+            limiter.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use crate::auth::{CurrentUser, Session};
+use crate::json::{
+    AuthorAndAssociatedQuotesResponse, AuthorResponse, Params, QuoteResponse,
+    TagAndRelatedQuotesResponse, TagResponse,
+};
+use crate::negotiate::{csv_field, Format, Representation, ResourceId};
+use crate::permission::Actor;
+use crate::template::{
+    AuthorTemplate, AuthorsTemplate, QuoteTemplate, QuotesTemplate, TagTemplate, TagsTemplate,
+};
+use crate::AppState;
+use askama::Template;
+use axum::extract::{Query, State};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use service::data_access::DataAccess;
+use service::data_transfer_objects::{AuthorDTO, QuoteDTO, QuoteStatus, TagDTO};
+
+/*
+One handler per resource, shared by the HTML routes and the `/api` alias.
+The service is called once and the result is rendered in whichever format
+the request negotiated (see negotiate::Format).
+*/
+
+#[utoipa::path(
+    get,
+    path = "/api/quotes",
+    params(Params),
+    responses(
+        (status = 200, description = "List of quotes", body = QuoteResponse),
+        (status = 404, description = "No quotes found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_quotes(
+    state: State<AppState>,
+    format: Format,
+    CurrentUser(session): CurrentUser,
+    Query(params): Query<Params>,
+) -> Response {
+    let (page, page_size) = params.resolve(&state.pagination);
+
+    let result = DataAccess::get_quotes_in_page(&state.db_conn, page, page_size)
+        .await
+        .map(|found| found.map(|(quotes, pages)| QuoteResponse { quotes, pages }));
+
+    format.respond(result, "No quotes found", session.as_ref())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/quotes/{quote_id}",
+    responses(
+        (status = 200, description = "Single quote", body = QuoteDTO),
+        (status = 404, description = "Quote not found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_single_quote(
+    state: State<AppState>,
+    format: Format,
+    actor: Option<Actor>,
+    ResourceId(quote_id): ResourceId,
+) -> Response {
+    let result = DataAccess::get_quote(&state.db_conn, quote_id)
+        .await
+        .map(|found| {
+            found.filter(|quote| match &actor {
+                Some(actor) => actor.can_view(quote),
+                None => quote.status == QuoteStatus::Approved,
+            })
+        });
+
+    let session = actor.as_ref().and_then(Actor::session);
+
+    format.respond(result, "Quote not found", session)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tags",
+    params(Params),
+    responses(
+        (status = 200, description = "List of tags", body = TagResponse),
+        (status = 404, description = "No tags found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_tags(
+    state: State<AppState>,
+    format: Format,
+    CurrentUser(session): CurrentUser,
+    Query(params): Query<Params>,
+) -> Response {
+    let (page, page_size) = params.resolve(&state.pagination);
+
+    let result = match DataAccess::get_tags_in_page(&state.db_conn, page, page_size).await {
+        Ok(Some((tags, pages))) => Ok(Some(TagResponse { tags, pages })),
+        // the html page lists no tags rather than showing an error
+        Ok(None) if format == Format::Html => Ok(Some(TagResponse {
+            tags: Vec::new(),
+            pages: 0,
+        })),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    format.respond(result, "No tags found", session.as_ref())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tags/{tag_id}",
+    params(Params),
+    responses(
+        (status = 200, description = "Tag and associated quotes", body = TagAndRelatedQuotesResponse),
+        (status = 404, description = "Tag not found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_tag_and_associated_quotes(
+    state: State<AppState>,
+    format: Format,
+    CurrentUser(session): CurrentUser,
+    ResourceId(tag_id): ResourceId,
+    Query(params): Query<Params>,
+) -> Response {
+    let (page, page_size) = params.resolve(&state.pagination);
+
+    let result = DataAccess::get_tag_with_related_quotes(&state.db_conn, tag_id, page, page_size)
+        .await
+        .map(|found| {
+            found.map(|(tag, quotes, pages)| TagAndRelatedQuotesResponse { tag, quotes, pages })
+        });
+
+    format.respond(result, "Tag not found", session.as_ref())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/authors",
+    params(Params),
+    responses(
+        (status = 200, description = "List of authors", body = AuthorResponse),
+        (status = 404, description = "No authors found"),
+        (status = 500, description = "Internal server error")
+))]
+pub async fn get_authors(
+    state: State<AppState>,
+    format: Format,
+    CurrentUser(session): CurrentUser,
+    Query(params): Query<Params>,
+) -> Response {
+    let (page, page_size) = params.resolve(&state.pagination);
+
+    let result = match DataAccess::get_authors_in_page(&state.db_conn, page, page_size).await {
+        Ok(Some((authors, pages))) => Ok(Some(AuthorResponse { authors, pages })),
+        // the html page lists no authors rather than showing an error
+        Ok(None) if format == Format::Html => Ok(Some(AuthorResponse {
+            authors: Vec::new(),
+            pages: 0,
+        })),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    format.respond(result, "No authors found", session.as_ref())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/authors/{author_id}",
+    params(Params),
+    responses(
+        (status = 200, description = "Author and associated quotes", body = AuthorAndAssociatedQuotesResponse),
+        (status = 404, description = "Author not found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+pub async fn get_author_and_associated_quotes(
+    state: State<AppState>,
+    format: Format,
+    CurrentUser(session): CurrentUser,
+    ResourceId(author_id): ResourceId,
+    Query(params): Query<Params>,
+) -> Response {
+    let (page, page_size) = params.resolve(&state.pagination);
+
+    let result =
+        DataAccess::get_author_with_related_quotes(&state.db_conn, author_id, page, page_size)
+            .await
+            .map(|found| {
+                found.map(
+                    |(author, quotes, pages)| AuthorAndAssociatedQuotesResponse {
+                        author,
+                        quotes,
+                        pages,
+                    },
+                )
+            });
+
+    format.respond(result, "Author not found", session.as_ref())
+}
+
+const QUOTE_CSV_HEADER: &str = "id,quote,author,tags,source,created_at,updated_at\n";
+
+fn quote_csv_row(quote: &QuoteDTO) -> String {
+    let tags: Vec<&str> = quote
+        .related_tags
+        .iter()
+        .map(|tag| tag.tag.as_str())
+        .collect();
+
+    format!(
+        "{},{},{},{},{},{},{}\n",
+        quote.id,
+        csv_field(&quote.quote),
+        csv_field(&quote.author.name),
+        csv_field(&tags.join(";")),
+        csv_field(quote.source.as_deref().unwrap_or_default()),
+        quote.created_at.to_rfc3339(),
+        quote.updated_at.to_rfc3339()
+    )
+}
+
+fn quotes_csv(quotes: &[QuoteDTO]) -> String {
+    let mut csv = QUOTE_CSV_HEADER.to_owned();
+    for quote in quotes {
+        csv.push_str(&quote_csv_row(quote));
+    }
+    csv
+}
+
+fn quote_text(quote: &QuoteDTO) -> String {
+    let mut text = format!("{}\n    -- {}\n", quote.quote, quote.author.name);
+
+    if !quote.related_tags.is_empty() {
+        let tags: Vec<&str> = quote
+            .related_tags
+            .iter()
+            .map(|tag| tag.tag.as_str())
+            .collect();
+        text.push_str(&format!("    [{}]\n", tags.join(", ")));
+    }
+
+    text
+}
+
+fn quotes_text(quotes: &[QuoteDTO]) -> String {
+    quotes.iter().map(quote_text).collect::<Vec<_>>().join("\n")
+}
+
+fn tags_csv(tags: &[TagDTO]) -> String {
+    let mut csv = String::from("id,tag\n");
+    for tag in tags {
+        csv.push_str(&format!("{},{}\n", tag.id, csv_field(&tag.tag)));
+    }
+    csv
+}
+
+fn authors_csv(authors: &[AuthorDTO]) -> String {
+    let mut csv = String::from("id,name\n");
+    for author in authors {
+        csv.push_str(&format!("{},{}\n", author.id, csv_field(&author.name)));
+    }
+    csv
+}
+
+impl Representation for QuoteDTO {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        QuoteTemplate {
+            quote: self,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        format!("{}{}", QUOTE_CSV_HEADER, quote_csv_row(self))
+    }
+
+    fn text(&self) -> String {
+        quote_text(self)
+    }
+
+    fn last_modified(&self) -> Option<DateTime<Utc>> {
+        Some(self.updated_at)
+    }
+}
+
+impl Representation for QuoteResponse {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        QuotesTemplate {
+            quotes: &self.quotes,
+            pages: self.pages,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        quotes_csv(&self.quotes)
+    }
+
+    fn text(&self) -> String {
+        quotes_text(&self.quotes)
+    }
+}
+
+impl Representation for TagResponse {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        TagsTemplate {
+            tags: &self.tags,
+            pages: self.pages,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        tags_csv(&self.tags)
+    }
+
+    fn text(&self) -> String {
+        self.tags
+            .iter()
+            .map(|tag| format!("{}\n", tag.tag))
+            .collect()
+    }
+}
+
+impl Representation for AuthorResponse {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        AuthorsTemplate {
+            authors: &self.authors,
+            pages: self.pages,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        authors_csv(&self.authors)
+    }
+
+    fn text(&self) -> String {
+        self.authors
+            .iter()
+            .map(|author| format!("{}\n", author.name))
+            .collect()
+    }
+}
+
+impl Representation for TagAndRelatedQuotesResponse {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        TagTemplate {
+            tag: &self.tag,
+            quotes: &self.quotes,
+            pages: self.pages,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        quotes_csv(&self.quotes)
+    }
+
+    fn text(&self) -> String {
+        format!("Tag: {}\n\n{}", self.tag.tag, quotes_text(&self.quotes))
+    }
+}
+
+impl Representation for AuthorAndAssociatedQuotesResponse {
+    fn html(&self, session: Option<&Session>) -> Result<String, askama::Error> {
+        AuthorTemplate {
+            author: &self.author,
+            quotes: &self.quotes,
+            pages: self.pages,
+            session,
+        }
+        .render()
+    }
+
+    fn csv(&self) -> String {
+        quotes_csv(&self.quotes)
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "Author: {}\n\n{}",
+            self.author.name,
+            quotes_text(&self.quotes)
+        )
+    }
+}
@@ -0,0 +1,458 @@
+use crate::csrf::constant_time_eq;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/*
+Bot protection for the html quote and registration forms. Nothing about an
+open form is kept on the server: the form carries a signed token saying when
+it was served and who it was served to. The sum it asks isn't in the token,
+only an HMAC of its answer, so the answer can't be read off the page. Each
+token is good for one try; the server remembers the ones it has seen until
+they would have expired anyway. A quote is checked for
+
+- the honeypot: a field hidden from people with css, which bots fill in
+- a token that is missing, forged, or came back faster than a person types
+- the answer to the sum
+- links in the quote or author, and keywords that only spam uses
+
+A wrong answer, an expired form or a form sent twice just shows the form
+again, with a new question. Anything else
+that fails is logged, then either held for moderation with the reasons or
+refused, depending on `SpamAction`. Registrations get the first three checks
+(`check_form`), and one that fails is always refused: there is no queue for
+accounts.
+*/
+
+/// Name of the honeypot field.
+pub const HONEYPOT_FIELD: &str = "website";
+
+// people take longer than this to type a quote
+const MIN_FILL_SECONDS: i64 = 3;
+const MAX_FORM_AGE_SECONDS: i64 = 2 * 60 * 60;
+
+const BLOCKED_KEYWORDS: &[&str] = &[
+    "viagra",
+    "cialis",
+    "casino",
+    "payday loan",
+    "crypto giveaway",
+    "buy followers",
+    "seo services",
+    "porn",
+];
+
+const LINK_MARKERS: &[&str] = &["http://", "https://", "www.", "[url"];
+
+/// What to do with a submission the spam checks catch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamAction {
+    /// keep it as a pending quote, flagged with the reasons
+    #[default]
+    Moderate,
+    /// refuse it
+    Reject,
+}
+
+impl FromStr for SpamAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action.trim().to_lowercase().as_str() {
+            "moderate" => Ok(SpamAction::Moderate),
+            "reject" => Ok(SpamAction::Reject),
+            other => Err(format!("unknown spam action '{}'", other)),
+        }
+    }
+}
+
+/// A sum to ask, and the signed token to send with the form.
+pub struct Challenge {
+    pub question: String,
+    pub token: String,
+}
+
+/// The fields every guarded form sends back.
+pub struct FormFields<'a> {
+    pub form_token: &'a str,
+    pub answer: &'a str,
+    pub honeypot: &'a str,
+}
+
+/// The fields of a quote submission the checks look at.
+pub struct Submission<'a> {
+    pub form: FormFields<'a>,
+    pub quote: &'a str,
+    pub author_name: &'a str,
+    pub source: Option<&'a str>,
+}
+
+pub enum Verdict {
+    Pass,
+    /// show the form again with this message
+    Retry(&'static str),
+    /// looks like spam, for these reasons
+    Spam(Vec<String>),
+}
+
+/// Signs and checks quote forms. The key is made when the server starts, so
+/// forms served before a restart have to be filled in again.
+#[derive(Clone)]
+pub struct SpamGuard {
+    key: Arc<[u8; 32]>,
+    // nonces of the tokens already sent back, with when they were issued
+    used: Arc<Mutex<HashMap<String, i64>>>,
+    pub action: SpamAction,
+}
+
+// what a valid token says
+struct FormToken<'a> {
+    age: i64,
+    issued: i64,
+    nonce: &'a str,
+    answer_mac: &'a str,
+}
+
+impl SpamGuard {
+    pub fn new(action: SpamAction) -> Self {
+        SpamGuard {
+            key: Arc::new(rand::random()),
+            used: Arc::new(Mutex::new(HashMap::new())),
+            action,
+        }
+    }
+
+    /// A new question for a form served to `subject`, e.g. a user id or the
+    /// form's CSRF token; the token it comes with is only good for them.
+    pub fn challenge(&self, subject: &str) -> Challenge {
+        let mut rng = rand::thread_rng();
+        let (a, b): (u32, u32) = (rng.gen_range(1..=9), rng.gen_range(1..=9));
+
+        Challenge {
+            question: format!("What is {} plus {}?", a, b),
+            token: self.issue(subject, chrono::Utc::now().timestamp(), a + b),
+        }
+    }
+
+    // `issued.nonce.answer_mac.signature`
+    fn issue(&self, subject: &str, issued: i64, answer: u32) -> String {
+        let nonce: String = rand::random::<[u8; 16]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let payload = format!("{}.{}", issued, nonce);
+        let answer_mac = self.sign(subject, &format!("{}.{}", payload, answer));
+        let payload = format!("{}.{}", payload, answer_mac);
+
+        format!("{}.{}", payload, self.sign(subject, &payload))
+    }
+
+    /// Checks the honeypot, the form token and the answer.
+    pub fn check_form(&self, subject: &str, form: &FormFields) -> Verdict {
+        match self.form_reasons(subject, form) {
+            Err(message) => Verdict::Retry(message),
+            Ok(reasons) if reasons.is_empty() => Verdict::Pass,
+            Ok(reasons) => Verdict::Spam(reasons),
+        }
+    }
+
+    /// Checks the form, then the quote itself for links and keywords.
+    pub fn check(&self, subject: &str, submission: &Submission) -> Verdict {
+        let mut reasons = match self.form_reasons(subject, &submission.form) {
+            Ok(reasons) => reasons,
+            Err(message) => return Verdict::Retry(message),
+        };
+
+        if has_link(submission.quote) || has_link(submission.author_name) {
+            reasons.push("link in the quote or author".to_owned());
+        }
+
+        // a single link to where the quote came from is fine
+        let source_links = submission.source.map_or(0, count_links);
+        if source_links > 1 {
+            reasons.push(format!("{} links in the source", source_links));
+        }
+
+        let text = format!(
+            "{} {} {}",
+            submission.quote,
+            submission.author_name,
+            submission.source.unwrap_or_default()
+        )
+        .to_lowercase();
+        for keyword in BLOCKED_KEYWORDS {
+            if text.contains(keyword) {
+                reasons.push(format!("contains '{}'", keyword));
+            }
+        }
+
+        if reasons.is_empty() {
+            Verdict::Pass
+        } else {
+            Verdict::Spam(reasons)
+        }
+    }
+
+    // what's wrong with the form, or a message to show it again with
+    fn form_reasons(&self, subject: &str, form: &FormFields) -> Result<Vec<String>, &'static str> {
+        let mut reasons = Vec::new();
+
+        if !form.honeypot.trim().is_empty() {
+            reasons.push("filled in the hidden field".to_owned());
+        }
+
+        let Some(token) = self.read_token(subject, form.form_token) else {
+            reasons.push("form token missing or forged".to_owned());
+            return Ok(reasons);
+        };
+
+        if token.age > MAX_FORM_AGE_SECONDS {
+            return Err("The form expired, please try again.");
+        }
+        if !self.first_use(&token) {
+            return Err("That form was already sent, please try again.");
+        }
+
+        if token.age < MIN_FILL_SECONDS {
+            reasons.push(format!(
+                "sent {}s after the form was served",
+                token.age.max(0)
+            ));
+        } else {
+            let answer = form.answer.trim().parse::<u32>().unwrap_or(u32::MAX);
+            let expected = self.sign(
+                subject,
+                &format!("{}.{}.{}", token.issued, token.nonce, answer),
+            );
+            if !constant_time_eq(token.answer_mac.as_bytes(), expected.as_bytes()) {
+                return Err("That answer to the question isn't right.");
+            }
+        }
+
+        Ok(reasons)
+    }
+
+    // the token's parts and the form's age in seconds, if the token is ours
+    fn read_token<'a>(&self, subject: &str, token: &'a str) -> Option<FormToken<'a>> {
+        let (payload, signature) = token.rsplit_once('.')?;
+
+        if !constant_time_eq(signature.as_bytes(), self.sign(subject, payload).as_bytes()) {
+            return None;
+        }
+
+        let mut parts = payload.split('.');
+        let issued: i64 = parts.next()?.parse().ok()?;
+        let nonce = parts.next()?;
+        let answer_mac = parts.next()?;
+
+        Some(FormToken {
+            age: chrono::Utc::now().timestamp() - issued,
+            issued,
+            nonce,
+            answer_mac,
+        })
+    }
+
+    // remembers the token's nonce; false if it was sent back before
+    fn first_use(&self, token: &FormToken) -> bool {
+        let mut used = self
+            .used
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // expired tokens are refused before they get here
+        let oldest = chrono::Utc::now().timestamp() - MAX_FORM_AGE_SECONDS;
+        used.retain(|_, issued| *issued >= oldest);
+
+        used.insert(token.nonce.to_owned(), token.issued).is_none()
+    }
+
+    fn sign(&self, subject: &str, payload: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.key.as_slice())
+            .expect("hmac takes keys of any length");
+        mac.update(format!("{}:{}", subject, payload).as_bytes());
+
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+// words that look like a link, so `https://www.example.com` counts once
+fn count_links(text: &str) -> usize {
+    text.to_lowercase()
+        .split_whitespace()
+        .filter(|word| LINK_MARKERS.iter().any(|marker| word.contains(marker)))
+        .count()
+}
+
+fn has_link(text: &str) -> bool {
+    count_links(text) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a token for a sum of 3 served `age` seconds ago
+    fn token(guard: &SpamGuard, subject: &str, age: i64) -> String {
+        guard.issue(subject, chrono::Utc::now().timestamp() - age, 3)
+    }
+
+    fn form<'a>(token: &'a str, answer: &'a str) -> FormFields<'a> {
+        FormFields {
+            form_token: token,
+            answer,
+            honeypot: "",
+        }
+    }
+
+    #[test]
+    fn a_form_filled_in_at_human_speed_passes() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let token = token(&guard, "user:1", 10);
+
+        assert!(matches!(
+            guard.check_form("user:1", &form(&token, "3")),
+            Verdict::Pass
+        ));
+    }
+
+    #[test]
+    fn tokens_only_work_for_the_subject_they_were_served_to() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let token = token(&guard, "user:1", 10);
+
+        let Verdict::Spam(reasons) = guard.check_form("user:2", &form(&token, "3")) else {
+            panic!("a token served to someone else passed");
+        };
+        assert_eq!(reasons, ["form token missing or forged"]);
+
+        // nor for a server with another key
+        let other = SpamGuard::new(SpamAction::Moderate);
+        assert!(matches!(
+            other.check_form("user:1", &form(&token, "3")),
+            Verdict::Spam(_)
+        ));
+    }
+
+    #[test]
+    fn fast_and_expired_forms_are_caught() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+
+        let fast = token(&guard, "user:1", 0);
+        assert!(matches!(
+            guard.check_form("user:1", &form(&fast, "3")),
+            Verdict::Spam(_)
+        ));
+
+        let expired = token(&guard, "user:1", MAX_FORM_AGE_SECONDS + 1);
+        assert!(matches!(
+            guard.check_form("user:1", &form(&expired, "3")),
+            Verdict::Retry(_)
+        ));
+    }
+
+    #[test]
+    fn the_token_does_not_give_away_the_answer() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let first = guard.issue("user:1", 1_792_390_556, 7);
+        let second = guard.issue("user:1", 1_792_390_556, 7);
+
+        // when it was served, a nonce and two macs, and nothing else
+        let parts: Vec<&str> = first.split('.').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "1792390556");
+        assert_eq!(
+            parts[1..].iter().map(|part| part.len()).collect::<Vec<_>>(),
+            [32, 64, 64]
+        );
+        assert!(parts[1..]
+            .iter()
+            .all(|part| part.chars().all(|c| c.is_ascii_hexdigit())));
+
+        // the same answer looks different every time, so there's nothing to
+        // look it up by
+        let answer_mac = |token: &str| token.split('.').nth(2).unwrap().to_owned();
+        assert_ne!(answer_mac(&first), answer_mac(&second));
+    }
+
+    #[test]
+    fn a_token_is_good_for_one_try() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let token = token(&guard, "user:1", 10);
+
+        assert!(matches!(
+            guard.check_form("user:1", &form(&token, "3")),
+            Verdict::Pass
+        ));
+        assert!(matches!(
+            guard.check_form("user:1", &form(&token, "3")),
+            Verdict::Retry(_)
+        ));
+
+        // a clone shares what it has seen
+        let clone = guard.clone();
+        assert!(matches!(
+            clone.check_form("user:1", &form(&token, "3")),
+            Verdict::Retry(_)
+        ));
+    }
+
+    #[test]
+    fn wrong_answers_and_filled_honeypots() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let token = token(&guard, "user:1", 10);
+
+        assert!(matches!(
+            guard.check_form("user:1", &form(&token, "4")),
+            Verdict::Retry(_)
+        ));
+        // the wrong guess used the token up
+        assert!(matches!(
+            guard.check_form("user:1", &form(&token, "3")),
+            Verdict::Retry(_)
+        ));
+
+        let token = self::token(&guard, "user:1", 10);
+        let mut filled = form(&token, "3");
+        filled.honeypot = "https://spam.example";
+        assert!(matches!(
+            guard.check_form("user:1", &filled),
+            Verdict::Spam(_)
+        ));
+    }
+
+    #[test]
+    fn quotes_are_checked_for_links_and_keywords() {
+        let guard = SpamGuard::new(SpamAction::Moderate);
+        let (first, second) = (token(&guard, "user:1", 10), token(&guard, "user:1", 10));
+        let submission = |token, quote, source| Submission {
+            form: form(token, "3"),
+            quote,
+            author_name: "Ann",
+            source,
+        };
+
+        let one_source_link = submission(&first, "Be kind.", Some("https://example.com/kindness"));
+        assert!(matches!(
+            guard.check("user:1", &one_source_link),
+            Verdict::Pass
+        ));
+
+        let Verdict::Spam(reasons) = guard.check(
+            "user:1",
+            &submission(&second, "Visit www.example.com for a casino bonus", None),
+        ) else {
+            panic!("spam passed");
+        };
+        assert_eq!(reasons.len(), 2);
+    }
+}
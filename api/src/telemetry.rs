@@ -0,0 +1,207 @@
+use crate::metrics::statement_kind;
+use axum::http::HeaderMap;
+use opentelemetry::trace::{Span as _, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use sea_orm::metric::Info;
+use std::time::SystemTime;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/*
+OpenTelemetry support for the request spans. `tracing` spans become OTLP
+spans through the layer set up in main.rs; this adds what that layer can't
+see on its own:
+
+- the caller's trace, from a W3C `traceparent` header, as the parent of the
+  request span. The propagator parses ids leniently, so a header that
+  isn't exactly the shape the spec gives is ignored first
+- a client span for every SQL statement, under the `DataAccess` call that
+  ran it
+
+Without an exporter configured both are no-ops.
+*/
+
+const TRACER_NAME: &str = "quote-server";
+
+const TRACEPARENT: &str = "traceparent";
+
+/// Continues the trace named in the request's `traceparent` header, if any.
+pub(crate) fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let traceparent = headers
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok());
+    if !traceparent.is_some_and(is_well_formed) {
+        return;
+    }
+
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+
+    if parent.span().span_context().is_valid() {
+        // only fails if the span is already closed, which it can't be yet
+        let _ = span.set_parent(parent);
+    }
+}
+
+// `version-trace_id-parent_id-flags` in lowercase hex. Later versions may
+// add fields after the flags, version 00 may not
+fn is_well_formed(traceparent: &str) -> bool {
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+
+    let fields: Vec<&str> = traceparent.trim().split('-').collect();
+    match fields.as_slice() {
+        [version, trace_id, parent_id, flags, rest @ ..] => {
+            is_hex(version, 2)
+                && *version != "ff"
+                && (rest.is_empty() || *version != "00")
+                && is_hex(trace_id, 32)
+                && is_hex(parent_id, 16)
+                && is_hex(flags, 2)
+        }
+        _ => false,
+    }
+}
+
+/// Records a finished statement as a span of its own. sea-orm reports a
+/// query once it is done, so the span is backdated by its duration.
+pub(crate) fn export_query(info: &Info<'_>) {
+    let parent = Span::current().context();
+    if !parent.span().span_context().is_sampled() {
+        return;
+    }
+
+    let end = SystemTime::now();
+    let start = end.checked_sub(info.elapsed).unwrap_or(end);
+    let kind = statement_kind(&info.statement.sql);
+
+    let tracer = global::tracer(TRACER_NAME);
+    let mut span = tracer
+        .span_builder(kind.to_uppercase())
+        .with_kind(SpanKind::Client)
+        .with_start_time(start)
+        .with_attributes([
+            KeyValue::new("db.system.name", "sqlite"),
+            KeyValue::new("db.operation.name", kind.to_uppercase()),
+            KeyValue::new("db.query.text", info.statement.sql.clone()),
+        ])
+        .start_with_context(&tracer, &parent);
+
+    if info.failed {
+        span.set_status(Status::error("query failed"));
+    }
+    span.end_with_timestamp(end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use opentelemetry::trace::{SpanId, SpanKind, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    // the server spans of requests to a route behind the request trace
+    // layer, each sent with the given `traceparent`
+    async fn server_spans(traceparents: &[Option<&str>]) -> Vec<SpanData> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME)));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route("/quotes", get(|| async { "ok" }))
+            .layer(crate::logging::trace_layer());
+        for traceparent in traceparents {
+            let mut request = Request::get("/quotes");
+            if let Some(traceparent) = traceparent {
+                request = request.header("traceparent", *traceparent);
+            }
+            app.clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        provider.force_flush().unwrap();
+        exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| span.span_kind == SpanKind::Server)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_traceparent_becomes_the_server_spans_parent() {
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        let spans = server_spans(&[Some(&traceparent)]).await;
+
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(span.name, "GET /quotes");
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+        assert!(span.parent_span_is_remote);
+    }
+
+    #[tokio::test]
+    async fn a_malformed_traceparent_starts_a_new_trace() {
+        let zeros = "0".repeat(32);
+        let malformed = [
+            None,
+            Some("".to_owned()),
+            Some("garbage".to_owned()),
+            // no flags
+            Some(format!("00-{}-{}", TRACE_ID, PARENT_ID)),
+            // too short a trace id
+            Some(format!("00-{}-{}-01", &TRACE_ID[..30], PARENT_ID)),
+            // not hex
+            Some(format!("00-{}-{}-01", "z".repeat(32), PARENT_ID)),
+            // all-zero ids are invalid
+            Some(format!("00-{}-{}-01", zeros, PARENT_ID)),
+            Some(format!("00-{}-{}-01", TRACE_ID, &zeros[..16])),
+            // version ff is forbidden, and 00 has nothing after the flags
+            Some(format!("ff-{}-{}-01", TRACE_ID, PARENT_ID)),
+            Some(format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID)),
+            Some(format!("00-{}-{}-01", TRACE_ID.to_uppercase(), PARENT_ID)),
+        ];
+        let traceparents: Vec<Option<&str>> =
+            malformed.iter().map(|value| value.as_deref()).collect();
+
+        let spans = server_spans(&traceparents).await;
+
+        assert_eq!(spans.len(), malformed.len());
+        for (span, traceparent) in spans.iter().zip(&malformed) {
+            assert_eq!(span.parent_span_id, SpanId::INVALID, "{:?}", traceparent);
+            assert!(!span.parent_span_is_remote, "{:?}", traceparent);
+            assert_ne!(
+                span.span_context.trace_id(),
+                TraceId::from_hex(TRACE_ID).unwrap(),
+                "{:?}",
+                traceparent
+            );
+        }
+    }
+}
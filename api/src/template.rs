@@ -1,70 +1,87 @@
 use askama::Template;
 use serde::Deserialize;
+use service::accounts::AccountError;
 use service::data_access::DataAccess;
 use service::data_transfer_objects::AuthorDTO;
+use service::data_transfer_objects::Permission;
 use service::data_transfer_objects::QuoteCreateDTO;
 use service::data_transfer_objects::QuoteDTO;
+use service::data_transfer_objects::QuoteStatus;
+use service::data_transfer_objects::Role;
 use service::data_transfer_objects::TagDTO;
+use service::data_transfer_objects::UserDTO;
+use service::duplicates::DuplicateVerdict;
 
+use super::auth::Session;
+use super::caching;
+use super::permission::{
+    Actor, Authorized, DeleteAuthors, DeleteTags, EditOwnQuotes, ManageUsers, SubmitQuotes,
+};
+use super::spam::{self, Challenge, SpamAction, Verdict};
 use super::AppState;
 
 use axum::{
-    extract::{Form, Path, Query, State},
-    http::StatusCode,
+    extract::{Form, Path, State},
+    http::{HeaderMap, StatusCode},
     response::{Html, IntoResponse, Redirect, Response},
 };
 
 #[derive(Template)]
 #[template(path = "./quote.html")]
-struct QuoteTemplate {
-    quote: QuoteDTO,
+pub(crate) struct QuoteTemplate<'a> {
+    pub(crate) quote: &'a QuoteDTO,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./quotes.html")]
-struct QuotesTemplate {
-    quotes: Vec<QuoteDTO>,
-    pages: u64,
+pub(crate) struct QuotesTemplate<'a> {
+    pub(crate) quotes: &'a [QuoteDTO],
+    pub(crate) pages: u64,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./tag.html")]
-struct TagTemplate {
-    tag: service::data_transfer_objects::TagDTO,
-    quotes: Vec<QuoteDTO>,
-    pages: u64,
+pub(crate) struct TagTemplate<'a> {
+    pub(crate) tag: &'a TagDTO,
+    pub(crate) quotes: &'a [QuoteDTO],
+    pub(crate) pages: u64,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./author.html")]
-struct AuthorTemplate {
-    author: service::data_transfer_objects::AuthorDTO,
-    quotes: Vec<QuoteDTO>,
-    pages: u64,
+pub(crate) struct AuthorTemplate<'a> {
+    pub(crate) author: &'a AuthorDTO,
+    pub(crate) quotes: &'a [QuoteDTO],
+    pub(crate) pages: u64,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./authors.html")]
-struct AuthorsTemplate {
-    authors: Vec<AuthorDTO>,
-    pages: u64,
+pub(crate) struct AuthorsTemplate<'a> {
+    pub(crate) authors: &'a [AuthorDTO],
+    pub(crate) pages: u64,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./tags.html")]
-struct TagsTemplate {
-    tags: Vec<TagDTO>,
-    pages: u64,
+pub(crate) struct TagsTemplate<'a> {
+    pub(crate) tags: &'a [TagDTO],
+    pub(crate) pages: u64,
+    pub(crate) session: Option<&'a Session>,
 }
 
 #[derive(Template)]
 #[template(path = "./quote_form.html")]
-struct QuoteFormTemplate {}
-
-#[derive(Deserialize)]
-pub struct Params {
-    page: Option<u64>,
-    page_size: Option<u64>,
+struct QuoteFormTemplate<'a> {
+    submission: &'a QuoteFormSubmission,
+    challenge: &'a Challenge,
+    error: Option<&'a str>,
+    session: Option<&'a Session>,
 }
 
 /*
@@ -78,6 +95,20 @@ pub enum AppError {
     Database(#[from] sea_orm::DbErr),
     /// Not Found
     NotFound,
+    /// Unauthorized
+    Unauthorized,
+    /// Forbidden
+    Forbidden,
+    /// Too Many Requests
+    TooManyRequests,
+    /// Conflict
+    Conflict,
+    /// Precondition Failed
+    PreconditionFailed,
+    /// Precondition Required
+    PreconditionRequired,
+    /// Had trouble with an account
+    Account(#[from] AccountError),
 }
 
 impl IntoResponse for AppError {
@@ -86,15 +117,28 @@ impl IntoResponse for AppError {
         #[template(path = "./error.html")]
         struct ErrorTemplate<'a> {
             status_code: &'a str,
+            session: Option<&'a Session>,
         }
 
         let (status, status_string) = match &self {
             AppError::Render(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
             AppError::Database(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
             AppError::NotFound => (StatusCode::NOT_FOUND, "Not Found"),
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
+            AppError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests"),
+            AppError::Conflict => (StatusCode::CONFLICT, "Conflict"),
+            AppError::PreconditionFailed => {
+                (StatusCode::PRECONDITION_FAILED, "Precondition Failed")
+            }
+            AppError::PreconditionRequired => {
+                (StatusCode::PRECONDITION_REQUIRED, "Precondition Required")
+            }
+            AppError::Account(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
         };
         let tmpl = ErrorTemplate {
             status_code: status_string,
+            session: None,
         };
 
         if let Ok(body) = tmpl.render() {
@@ -106,139 +150,81 @@ impl IntoResponse for AppError {
 }
 
 #[axum::debug_handler]
-pub async fn get_quotes(
+pub async fn get_quote_form(
     state: State<AppState>,
-    Query(params): Query<Params>,
+    authorized: Authorized<SubmitQuotes>,
 ) -> Result<impl IntoResponse, AppError> {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_quotes_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((quotes, pages))) => {
-            let quotes_template = QuotesTemplate { quotes, pages };
-
-            Ok(Html(quotes_template.render()?))
-        }
-        Ok(None) => Err(AppError::NotFound),
-        Err(e) => Err(AppError::Database(e)),
-    }
+    render_quote_form(
+        &state,
+        &authorized.actor,
+        &QuoteFormSubmission::default(),
+        None,
+    )
 }
 
-#[axum::debug_handler]
-pub async fn get_tag_and_associated_quotes(
-    state: State<AppState>,
-    Path(tag_id): Path<i32>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, AppError> {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_tag_with_related_quotes(&state.db_conn, tag_id, page, page_size).await {
-        Ok(Some((tag, quotes, pages))) => {
-            let quotes_template = TagTemplate { tag, quotes, pages };
+// every render asks a new question, with a token for the time it was served
+fn render_quote_form(
+    state: &AppState,
+    actor: &Actor,
+    submission: &QuoteFormSubmission,
+    error: Option<&str>,
+) -> Result<Html<String>, AppError> {
+    let challenge = state.spam_guard.challenge(&spam_subject(actor));
+
+    let quote_form_template = QuoteFormTemplate {
+        submission,
+        challenge: &challenge,
+        error,
+        session: actor.session(),
+    };
 
-            Ok(Html(quotes_template.render()?))
-        }
-        Ok(None) => Err(AppError::NotFound),
-        Err(e) => Err(AppError::Database(e)),
-    }
+    Ok(Html(quote_form_template.render()?))
 }
 
-#[axum::debug_handler]
-pub async fn get_author_and_associated_quotes(
-    state: State<AppState>,
-    Path(author_id): Path<i32>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, AppError> {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_author_with_related_quotes(&state.db_conn, author_id, page, page_size)
-        .await
-    {
-        Ok(Some((author, quotes, pages))) => {
-            let quotes_template = AuthorTemplate {
-                author,
-                quotes,
-                pages,
-            };
-
-            Ok(Html(quotes_template.render()?))
-        }
-        Ok(None) => Err(AppError::NotFound),
-        Err(e) => Err(AppError::Database(e)),
-    }
+// quote forms are only good for the user they were served to
+fn spam_subject(actor: &Actor) -> String {
+    format!("user:{}", actor.user_id().unwrap_or_default())
 }
 
-#[axum::debug_handler]
-pub async fn get_authors(
-    state: State<AppState>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, AppError> {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_authors_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((authors, pages))) => {
-            let authors_template = AuthorsTemplate { authors, pages };
-
-            Ok(Html(authors_template.render()?))
-        }
-        Ok(None) => {
-            let authors_template = AuthorsTemplate {
-                authors: Vec::new(),
-                pages: 0,
-            };
-            Ok(Html(authors_template.render()?))
-        }
-        Err(e) => Err(AppError::Database(e)),
-    }
+#[derive(Default, Deserialize)]
+pub struct QuoteFormSubmission {
+    quote: String,
+    author_name: String,
+    source: Option<String>,
+    #[serde(default)]
+    form_token: String,
+    #[serde(default)]
+    answer: String,
+    /// the honeypot, see `spam`
+    #[serde(default)]
+    website: String,
 }
 
-#[axum::debug_handler]
-pub async fn get_tags(
-    state: State<AppState>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, AppError> {
-    let page = params.page.unwrap_or(1);
-    let page_size = params.page_size.unwrap_or(10);
-
-    match DataAccess::get_tags_in_page(&state.db_conn, page, page_size).await {
-        Ok(Some((tags, pages))) => {
-            let tags_template = TagsTemplate { tags, pages };
-
-            Ok(Html(tags_template.render()?))
-        }
-        Ok(None) => {
-            let tags_template = TagsTemplate {
-                tags: Vec::new(),
-                pages: 0,
-            };
-            Ok(Html(tags_template.render()?))
+impl QuoteFormSubmission {
+    fn spam_fields(&self) -> spam::Submission<'_> {
+        spam::Submission {
+            form: spam::FormFields {
+                form_token: &self.form_token,
+                answer: &self.answer,
+                honeypot: &self.website,
+            },
+            quote: &self.quote,
+            author_name: &self.author_name,
+            source: self.source.as_deref(),
         }
-        Err(e) => Err(AppError::Database(e)),
     }
 }
 
-#[axum::debug_handler]
-pub async fn get_quote_form() -> Result<impl IntoResponse, AppError> {
-    let quote_form_template = QuoteFormTemplate {};
-
-    Ok(Html(quote_form_template.render()?))
-}
-
-#[derive(Deserialize)]
-pub struct QuoteFormSubmission {
-    quote: String,
-    author_name: String,
-}
-
 impl From<QuoteFormSubmission> for QuoteCreateDTO {
     fn from(submission: QuoteFormSubmission) -> Self {
         QuoteCreateDTO {
             quote: submission.quote,
             related_tags: Vec::new(), // Assuming no tags are submitted in the form
             author_name: submission.author_name,
+            source: submission.source.filter(|source| !source.trim().is_empty()),
+            submitted_by: None,
+            status: QuoteStatus::Pending,
+            flagged_reason: None,
         }
     }
 }
@@ -246,12 +232,80 @@ impl From<QuoteFormSubmission> for QuoteCreateDTO {
 #[axum::debug_handler]
 pub async fn post_quote_form(
     state: State<AppState>,
-    Form(quote_dto): Form<QuoteFormSubmission>,
+    authorized: Authorized<SubmitQuotes>,
+    Form(submission): Form<QuoteFormSubmission>,
 ) -> Result<impl IntoResponse, AppError> {
-    let _ = DataAccess::create_quote(&state.db_conn, quote_dto.into()).await?;
+    let user_id = authorized.actor.user_id().unwrap_or_default();
+
+    let flagged_reason = match state
+        .spam_guard
+        .check(&spam_subject(&authorized.actor), &submission.spam_fields())
+    {
+        Verdict::Pass => None,
+        Verdict::Retry(message) => {
+            let form = render_quote_form(&state, &authorized.actor, &submission, Some(message))?;
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, form).into_response());
+        }
+        Verdict::Spam(reasons) => {
+            let reasons = reasons.join("; ");
+            tracing::warn!(
+                "Spam checks caught a quote from user {} ({:?}): {}",
+                user_id,
+                state.spam_guard.action,
+                reasons
+            );
+
+            if state.spam_guard.action == SpamAction::Reject {
+                let form = render_quote_form(
+                    &state,
+                    &authorized.actor,
+                    &submission,
+                    Some("This looks like spam, so it wasn't saved."),
+                )?;
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, form).into_response());
+            }
+
+            Some(reasons)
+        }
+    };
+
+    let duplicate_reason =
+        match DataAccess::check_duplicates(&state.db_conn, &state.duplicates, &submission.quote)
+            .await?
+        {
+            DuplicateVerdict::Unique => None,
+            DuplicateVerdict::Flag(reason) => Some(reason),
+            DuplicateVerdict::Reject(_) => {
+                let form = render_quote_form(
+                    &state,
+                    &authorized.actor,
+                    &submission,
+                    Some("This quote is already in the collection."),
+                )?;
+                return Ok((StatusCode::CONFLICT, form).into_response());
+            }
+        };
+
+    let mut quote_dto: QuoteCreateDTO = submission.into();
+    quote_dto.submitted_by = authorized.actor.user_id();
+    quote_dto.status = match flagged_reason {
+        Some(_) => QuoteStatus::Pending,
+        None => authorized.actor.submission_status(),
+    };
+    // a possible duplicate is saved as usual, with a note for the moderators
+    quote_dto.flagged_reason = match (flagged_reason, duplicate_reason) {
+        (Some(spam), Some(duplicate)) => Some(format!("{}; {}", spam, duplicate)),
+        (spam, duplicate) => spam.or(duplicate),
+    };
+
+    let quote = DataAccess::create_quote(&state.db_conn, &authorized.audit(), quote_dto).await?;
 
     // probably should flash a message but idk how to do that right now
-    Ok(Redirect::to("/quotes").into_response())
+    match quote.status {
+        // the submitter can see their own pending quote and its status
+        QuoteStatus::Pending => Ok(Redirect::to(&format!("/quotes/{}", quote.id)).into_response()),
+        _ => Ok(Redirect::to("/quotes").into_response()),
+    }
 }
 
 pub async fn get_root() -> Response {
@@ -261,9 +315,10 @@ pub async fn get_root() -> Response {
 #[axum::debug_handler]
 pub async fn delete_tag(
     state: State<AppState>,
+    authorized: Authorized<DeleteTags>,
     Path(tag_id): Path<i32>,
 ) -> Result<impl IntoResponse, AppError> {
-    match DataAccess::delete_tag(&state.db_conn, tag_id).await {
+    match DataAccess::delete_tag(&state.db_conn, &authorized.audit(), tag_id).await {
         Ok(_) => Ok(Redirect::to("/tags").into_response()),
         Err(e) => Err(AppError::Database(e)),
     }
@@ -271,9 +326,10 @@ pub async fn delete_tag(
 #[axum::debug_handler]
 pub async fn delete_author(
     state: State<AppState>,
+    authorized: Authorized<DeleteAuthors>,
     Path(author_id): Path<i32>,
 ) -> Result<impl IntoResponse, AppError> {
-    match DataAccess::delete_author(&state.db_conn, author_id).await {
+    match DataAccess::delete_author(&state.db_conn, &authorized.audit(), author_id).await {
         Ok(_) => Ok(Redirect::to("/authors").into_response()),
         Err(e) => Err(AppError::Database(e)),
     }
@@ -282,39 +338,76 @@ pub async fn delete_author(
 #[axum::debug_handler]
 pub async fn delete_quote(
     state: State<AppState>,
+    authorized: Authorized<EditOwnQuotes>,
     Path(quote_id): Path<i32>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, AppError> {
-    match DataAccess::delete_quote(&state.db_conn, quote_id).await {
+    let quote = DataAccess::get_quote(&state.db_conn, quote_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    if !authorized.actor.can_edit(&quote) {
+        return Err(AppError::Forbidden);
+    }
+    if !caching::if_match(&headers, &caching::json_etag(&quote)) {
+        return Err(AppError::PreconditionFailed);
+    }
+
+    match DataAccess::delete_quote(&state.db_conn, &authorized.audit(), quote_id).await {
         Ok(_) => Ok(Redirect::to("/quotes").into_response()),
         Err(e) => Err(AppError::Database(e)),
     }
 }
 
+#[derive(Template)]
+#[template(path = "./users.html")]
+struct UsersTemplate<'a> {
+    users: &'a [UserDTO],
+    roles: &'a [Role],
+    session: Option<&'a Session>,
+}
+
 #[axum::debug_handler]
-pub async fn get_single_quote(
+pub async fn get_users(
     state: State<AppState>,
-    Path(quote_id): Path<i32>,
+    authorized: Authorized<ManageUsers>,
 ) -> Result<impl IntoResponse, AppError> {
-    match DataAccess::get_quote(&state.db_conn, quote_id).await {
-        Ok(Some(quote)) => {
-            let quote_dto: QuoteDTO = quote;
-            let quote_template = QuoteTemplate { quote: quote_dto };
+    let users = DataAccess::get_users(&state.db_conn).await?;
 
-            Ok(Html(quote_template.render()?))
-        }
-        Ok(None) => Err(AppError::NotFound),
-        Err(e) => Err(AppError::Database(e)),
-    }
+    let users_template = UsersTemplate {
+        users: &users,
+        roles: &Role::ALL,
+        session: authorized.actor.session(),
+    };
+
+    Ok(Html(users_template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct RoleFormSubmission {
+    role: Role,
 }
 
 #[axum::debug_handler]
-pub async fn update_quote_with_new_tag(
+pub async fn post_user_role(
     state: State<AppState>,
-    Path(quote_id): Path<i32>,
-    Form(tag_dto): Form<String>,
+    authorized: Authorized<ManageUsers>,
+    Path(user_id): Path<i32>,
+    Form(submission): Form<RoleFormSubmission>,
 ) -> Result<impl IntoResponse, AppError> {
-    match DataAccess::update_quote_with_new_tag(&state.db_conn, quote_id, tag_dto).await {
-        Ok(_) => Ok(Redirect::to("/quotes").into_response()),
-        Err(e) => Err(AppError::Database(e)),
+    // admins can't demote themselves and leave nobody able to manage users
+    if authorized.actor.user_id() == Some(user_id) {
+        return Err(AppError::Forbidden);
     }
+
+    DataAccess::set_user_role(
+        &state.db_conn,
+        &authorized.audit(),
+        user_id,
+        submission.role,
+    )
+    .await?
+    .ok_or(AppError::NotFound)?;
+
+    Ok(Redirect::to("/users").into_response())
 }
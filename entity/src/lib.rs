@@ -1,6 +1,11 @@
 pub mod prelude;
 
+pub mod api_key;
+pub mod audit_log;
 pub mod author;
+pub mod idempotency_key;
 pub mod quote;
 pub mod quote_tag_association;
+pub mod session;
 pub mod tag;
+pub mod user;
@@ -1,6 +1,11 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.10
 
+pub use super::api_key::Entity as ApiKey;
+pub use super::audit_log::Entity as AuditLog;
 pub use super::author::Entity as Author;
+pub use super::idempotency_key::Entity as IdempotencyKey;
 pub use super::quote::Entity as Quote;
 pub use super::quote_tag_association::Entity as QuoteTagAssociation;
+pub use super::session::Entity as Session;
 pub use super::tag::Entity as Tag;
+pub use super::user::Entity as User;
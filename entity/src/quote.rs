@@ -11,6 +11,19 @@ pub struct Model {
     pub id: i32,
     pub quote: String,
     pub author_id: i32,
+    pub source: Option<String>,
+    pub created_at: DateTimeUtc,
+    pub updated_at: DateTimeUtc,
+    pub submitted_by: Option<i32>,
+    pub status: String,
+    pub rejection_reason: Option<String>,
+    pub moderated_by: Option<i32>,
+    pub moderated_at: Option<DateTimeUtc>,
+    pub flagged_reason: Option<String>,
+    pub version: i32,
+    pub text_hash: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub minhash: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -25,6 +38,14 @@ pub enum Relation {
     Author,
     #[sea_orm(has_many = "super::quote_tag_association::Entity")]
     QuoteTagAssociation,
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::SubmittedBy",
+        to = "super::user::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    User,
 }
 
 impl Related<super::author::Entity> for Entity {
@@ -39,6 +60,12 @@ impl Related<super::quote_tag_association::Entity> for Entity {
     }
 }
 
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
 impl Related<super::tag::Entity> for Entity {
     fn to() -> RelationDef {
         super::quote_tag_association::Relation::Tag.def()
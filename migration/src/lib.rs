@@ -3,6 +3,16 @@ pub use sea_orm_migration::prelude::*;
 mod m20240424_000001_create_quote_table;
 mod m20250430_133655_create_tags_table;
 mod m20250506_145225_create_author_table;
+mod m20261019_120000_add_quote_source_and_timestamps;
+mod m20261019_130000_create_api_key_table;
+mod m20261019_140000_create_user_and_session_tables;
+mod m20261019_150000_add_user_roles_and_quote_submitter;
+mod m20261019_160000_add_quote_moderation;
+mod m20261019_170000_create_audit_log_table;
+mod m20261019_180000_add_quote_flagged_reason;
+mod m20261019_190000_add_versions;
+mod m20261019_200000_create_idempotency_key_table;
+mod m20261019_210000_add_quote_fingerprints;
 
 pub struct Migrator;
 
@@ -13,6 +23,16 @@ impl MigratorTrait for Migrator {
             Box::new(m20240424_000001_create_quote_table::Migration),
             Box::new(m20250430_133655_create_tags_table::Migration),
             Box::new(m20250506_145225_create_author_table::Migration),
+            Box::new(m20261019_120000_add_quote_source_and_timestamps::Migration),
+            Box::new(m20261019_130000_create_api_key_table::Migration),
+            Box::new(m20261019_140000_create_user_and_session_tables::Migration),
+            Box::new(m20261019_150000_add_user_roles_and_quote_submitter::Migration),
+            Box::new(m20261019_160000_add_quote_moderation::Migration),
+            Box::new(m20261019_170000_create_audit_log_table::Migration),
+            Box::new(m20261019_180000_add_quote_flagged_reason::Migration),
+            Box::new(m20261019_190000_add_versions::Migration),
+            Box::new(m20261019_200000_create_idempotency_key_table::Migration),
+            Box::new(m20261019_210000_add_quote_fingerprints::Migration),
         ]
     }
 }
@@ -0,0 +1,86 @@
+use sea_orm_migration::{
+    prelude::*,
+    schema::*,
+    sea_orm::{DbBackend, Statement},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// sqlite can only add one column per ALTER TABLE, and not with a non-constant
+// default, so existing quotes get stamped with the time of the migration instead
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(string_null(Quote::Source))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(
+                        timestamp_with_time_zone(Quote::CreatedAt)
+                            .default("1970-01-01T00:00:00+00:00"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(
+                        timestamp_with_time_zone(Quote::UpdatedAt)
+                            .default("1970-01-01T00:00:00+00:00"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        let db = manager.get_connection();
+
+        db.execute(Statement::from_string(
+            DbBackend::Sqlite,
+            r#"
+                    UPDATE quote
+                    SET created_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now'),
+                        updated_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now')
+                    "#
+            .to_owned(),
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [Quote::Source, Quote::CreatedAt, Quote::UpdatedAt] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Quote::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Quote {
+    Table,
+    Source,
+    CreatedAt,
+    UpdatedAt,
+}
@@ -0,0 +1,45 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ApiKey::Table)
+                    .if_not_exists()
+                    .col(pk_auto(ApiKey::Id))
+                    .col(string(ApiKey::Name))
+                    .col(string(ApiKey::Prefix))
+                    .col(string_uniq(ApiKey::KeyHash))
+                    .col(string(ApiKey::Scopes))
+                    .col(timestamp_with_time_zone(ApiKey::CreatedAt))
+                    .col(timestamp_with_time_zone_null(ApiKey::LastUsedAt))
+                    .col(timestamp_with_time_zone_null(ApiKey::RevokedAt))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiKey::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum ApiKey {
+    Table,
+    Id,
+    Name,
+    Prefix,
+    KeyHash,
+    Scopes,
+    CreatedAt,
+    LastUsedAt,
+    RevokedAt,
+}
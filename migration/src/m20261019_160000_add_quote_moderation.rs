@@ -0,0 +1,95 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// everything already in the table was published before moderation existed,
+// so the status column defaults to approved
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(string(Quote::Status).default("approved"))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(string_null(Quote::RejectionReason))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(integer_null(Quote::ModeratedBy))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Quote::Table)
+                    .add_column(timestamp_with_time_zone_null(Quote::ModeratedAt))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-quote-status")
+                    .table(Quote::Table)
+                    .col(Quote::Status)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-quote-status")
+                    .table(Quote::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        for column in [
+            Quote::Status,
+            Quote::RejectionReason,
+            Quote::ModeratedBy,
+            Quote::ModeratedAt,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Quote::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Quote {
+    Table,
+    Status,
+    RejectionReason,
+    ModeratedBy,
+    ModeratedAt,
+}
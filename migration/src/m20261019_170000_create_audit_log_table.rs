@@ -0,0 +1,81 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// entries are only ever appended; the triggers refuse to change or remove them
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .if_not_exists()
+                    .col(pk_auto(AuditLog::Id))
+                    .col(string(AuditLog::Entity))
+                    .col(integer(AuditLog::EntityId))
+                    .col(string(AuditLog::Action))
+                    .col(string(AuditLog::Actor))
+                    .col(text_null(AuditLog::Before))
+                    .col(text_null(AuditLog::After))
+                    .col(string_null(AuditLog::RequestId))
+                    .col(timestamp_with_time_zone(AuditLog::CreatedAt))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit_log-created_at")
+                    .table(AuditLog::Table)
+                    .col(AuditLog::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        let db = manager.get_connection();
+
+        db.execute_unprepared(
+            "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log \
+             BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+        )
+        .await?;
+
+        db.execute_unprepared(
+            "CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log \
+             BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        db.execute_unprepared("DROP TRIGGER IF EXISTS audit_log_no_update")
+            .await?;
+        db.execute_unprepared("DROP TRIGGER IF EXISTS audit_log_no_delete")
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(AuditLog::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum AuditLog {
+    Table,
+    Id,
+    Entity,
+    EntityId,
+    Action,
+    Actor,
+    Before,
+    After,
+    RequestId,
+    CreatedAt,
+}
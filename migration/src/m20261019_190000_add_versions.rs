@@ -0,0 +1,48 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// bumped by every update, so an edit made against an old copy can be refused
+// instead of overwriting someone else's
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite only adds one column per ALTER TABLE
+        for table in [Versioned::Quote, Versioned::Author, Versioned::Tag] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .add_column(integer(Versioned::Version).default(1))
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [Versioned::Quote, Versioned::Author, Versioned::Tag] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .drop_column(Versioned::Version)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Versioned {
+    Quote,
+    Author,
+    Tag,
+    Version,
+}
@@ -0,0 +1,69 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// a row is claimed before the request runs and gets its response once it has
+// one, so a null status means the first attempt is still in flight
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(IdempotencyKey::Table)
+                    .if_not_exists()
+                    .col(pk_auto(IdempotencyKey::Id))
+                    .col(string(IdempotencyKey::Actor))
+                    .col(string(IdempotencyKey::Key))
+                    .col(string(IdempotencyKey::RequestHash))
+                    .col(integer_null(IdempotencyKey::Status))
+                    .col(string_null(IdempotencyKey::ContentType))
+                    .col(text_null(IdempotencyKey::Body))
+                    .col(timestamp_with_time_zone(IdempotencyKey::CreatedAt))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-idempotency_key-actor-key")
+                    .table(IdempotencyKey::Table)
+                    .col(IdempotencyKey::Actor)
+                    .col(IdempotencyKey::Key)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-idempotency_key-created_at")
+                    .table(IdempotencyKey::Table)
+                    .col(IdempotencyKey::CreatedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(IdempotencyKey::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum IdempotencyKey {
+    Table,
+    Id,
+    Actor,
+    Key,
+    RequestHash,
+    Status,
+    ContentType,
+    Body,
+    CreatedAt,
+}
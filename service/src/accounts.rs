@@ -0,0 +1,58 @@
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use sea_orm::DbErr;
+
+/// How long a login lasts before the user has to sign in again.
+pub const SESSION_LIFETIME: chrono::TimeDelta = chrono::TimeDelta::days(30);
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum AccountError {
+    /// usernames are 1 to 32 letters, digits, '-' or '_'
+    InvalidUsername,
+    /// passwords need at least 8 characters
+    WeakPassword,
+    /// that username is taken
+    UsernameTaken,
+    /// could not hash the password
+    Hash(password_hash::Error),
+    /// Had trouble with database
+    Database(#[from] DbErr),
+}
+
+pub(crate) fn validate(username: &str, password: &str) -> Result<(), AccountError> {
+    let valid_username = !username.is_empty()
+        && username.chars().count() <= MAX_USERNAME_LENGTH
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+
+    if !valid_username {
+        return Err(AccountError::InvalidUsername);
+    }
+
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AccountError::WeakPassword);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(AccountError::Hash)
+}
+
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
@@ -0,0 +1,348 @@
+use super::data_transfer_objects::QuoteDTO;
+use sea_orm::{ConnectionTrait, Database, DbBackend, DbErr, Statement};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::io::{Cursor, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/*
+source: https://github.com/kerrickstaley/genanki/blob/main/genanki/apkg_schema.py
+
+An .apkg is a zip holding a legacy (schema 11) Anki collection named
+`collection.anki2` and a `media` manifest. Notes are matched on import by
+their guid, so a guid derived from the quote id makes re-exports update the
+existing cards instead of adding new ones.
+*/
+
+const SCHEMA: &str = r#"
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (
+    usn integer not null, oid integer not null, type integer not null
+);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+"#;
+
+// fixed so every export shares one note type
+const MODEL_ID: i64 = 1_715_000_000_000;
+
+// quote ids are added to this so note and card ids stay stable between exports
+const NOTE_ID_BASE: i64 = 1_715_000_000_000;
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum AnkiError {
+    /// could not write the anki collection
+    Database(#[from] DbErr),
+    /// could not write the package
+    Io(#[from] std::io::Error),
+    /// could not zip the package
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// Builds an `.apkg` package holding one note per quote in a deck called
+/// `deck_name`.
+pub async fn build_package(quotes: &[QuoteDTO], deck_name: &str) -> Result<Vec<u8>, AnkiError> {
+    let collection = tempfile::NamedTempFile::new()?;
+    let db = Database::connect(format!(
+        "sqlite:{}?mode=rwc",
+        collection.path().to_string_lossy()
+    ))
+    .await?;
+
+    db.execute_unprepared(SCHEMA).await?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let now_secs = now.as_secs() as i64;
+    let now_millis = now.as_millis() as i64;
+    let deck_id = deck_id(deck_name);
+
+    db.execute(Statement::from_sql_and_values(
+        DbBackend::Sqlite,
+        "INSERT INTO col VALUES (1, ?, ?, ?, 11, 0, 0, 0, ?, ?, ?, ?, '{}')",
+        [
+            now_secs.into(),
+            now_millis.into(),
+            now_millis.into(),
+            collection_config().to_string().into(),
+            models(deck_id, now_secs).to_string().into(),
+            decks(deck_id, deck_name, now_secs).to_string().into(),
+            deck_config().to_string().into(),
+        ],
+    ))
+    .await?;
+
+    for (position, quote) in quotes.iter().enumerate() {
+        let note_id = NOTE_ID_BASE + quote.id as i64;
+        let front = escape_html(&quote.quote);
+        let back = back_of_card(quote);
+        let tags = quote
+            .related_tags
+            .iter()
+            .map(|tag| tag.tag.split_whitespace().collect::<Vec<_>>().join("_"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        db.execute(Statement::from_sql_and_values(
+            DbBackend::Sqlite,
+            "INSERT INTO notes VALUES (?, ?, ?, ?, -1, ?, ?, ?, ?, 0, '')",
+            [
+                note_id.into(),
+                format!("quote-server-{}", quote.id).into(),
+                MODEL_ID.into(),
+                now_secs.into(),
+                format!(" {} ", tags).into(),
+                format!("{}\x1f{}", front, back).into(),
+                strip_html(&front).into(),
+                checksum(&front).into(),
+            ],
+        ))
+        .await?;
+
+        db.execute(Statement::from_sql_and_values(
+            DbBackend::Sqlite,
+            "INSERT INTO cards VALUES (?, ?, ?, 0, ?, -1, 0, 0, ?, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            [
+                note_id.into(),
+                note_id.into(),
+                deck_id.into(),
+                now_secs.into(),
+                (position as i64 + 1).into(),
+            ],
+        ))
+        .await?;
+    }
+
+    db.close().await?;
+
+    let mut package = ZipWriter::new(Cursor::new(Vec::new()));
+    package.start_file("collection.anki2", SimpleFileOptions::default())?;
+    package.write_all(&std::fs::read(collection.path())?)?;
+    package.start_file("media", SimpleFileOptions::default())?;
+    package.write_all(b"{}")?;
+
+    Ok(package.finish()?.into_inner())
+}
+
+fn back_of_card(quote: &QuoteDTO) -> String {
+    let mut back = format!("&mdash; {}", escape_html(&quote.author.name));
+
+    if !quote.related_tags.is_empty() {
+        let tags = quote
+            .related_tags
+            .iter()
+            .map(|tag| escape_html(&tag.tag))
+            .collect::<Vec<_>>()
+            .join(", ");
+        back.push_str(&format!("<br><small>{}</small>", tags));
+    }
+
+    back
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "<br>")
+}
+
+// anki uses the first 8 hex digits of the sha1 of the sort field, with the
+// html stripped, to spot duplicates
+fn checksum(sort_field: &str) -> i64 {
+    let digest = Sha1::digest(strip_html(sort_field).as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) as i64
+}
+
+// the inverse of `escape_html`, dropping tags the way anki's strip_html does
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+// stable per deck name, and small enough to survive a round trip through javascript
+fn deck_id(deck_name: &str) -> i64 {
+    let digest = Sha1::digest(deck_name.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) >> 12) as i64
+}
+
+fn collection_config() -> serde_json::Value {
+    json!({
+        "activeDecks": [1],
+        "curDeck": 1,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": null,
+        "nextPos": 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true
+    })
+}
+
+fn models(deck_id: i64, now_secs: i64) -> serde_json::Value {
+    json!({
+        MODEL_ID.to_string(): {
+            "id": MODEL_ID,
+            "name": "Quote Server Quote",
+            "type": 0,
+            "mod": now_secs,
+            "usn": -1,
+            "sortf": 0,
+            "did": deck_id,
+            "tmpls": [{
+                "name": "Quote",
+                "ord": 0,
+                "qfmt": "{{Quote}}",
+                "afmt": "{{FrontSide}}<hr id=answer>{{Attribution}}",
+                "did": null,
+                "bqfmt": "",
+                "bafmt": ""
+            }],
+            "flds": [
+                { "name": "Quote", "ord": 0, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] },
+                { "name": "Attribution", "ord": 1, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] }
+            ],
+            "css": ".card { font-family: arial; font-size: 20px; text-align: center; color: black; background-color: white; }",
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "tags": [],
+            "vers": [],
+            "req": [[0, "all", [0]]]
+        }
+    })
+}
+
+fn decks(deck_id: i64, deck_name: &str, now_secs: i64) -> serde_json::Value {
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id,
+            "name": name,
+            "mod": now_secs,
+            "usn": -1,
+            "lrnToday": [0, 0],
+            "revToday": [0, 0],
+            "newToday": [0, 0],
+            "timeToday": [0, 0],
+            "collapsed": false,
+            "desc": "",
+            "dyn": 0,
+            "conf": 1,
+            "extendNew": 10,
+            "extendRev": 50
+        })
+    };
+
+    json!({
+        "1": deck(1, "Default"),
+        deck_id.to_string(): deck(deck_id, deck_name)
+    })
+}
+
+fn deck_config() -> serde_json::Value {
+    json!({
+        "1": {
+            "id": 1,
+            "name": "Default",
+            "replayq": true,
+            "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 },
+            "rev": { "perDay": 100, "ease4": 1.3, "fuzz": 0.05, "minSpace": 1, "ivlFct": 1, "maxIvl": 36500, "bury": true },
+            "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "separate": true, "order": 1, "perDay": 20, "bury": true },
+            "timer": 0,
+            "maxTaken": 60,
+            "usn": 0,
+            "mod": 0,
+            "autoplay": true,
+            "dyn": false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_is_the_first_four_bytes_of_the_sha1() {
+        // sha1("hello") = aaf4c61d...
+        assert_eq!(checksum("hello"), 0xaaf4c61d);
+    }
+
+    #[test]
+    fn checksum_ignores_html_in_the_sort_field() {
+        assert_eq!(checksum("one<br>two"), checksum("onetwo"));
+
+        // sha1("a < b & c") = b1c40578...
+        assert_eq!(checksum(&escape_html("a < b & c")), 0xb1c40578);
+    }
+
+    #[test]
+    fn strip_html_reverses_escaping() {
+        let text = "\"Fish & chips\" <3";
+
+        assert_eq!(strip_html(&escape_html(text)), text);
+        assert_eq!(strip_html("line<br>break<br/>"), "linebreak");
+    }
+
+    #[test]
+    fn deck_id_is_stable_and_fits_in_a_javascript_number() {
+        let id = deck_id("Quotes");
+
+        assert_eq!(id, deck_id("Quotes"));
+        assert_ne!(id, deck_id("Other"));
+        assert!(id < (1 << 53));
+    }
+}
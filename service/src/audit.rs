@@ -0,0 +1,22 @@
+/// Who is behind a change and which request made it, recorded alongside
+/// every mutation `DataAccess` performs.
+#[derive(Clone, Debug)]
+pub struct AuditContext {
+    /// `user:<username>`, `api_key:<id>`, `cli` or `anonymous`
+    pub actor: String,
+    pub request_id: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new(actor: impl Into<String>, request_id: Option<String>) -> Self {
+        AuditContext {
+            actor: actor.into(),
+            request_id,
+        }
+    }
+
+    /// Changes made from the command line, e.g. `--init` or `--set-role`.
+    pub fn cli() -> Self {
+        AuditContext::new("cli", None)
+    }
+}
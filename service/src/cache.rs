@@ -0,0 +1,379 @@
+use sea_orm::DbErr;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/*
+An in-process cache for the hottest reads: single quotes, the pages of a tag
+or author, and the counts. Entries live for `ttl` seconds at most and there
+are never more than `capacity` of them; when full, the least recently used
+one goes.
+
+The mutations in DataAccess forget exactly the entries their change shows up
+in, so within one process a read never sees an older value than the
+database. Another process writing to the same database is only noticed once
+the TTL runs out, so keep it short when running more than one server.
+
+Nothing is cached until `configure` is called, so command line tools read the
+database directly.
+*/
+
+static CACHE: OnceLock<ReadCache> = OnceLock::new();
+static OBSERVER: OnceLock<Box<dyn Fn(&'static str, bool) + Send + Sync>> = OnceLock::new();
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// seconds an entry is trusted for
+    pub ttl: u64,
+    /// most entries kept at once
+    pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            ttl: 60,
+            capacity: 1000,
+        }
+    }
+}
+
+/// Turns the cache on with these settings. Only the first call counts.
+pub fn configure(config: &CacheConfig) {
+    if config.enabled && config.ttl > 0 && config.capacity > 0 {
+        let _ = CACHE.set(ReadCache::new(config));
+    }
+}
+
+/// Calls `observer` with the kind of entry (`quote`, `tag_page`,
+/// `author_page` or `counts`) and whether it was found, on every lookup.
+pub fn set_observer(observer: impl Fn(&'static str, bool) + Send + Sync + 'static) {
+    let _ = OBSERVER.set(Box::new(observer));
+}
+
+/// How many entries are cached right now.
+pub fn len() -> usize {
+    CACHE.get().map_or(0, |cache| cache.lock().len())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CacheKey {
+    Quote(i32),
+    TagPage {
+        tag_id: i32,
+        page: u64,
+        page_size: u64,
+    },
+    AuthorPage {
+        author_id: i32,
+        page: u64,
+        page_size: u64,
+    },
+    Counts,
+}
+
+impl CacheKey {
+    fn kind(self) -> &'static str {
+        match self {
+            CacheKey::Quote(_) => "quote",
+            CacheKey::TagPage { .. } => "tag_page",
+            CacheKey::AuthorPage { .. } => "author_page",
+            CacheKey::Counts => "counts",
+        }
+    }
+}
+
+struct Entry {
+    value: Arc<dyn Any + Send + Sync>,
+    expires: Instant,
+    last_used: u64,
+}
+
+struct ReadCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+    // bumped by every invalidation, so a load that raced a write isn't stored
+    generation: AtomicU64,
+    // orders uses for LRU eviction
+    clock: AtomicU64,
+}
+
+impl ReadCache {
+    fn new(config: &CacheConfig) -> Self {
+        ReadCache {
+            ttl: Duration::from_secs(config.ttl),
+            capacity: config.capacity,
+            entries: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<CacheKey, Entry>> {
+        // the map is never left half updated, so a panic elsewhere doesn't
+        // make it unusable
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn get<T: Clone + Send + Sync + 'static>(&self, key: CacheKey) -> Option<T> {
+        let mut entries = self.lock();
+        let entry = entries.get_mut(&key)?;
+
+        if entry.expires <= Instant::now() {
+            entries.remove(&key);
+            return None;
+        }
+
+        entry.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.value.downcast_ref::<T>().cloned()
+    }
+
+    fn insert<T: Send + Sync + 'static>(&self, key: CacheKey, value: T, generation: u64) {
+        let mut entries = self.lock();
+
+        if self.generation.load(Ordering::Acquire) != generation {
+            return;
+        }
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                value: Arc::new(value),
+                expires: Instant::now() + self.ttl,
+                last_used: self.clock.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+    }
+
+    fn forget(&self, matches: impl Fn(&CacheKey) -> bool) {
+        let mut entries = self.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        entries.retain(|key, _| !matches(key));
+    }
+
+    async fn get_or_load<T, F>(&self, key: CacheKey, load: F) -> Result<T, DbErr>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: Future<Output = Result<T, DbErr>>,
+    {
+        let cached = self.get::<T>(key);
+        if let Some(observer) = OBSERVER.get() {
+            observer(key.kind(), cached.is_some());
+        }
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let value = load.await?;
+        self.insert(key, value.clone(), generation);
+
+        Ok(value)
+    }
+
+    fn forget_quote(&self, quote_id: i32, tag_ids: &[i32], author_id: i32) {
+        self.forget(|key| match *key {
+            CacheKey::Quote(id) => id == quote_id,
+            CacheKey::TagPage { tag_id, .. } => tag_ids.contains(&tag_id),
+            CacheKey::AuthorPage { author_id: id, .. } => id == author_id,
+            CacheKey::Counts => true,
+        });
+    }
+}
+
+/// Returns the cached value for `key`, or loads and caches it.
+pub(crate) async fn get_or_load<T, F>(key: CacheKey, load: F) -> Result<T, DbErr>
+where
+    T: Clone + Send + Sync + 'static,
+    F: Future<Output = Result<T, DbErr>>,
+{
+    match CACHE.get() {
+        Some(cache) => cache.get_or_load(key, load).await,
+        None => load.await,
+    }
+}
+
+/// Forgets a single entry.
+pub(crate) fn forget(key: CacheKey) {
+    if let Some(cache) = CACHE.get() {
+        cache.forget(|cached| *cached == key);
+    }
+}
+
+/// Forgets every page of a tag.
+pub(crate) fn forget_tag_pages(tag_id: i32) {
+    if let Some(cache) = CACHE.get() {
+        cache.forget(|key| matches!(key, CacheKey::TagPage { tag_id: id, .. } if *id == tag_id));
+    }
+}
+
+/// Forgets every page of an author.
+pub(crate) fn forget_author_pages(author_id: i32) {
+    if let Some(cache) = CACHE.get() {
+        cache.forget(
+            |key| matches!(key, CacheKey::AuthorPage { author_id: id, .. } if *id == author_id),
+        );
+    }
+}
+
+/// Forgets everything a quote shows up in: itself, the pages of its tags
+/// and author, and the counts.
+pub(crate) fn forget_quote(quote_id: i32, tag_ids: &[i32], author_id: i32) {
+    if let Some(cache) = CACHE.get() {
+        cache.forget_quote(quote_id, tag_ids, author_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: u64, capacity: usize) -> ReadCache {
+        ReadCache::new(&CacheConfig {
+            enabled: true,
+            ttl,
+            capacity,
+        })
+    }
+
+    fn page(tag_id: i32) -> CacheKey {
+        CacheKey::TagPage {
+            tag_id,
+            page: 0,
+            page_size: 20,
+        }
+    }
+
+    fn author_page(author_id: i32) -> CacheKey {
+        CacheKey::AuthorPage {
+            author_id,
+            page: 0,
+            page_size: 20,
+        }
+    }
+
+    fn store(cache: &ReadCache, key: CacheKey, value: &'static str) {
+        cache.insert(key, value, cache.generation.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn a_cached_value_is_served_without_loading() {
+        let cache = cache(60, 10);
+
+        let first = cache
+            .get_or_load(CacheKey::Counts, async { Ok("loaded") })
+            .await;
+        let second = cache
+            .get_or_load(CacheKey::Counts, async { Ok("loaded again") })
+            .await;
+
+        assert_eq!(first, Ok("loaded"));
+        assert_eq!(second, Ok("loaded"));
+    }
+
+    #[test]
+    fn a_changed_quote_forgets_only_what_it_shows_up_in() {
+        let cache = cache(60, 10);
+        for key in [
+            CacheKey::Quote(1),
+            CacheKey::Quote(2),
+            page(10),
+            page(11),
+            author_page(20),
+            author_page(21),
+            CacheKey::Counts,
+        ] {
+            store(&cache, key, "cached");
+        }
+
+        cache.forget_quote(1, &[10], 20);
+
+        let kept: Vec<bool> = [
+            CacheKey::Quote(1),
+            CacheKey::Quote(2),
+            page(10),
+            page(11),
+            author_page(20),
+            author_page(21),
+            CacheKey::Counts,
+        ]
+        .into_iter()
+        .map(|key| cache.get::<&str>(key).is_some())
+        .collect();
+        assert_eq!(kept, [false, true, false, true, false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn a_load_that_raced_a_write_is_not_stored() {
+        let cache = cache(60, 10);
+
+        // the write commits and invalidates while the read is still loading
+        // what it saw before
+        let stale = cache
+            .get_or_load(CacheKey::Quote(1), async {
+                cache.forget_quote(1, &[], 1);
+                Ok("before the write")
+            })
+            .await;
+        assert_eq!(stale, Ok("before the write"));
+
+        let fresh = cache
+            .get_or_load(CacheKey::Quote(1), async { Ok("after the write") })
+            .await;
+        assert_eq!(fresh, Ok("after the write"));
+        assert_eq!(
+            cache.get::<&str>(CacheKey::Quote(1)),
+            Some("after the write")
+        );
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_goes_when_full() {
+        let cache = cache(60, 2);
+        store(&cache, CacheKey::Quote(1), "one");
+        store(&cache, CacheKey::Quote(2), "two");
+
+        cache.get::<&str>(CacheKey::Quote(1));
+        store(&cache, CacheKey::Quote(3), "three");
+
+        assert_eq!(cache.get::<&str>(CacheKey::Quote(1)), Some("one"));
+        assert_eq!(cache.get::<&str>(CacheKey::Quote(2)), None);
+        assert_eq!(cache.get::<&str>(CacheKey::Quote(3)), Some("three"));
+    }
+
+    #[test]
+    fn expired_entries_are_not_served() {
+        let cache = cache(60, 10);
+        store(&cache, CacheKey::Counts, "cached");
+
+        cache.lock().get_mut(&CacheKey::Counts).unwrap().expires = Instant::now();
+
+        assert_eq!(cache.get::<&str>(CacheKey::Counts), None);
+        assert_eq!(cache.lock().len(), 0);
+    }
+}
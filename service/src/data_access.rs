@@ -1,15 +1,50 @@
-use super::data_transfer_objects::{AuthorDTO, QuoteCreateDTO, QuoteDTO, TagDTO};
+use super::accounts::{self, AccountError, SESSION_LIFETIME};
+use super::audit::AuditContext;
+use super::cache::{self, CacheKey};
+use super::data_transfer_objects::{
+    ApiKeyDTO, AuditAction, AuditEntryDTO, AuthorDTO, CountsDTO, DuplicateClusterDTO,
+    DuplicateMatchDTO, IdempotencyRecordDTO, QuoteCreateDTO, QuoteDTO, QuoteEditDTO, QuoteStatus,
+    Role, Scope, TagDTO, UserDTO,
+};
+use super::duplicates::{self, DuplicateVerdict, DuplicatesConfig, Signature};
 use ::entity::{
+    api_key::{self, Entity as ApiKey},
+    audit_log::{self, Entity as AuditLog},
     author::{self, Entity as Author},
+    idempotency_key::{self, Entity as IdempotencyKey},
     prelude::QuoteTagAssociation,
     quote::{self, Entity as Quote},
     quote_tag_association,
+    session::{self, Entity as Session},
     tag::{self, Entity as Tag},
+    user::{self, Entity as User},
 };
 use sea_orm::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
+/// Every public call gets a debug level span named after it, so traces show
+/// which queries each one ran. Arguments are left out of the span: some are
+/// passwords and keys.
 pub struct DataAccess {}
 
+/// How an update that checks the version went.
+#[derive(Clone, Debug)]
+pub enum UpdateOutcome<T> {
+    Updated(T),
+    /// someone else changed it first; this is how it is now
+    Conflict(T),
+}
+
+/// What claiming an idempotency key found.
+#[derive(Clone, Debug)]
+pub enum IdempotencyClaim {
+    /// the key is new; the caller runs the request and stores its response
+    Claimed,
+    /// the key was used before, by this request or another one
+    Existing(IdempotencyRecordDTO),
+}
+
 /*
 source: https://github.com/SeaQL/sea-orm/blob/master/examples/axum_example/service/src/mutation.rs
 
@@ -21,16 +56,38 @@ impl DataAccess {
     // AUTHOR
     // id
     // name
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_tag_with_related_quotes(
         db: &DbConn,
         tag_id: i32,
         page: u64,
         page_size: u64,
+    ) -> Result<Option<(TagDTO, Vec<QuoteDTO>, u64)>, DbErr> {
+        let key = CacheKey::TagPage {
+            tag_id,
+            page,
+            page_size,
+        };
+
+        cache::get_or_load(
+            key,
+            Self::load_tag_with_related_quotes(db, tag_id, page, page_size),
+        )
+        .await
+    }
+
+    async fn load_tag_with_related_quotes(
+        db: &DbConn,
+        tag_id: i32,
+        page: u64,
+        page_size: u64,
     ) -> Result<Option<(TagDTO, Vec<QuoteDTO>, u64)>, DbErr> {
         let tag = Tag::find_by_id(tag_id).one(db).await?;
 
         if let Some(tag) = tag {
-            let quotes_query = tag.find_related(Quote);
+            let quotes_query = tag
+                .find_related(Quote)
+                .filter(quote::Column::Status.eq(QuoteStatus::Approved.as_str()));
 
             let paginator = quotes_query.paginate(db, page_size);
 
@@ -54,16 +111,38 @@ impl DataAccess {
         Ok(None)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_author_with_related_quotes(
         db: &DbConn,
         author_id: i32,
         page: u64,
         page_size: u64,
+    ) -> Result<Option<(AuthorDTO, Vec<QuoteDTO>, u64)>, DbErr> {
+        let key = CacheKey::AuthorPage {
+            author_id,
+            page,
+            page_size,
+        };
+
+        cache::get_or_load(
+            key,
+            Self::load_author_with_related_quotes(db, author_id, page, page_size),
+        )
+        .await
+    }
+
+    async fn load_author_with_related_quotes(
+        db: &DbConn,
+        author_id: i32,
+        page: u64,
+        page_size: u64,
     ) -> Result<Option<(AuthorDTO, Vec<QuoteDTO>, u64)>, DbErr> {
         let author = Author::find_by_id(author_id).one(db).await?;
 
         if let Some(author) = author {
-            let quotes_query = author.find_related(Quote);
+            let quotes_query = author
+                .find_related(Quote)
+                .filter(quote::Column::Status.eq(QuoteStatus::Approved.as_str()));
 
             let paginator = quotes_query.paginate(db, page_size);
 
@@ -87,6 +166,7 @@ impl DataAccess {
         Ok(None)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_author(db: &DbConn, author_id: i32) -> Result<Option<AuthorDTO>, DbErr> {
         let result = Author::find_by_id(author_id).one(db).await?;
 
@@ -97,8 +177,10 @@ impl DataAccess {
         Ok(None)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_or_create_author_model(
         db: &DbConn,
+        ctx: &AuditContext,
         author_name: String,
     ) -> Result<AuthorDTO, DbErr> {
         let author_name_lower = author_name.to_lowercase();
@@ -115,18 +197,28 @@ impl DataAccess {
             }
         }
 
-        tracing::info!(
-            "Author with name '{}' not found, creating new author.",
-            author_name_lower
-        );
         // doesn't exist so we need to create
-        Ok(author::ActiveModel {
+        let author: AuthorDTO = author::ActiveModel {
             name: Set(author_name_lower),
             ..Default::default()
         }
         .insert(db)
         .await?
-        .into())
+        .into();
+        cache::forget(CacheKey::Counts);
+
+        Self::record_audit(
+            db,
+            ctx,
+            "author",
+            author.id,
+            AuditAction::Create,
+            None,
+            Some(&author),
+        )
+        .await?;
+
+        Ok(author)
     }
 
     // QUOTE
@@ -134,12 +226,27 @@ impl DataAccess {
     // quote
     // author_id
 
-    pub async fn create_quote(db: &DbConn, quote: QuoteCreateDTO) -> Result<QuoteDTO, DbErr> {
-        let author_dto = DataAccess::get_or_create_author_model(db, quote.author_name).await?;
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn create_quote(
+        db: &DbConn,
+        ctx: &AuditContext,
+        quote: QuoteCreateDTO,
+    ) -> Result<QuoteDTO, DbErr> {
+        let author_dto = DataAccess::get_or_create_author_model(db, ctx, quote.author_name).await?;
+
+        let now = chrono::Utc::now();
 
         let quote_model = quote::ActiveModel {
             author_id: Set(author_dto.id),
+            text_hash: Set(Some(duplicates::text_hash(&quote.quote))),
+            minhash: Set(Some(Signature::of(&quote.quote).to_hex())),
             quote: Set(quote.quote.to_owned()),
+            source: Set(quote.source),
+            created_at: Set(now),
+            updated_at: Set(now),
+            submitted_by: Set(quote.submitted_by),
+            status: Set(quote.status.as_str().to_owned()),
+            flagged_reason: Set(quote.flagged_reason),
             ..Default::default()
         }
         .insert(db)
@@ -148,7 +255,7 @@ impl DataAccess {
         let mut related_tags: Vec<TagDTO> = Vec::new();
 
         for tag in quote.related_tags {
-            let tag_dto = DataAccess::get_tag_or_create_tag(db, tag.tag).await?;
+            let tag_dto = DataAccess::get_tag_or_create_tag(db, ctx, tag.tag).await?;
 
             let _quote_tag_association =
                 DataAccess::create_quote_tag_association(db, &quote_model, &tag_dto).await?;
@@ -161,12 +268,37 @@ impl DataAccess {
             quote: quote_model.quote,
             related_tags,
             author: author_dto,
+            source: quote_model.source,
+            created_at: quote_model.created_at,
+            updated_at: quote_model.updated_at,
+            submitted_by: quote_model.submitted_by,
+            status: quote.status,
+            rejection_reason: None,
+            flagged_reason: quote_model.flagged_reason,
+            version: quote_model.version,
         };
+        Self::forget_quote(&dto);
+
+        Self::record_audit(
+            db,
+            ctx,
+            "quote",
+            dto.id,
+            AuditAction::Create,
+            None,
+            Some(&dto),
+        )
+        .await?;
 
         Ok(dto)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_quote(db: &DbConn, id: i32) -> Result<Option<QuoteDTO>, DbErr> {
+        cache::get_or_load(CacheKey::Quote(id), Self::load_quote(db, id)).await
+    }
+
+    async fn load_quote<C: ConnectionTrait>(db: &C, id: i32) -> Result<Option<QuoteDTO>, DbErr> {
         let model = Quote::find_by_id(id).one(db).await?;
 
         if let Some(model) = model {
@@ -178,8 +310,9 @@ impl DataAccess {
         }
     }
 
-    pub async fn get_quote_with_related_tags_and_author(
-        db: &DbConn,
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_quote_with_related_tags_and_author<C: ConnectionTrait>(
+        db: &C,
         quote: quote::Model,
     ) -> Result<QuoteDTO, DbErr> {
         let tags = quote.find_related(Tag).all(db).await?;
@@ -190,15 +323,25 @@ impl DataAccess {
             author: author.into(),
             quote: quote.quote,
             related_tags: tags.into_iter().map(TagDTO::from).collect(),
+            source: quote.source,
+            created_at: quote.created_at,
+            updated_at: quote.updated_at,
+            submitted_by: quote.submitted_by,
+            status: quote.status.parse().unwrap_or(QuoteStatus::Pending),
+            rejection_reason: quote.rejection_reason,
+            flagged_reason: quote.flagged_reason,
+            version: quote.version,
         })
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_quotes_in_page(
         db: &DbConn,
         page: u64,
         page_size: u64,
     ) -> Result<Option<(Vec<QuoteDTO>, u64)>, DbErr> {
         let query = Quote::find()
+            .filter(quote::Column::Status.eq(QuoteStatus::Approved.as_str()))
             .join(JoinType::LeftJoin, quote::Relation::Author.def())
             .order_by(author::Column::Name, Order::Asc);
 
@@ -220,6 +363,84 @@ impl DataAccess {
         Ok(Some((result, total)))
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_quotes_filtered(
+        db: &DbConn,
+        tag: Option<String>,
+        author_name: Option<String>,
+    ) -> Result<Vec<QuoteDTO>, DbErr> {
+        let mut query = Quote::find()
+            .filter(quote::Column::Status.eq(QuoteStatus::Approved.as_str()))
+            .join(JoinType::LeftJoin, quote::Relation::Author.def())
+            .order_by(author::Column::Name, Order::Asc)
+            .order_by(quote::Column::Id, Order::Asc);
+
+        if let Some(tag) = tag {
+            query = query
+                .join(
+                    JoinType::InnerJoin,
+                    quote::Relation::QuoteTagAssociation.def(),
+                )
+                .join(
+                    JoinType::InnerJoin,
+                    quote_tag_association::Relation::Tag.def(),
+                )
+                .filter(tag::Column::Tag.eq(tag.to_lowercase()));
+        }
+
+        if let Some(author_name) = author_name {
+            query = query.filter(author::Column::Name.eq(author_name.to_lowercase()));
+        }
+
+        let mut result: Vec<QuoteDTO> = Vec::new();
+
+        for quote in query.all(db).await? {
+            let dto = Self::get_quote_with_related_tags_and_author(db, quote).await?;
+
+            result.push(dto);
+        }
+
+        Ok(result)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_latest_quotes(
+        db: &DbConn,
+        tag_id: Option<i32>,
+        author_id: Option<i32>,
+        limit: u64,
+    ) -> Result<Vec<QuoteDTO>, DbErr> {
+        let mut query = Quote::find()
+            .filter(quote::Column::Status.eq(QuoteStatus::Approved.as_str()))
+            .order_by(quote::Column::CreatedAt, Order::Desc)
+            .order_by(quote::Column::Id, Order::Desc)
+            .limit(limit);
+
+        if let Some(tag_id) = tag_id {
+            query = query
+                .join(
+                    JoinType::InnerJoin,
+                    quote::Relation::QuoteTagAssociation.def(),
+                )
+                .filter(quote_tag_association::Column::TagId.eq(tag_id));
+        }
+
+        if let Some(author_id) = author_id {
+            query = query.filter(quote::Column::AuthorId.eq(author_id));
+        }
+
+        let mut result: Vec<QuoteDTO> = Vec::new();
+
+        for quote in query.all(db).await? {
+            let dto = Self::get_quote_with_related_tags_and_author(db, quote).await?;
+
+            result.push(dto);
+        }
+
+        Ok(result)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_tags_in_page(
         db: &DbConn,
         page: u64,
@@ -243,6 +464,7 @@ impl DataAccess {
         Ok(Some((result, total)))
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_authors_in_page(
         db: &DbConn,
         page: u64,
@@ -267,7 +489,12 @@ impl DataAccess {
     }
 
     // TAGS
-    pub async fn get_tag_or_create_tag(db: &DbConn, tag: String) -> Result<TagDTO, DbErr> {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_tag_or_create_tag(
+        db: &DbConn,
+        ctx: &AuditContext,
+        tag: String,
+    ) -> Result<TagDTO, DbErr> {
         let tag_lower = tag.to_lowercase();
         let search_results = DataAccess::get_tags(db, &tag_lower).await;
 
@@ -277,11 +504,19 @@ impl DataAccess {
             }
         }
 
-        let model = DataAccess::create_tag(db, &tag_lower).await?;
+        let model = DataAccess::create_tag(db, ctx, &tag_lower).await?;
 
         Ok(model.into())
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_tag(db: &DbConn, tag_id: i32) -> Result<Option<TagDTO>, DbErr> {
+        let result = Tag::find_by_id(tag_id).one(db).await?;
+
+        Ok(result.map(TagDTO::from))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn get_tags(db: &DbConn, tag: &str) -> Result<Vec<tag::Model>, DbErr> {
         let tags = Tag::find()
             .filter(tag::Column::Tag.contains(tag))
@@ -291,108 +526,259 @@ impl DataAccess {
         Ok(tags)
     }
 
-    pub async fn create_tag(db: &DbConn, tag: &str) -> Result<tag::Model, DbErr> {
-        tracing::info!("Creating tag: {}", tag);
-        tag::ActiveModel {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn create_tag(
+        db: &DbConn,
+        ctx: &AuditContext,
+        tag: &str,
+    ) -> Result<tag::Model, DbErr> {
+        let model = tag::ActiveModel {
             tag: Set(tag.to_owned()),
             ..Default::default()
         }
         .insert(db)
-        .await
+        .await?;
+        cache::forget(CacheKey::Counts);
+
+        let dto = TagDTO::from(model.clone());
+        Self::record_audit(
+            db,
+            ctx,
+            "tag",
+            dto.id,
+            AuditAction::Create,
+            None,
+            Some(&dto),
+        )
+        .await?;
+
+        Ok(model)
     }
 
+    /// Tags a quote, if it is still at `expected_version`. Returns `None`
+    /// when there is no quote with this id.
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn update_quote_with_new_tag(
         db: &DbConn,
+        ctx: &AuditContext,
         quote_id: i32,
         tag: String,
-    ) -> Result<Option<QuoteDTO>, DbErr> {
-        tracing::info!("Updating quote with id: {} to add tag: {}", quote_id, tag);
+        expected_version: i32,
+    ) -> Result<Option<UpdateOutcome<QuoteDTO>>, DbErr> {
+        let Some(quote) = Quote::find_by_id(quote_id).one(db).await? else {
+            return Ok(None);
+        };
 
-        let quote = Quote::find_by_id(quote_id).one(db).await?;
+        let before = Self::get_quote_with_related_tags_and_author(db, quote.clone()).await?;
+        if expected_version != quote.version {
+            return Ok(Some(UpdateOutcome::Conflict(before)));
+        }
 
-        let tag = DataAccess::get_tag_or_create_tag(db, tag).await?;
+        let tag = DataAccess::get_tag_or_create_tag(db, ctx, tag).await?;
 
-        if let Some(quote) = quote {
-            let _ = DataAccess::create_quote_tag_association(db, &quote, &tag).await?;
-            DataAccess::get_quote(db, quote_id).await
-        } else {
-            Err(DbErr::RecordNotFound(format!(
-                "Quote with id {} not found",
-                quote_id
-            )))
+        let version = quote.version;
+        let mut model: quote::ActiveModel = quote.clone().into();
+        model.updated_at = Set(chrono::Utc::now());
+        if !Self::bump_quote_version(db, model, version).await? {
+            return Self::quote_conflict(db, quote_id).await;
         }
+
+        let _ = DataAccess::create_quote_tag_association(db, &quote, &tag).await?;
+        // its other tags' and its author's pages show its tags too
+        Self::forget_quote(&before);
+
+        let after = DataAccess::get_quote(db, quote_id).await?;
+
+        Self::record_audit(
+            db,
+            ctx,
+            "quote",
+            quote_id,
+            AuditAction::Update,
+            Some(&before),
+            after.as_ref(),
+        )
+        .await?;
+
+        Ok(after.map(UpdateOutcome::Updated))
     }
 
-    pub async fn delete_tag(db: &DbConn, tag_id: i32) -> Result<DeleteResult, DbErr> {
-        tracing::info!("Deleting tag with id: {}", tag_id);
-        Tag::delete_by_id(tag_id).exec(db).await.map_err(|e| {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn delete_tag(
+        db: &DbConn,
+        ctx: &AuditContext,
+        tag_id: i32,
+    ) -> Result<DeleteResult, DbErr> {
+        let before = Self::get_tag(db, tag_id).await?;
+        let tagged = match Tag::find_by_id(tag_id).one(db).await? {
+            Some(tag) => tag.find_related(Quote).all(db).await?,
+            None => Vec::new(),
+        };
+
+        let result = Tag::delete_by_id(tag_id).exec(db).await.map_err(|e| {
             tracing::error!("Failed to delete tag: {:?}", e);
             e
-        })
+        })?;
+
+        if result.rows_affected > 0 {
+            for quote in &tagged {
+                cache::forget_quote(quote.id, &[tag_id], quote.author_id);
+            }
+            cache::forget_tag_pages(tag_id);
+            cache::forget(CacheKey::Counts);
+
+            Self::record_audit(
+                db,
+                ctx,
+                "tag",
+                tag_id,
+                AuditAction::Delete,
+                before.as_ref(),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(result)
     }
 
-    pub async fn delete_quote(db: &DbConn, quote_id: i32) -> Result<DeleteResult, DbErr> {
-        tracing::info!("Deleting quote with id: {}", quote_id);
-        Quote::delete_by_id(quote_id).exec(db).await.map_err(|e| {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn delete_quote(
+        db: &DbConn,
+        ctx: &AuditContext,
+        quote_id: i32,
+    ) -> Result<DeleteResult, DbErr> {
+        let before = Self::get_quote(db, quote_id).await?;
+
+        let result = Quote::delete_by_id(quote_id).exec(db).await.map_err(|e| {
             tracing::error!("Failed to delete quote: {:?}", e);
             e
-        })
+        })?;
+
+        if result.rows_affected > 0 {
+            if let Some(before) = &before {
+                Self::forget_quote(before);
+            }
+
+            Self::record_audit(
+                db,
+                ctx,
+                "quote",
+                quote_id,
+                AuditAction::Delete,
+                before.as_ref(),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(result)
     }
 
-    pub async fn delete_author(db: &DbConn, author_id: i32) -> Result<DeleteResult, DbErr> {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn delete_author(
+        db: &DbConn,
+        ctx: &AuditContext,
+        author_id: i32,
+    ) -> Result<DeleteResult, DbErr> {
         // delete the fucking author
-        tracing::info!("Deleting author with id: {}", author_id);
-        Author::delete_by_id(author_id).exec(db).await.map_err(|e| {
-            tracing::error!("Failed to delete author: {:?}", e);
-            e
-        })
+        let before = Self::get_author(db, author_id).await?;
+
+        let result = Author::delete_by_id(author_id)
+            .exec(db)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to delete author: {:?}", e);
+                e
+            })?;
+
+        if result.rows_affected > 0 {
+            cache::forget_author_pages(author_id);
+            cache::forget(CacheKey::Counts);
+
+            Self::record_audit(
+                db,
+                ctx,
+                "author",
+                author_id,
+                AuditAction::Delete,
+                before.as_ref(),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(result)
     }
 
     // don't know if we need these because I didn't explicitly set on delete cascade in the schema
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn delete_quote_tag_association_by_tag_id(
         db: &DbConn,
         tag_id: i32,
     ) -> Result<DeleteResult, DbErr> {
         // filter by tag_id and delete all associations
-        tracing::info!("Deleting quote-tag associations for tag_id: {}", tag_id);
+        tracing::debug!(tag_id, "deleting quote-tag associations");
 
-        QuoteTagAssociation::delete_many()
+        let tagged = QuoteTagAssociation::find()
+            .filter(quote_tag_association::Column::TagId.eq(tag_id))
+            .find_also_related(Quote)
+            .all(db)
+            .await?;
+
+        let result = QuoteTagAssociation::delete_many()
             .filter(quote_tag_association::Column::TagId.eq(tag_id))
             .exec(db)
             .await
             .map_err(|e| {
                 tracing::error!("Failed to delete quote-tag associations: {:?}", e);
                 e
-            })
+            })?;
+
+        for quote in tagged.into_iter().filter_map(|(_, quote)| quote) {
+            cache::forget_quote(quote.id, &[tag_id], quote.author_id);
+        }
+
+        Ok(result)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn delete_quote_tag_association_by_quote_id(
         db: &DbConn,
         quote_id: i32,
     ) -> Result<DeleteResult, DbErr> {
         //filter by quote_id and delete all associations
-        tracing::info!("Deleting quote-tag associations for quote_id: {}", quote_id);
+        tracing::debug!(quote_id, "deleting quote-tag associations");
+
+        let before = Self::get_quote(db, quote_id).await?;
 
-        QuoteTagAssociation::delete_many()
+        let result = QuoteTagAssociation::delete_many()
             .filter(quote_tag_association::Column::QuoteId.eq(quote_id))
             .exec(db)
             .await
             .map_err(|e| {
                 tracing::error!("Failed to delete quote-tag associations: {:?}", e);
                 e
-            })
+            })?;
+
+        if let Some(before) = &before {
+            Self::forget_quote(before);
+        }
+
+        Ok(result)
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn create_quote_tag_association(
         db: &DbConn,
         quote: &quote::Model,
         tag: &TagDTO,
     ) -> Result<quote_tag_association::Model, DbErr> {
-        tracing::info!(
-            "\n\n\tCreating quote-tag association for quote_id: {}, tag_id: {}",
-            quote.id,
-            tag.id
+        tracing::debug!(
+            quote_id = quote.id,
+            tag_id = tag.id,
+            "creating quote-tag association"
         );
 
         match (quote_tag_association::ActiveModel {
@@ -402,11 +788,1166 @@ impl DataAccess {
         .insert(db)
         .await)
         {
-            Ok(am) => Ok(am),
+            Ok(am) => {
+                cache::forget_quote(quote.id, &[tag.id], quote.author_id);
+                Ok(am)
+            }
             Err(e) => {
                 tracing::error!("Failed to save quote_tag_association: {:?}", e);
                 Err(e)
             }
         }
     }
+
+    // DUPLICATES
+    // every quote carries fingerprints of its text (see duplicates.rs).
+    // Rejected quotes are left out, so resubmitting one isn't refused
+
+    /// Runs the configured duplicate checks on the text of a new quote.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn check_duplicates(
+        db: &DbConn,
+        config: &DuplicatesConfig,
+        text: &str,
+    ) -> Result<DuplicateVerdict, DbErr> {
+        if !config.enabled() {
+            return Ok(DuplicateVerdict::Unique);
+        }
+
+        let matches = Self::find_duplicates(db, text, config.threshold).await?;
+        Ok(config.verdict(matches))
+    }
+
+    /// Stored quotes with the same text as `text` or at least `threshold`
+    /// similar to it, closest first.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn find_duplicates(
+        db: &DbConn,
+        text: &str,
+        threshold: f64,
+    ) -> Result<Vec<DuplicateMatchDTO>, DbErr> {
+        let text_hash = duplicates::text_hash(text);
+        let signature = Signature::of(text);
+
+        let mut matches: Vec<DuplicateMatchDTO> = Self::get_fingerprints(db)
+            .await?
+            .into_iter()
+            .filter_map(|(quote_id, stored_hash, stored_signature)| {
+                if stored_hash == text_hash {
+                    return Some(DuplicateMatchDTO {
+                        quote_id,
+                        similarity: 1.0,
+                        exact: true,
+                    });
+                }
+
+                let similarity = stored_signature.similarity(&signature);
+                (similarity >= threshold).then_some(DuplicateMatchDTO {
+                    quote_id,
+                    similarity,
+                    exact: false,
+                })
+            })
+            .collect();
+
+        matches.sort_by(|a, b| {
+            b.exact
+                .cmp(&a.exact)
+                .then(b.similarity.total_cmp(&a.similarity))
+                .then(a.quote_id.cmp(&b.quote_id))
+        });
+
+        Ok(matches)
+    }
+
+    /// Groups of stored quotes that duplicate one another, by lowest id.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_duplicate_clusters(
+        db: &DbConn,
+        threshold: f64,
+    ) -> Result<Vec<DuplicateClusterDTO>, DbErr> {
+        let fingerprints = Self::get_fingerprints(db).await?;
+
+        let mut result = Vec::new();
+        for cluster in duplicates::clusters(&fingerprints, threshold) {
+            let mut quotes = Vec::with_capacity(cluster.ids.len());
+            for id in cluster.ids {
+                if let Some(quote) = Self::get_quote(db, id).await? {
+                    quotes.push(quote);
+                }
+            }
+
+            result.push(DuplicateClusterDTO {
+                exact: cluster.exact,
+                similarity: cluster.similarity,
+                quotes,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Folds the `merged` quotes into `keep_id`: their tags are added to it,
+    /// its source is taken from them if it has none, and they are deleted.
+    /// All of it happens in one transaction, and nothing changes unless
+    /// `keep_id` is still at `expected_version`. Returns `None` when any of
+    /// the quotes doesn't exist.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn merge_quotes(
+        db: &DbConn,
+        ctx: &AuditContext,
+        keep_id: i32,
+        merged_ids: &[i32],
+        expected_version: i32,
+    ) -> Result<Option<UpdateOutcome<QuoteDTO>>, DbErr> {
+        let txn = db.begin().await?;
+
+        let Some(kept) = Quote::find_by_id(keep_id).one(&txn).await? else {
+            return Ok(None);
+        };
+
+        let mut merged = Vec::with_capacity(merged_ids.len());
+        for &id in merged_ids.iter().filter(|&&id| id != keep_id) {
+            let Some(quote) = Self::load_quote(&txn, id).await? else {
+                return Ok(None);
+            };
+            merged.push(quote);
+        }
+
+        let before = Self::get_quote_with_related_tags_and_author(&txn, kept.clone()).await?;
+        if expected_version != kept.version {
+            return Ok(Some(UpdateOutcome::Conflict(before)));
+        }
+
+        let version = kept.version;
+        let mut model: quote::ActiveModel = kept.clone().into();
+        model.updated_at = Set(chrono::Utc::now());
+        if before.source.is_none() {
+            if let Some(source) = merged.iter().find_map(|quote| quote.source.clone()) {
+                model.source = Set(Some(source));
+            }
+        }
+        if !Self::bump_quote_version(&txn, model, version).await? {
+            txn.rollback().await?;
+            return Self::quote_conflict(db, keep_id).await;
+        }
+
+        let mut tag_ids: Vec<i32> = before.related_tags.iter().map(|tag| tag.id).collect();
+        for quote in &merged {
+            for tag in &quote.related_tags {
+                if !tag_ids.contains(&tag.id) {
+                    quote_tag_association::ActiveModel {
+                        quote_id: Set(keep_id),
+                        tag_id: Set(tag.id),
+                    }
+                    .insert(&txn)
+                    .await?;
+                    tag_ids.push(tag.id);
+                }
+            }
+
+            QuoteTagAssociation::delete_many()
+                .filter(quote_tag_association::Column::QuoteId.eq(quote.id))
+                .exec(&txn)
+                .await?;
+            Quote::delete_by_id(quote.id).exec(&txn).await?;
+
+            Self::record_audit(
+                &txn,
+                ctx,
+                "quote",
+                quote.id,
+                AuditAction::Delete,
+                Some(quote),
+                None,
+            )
+            .await?;
+        }
+
+        let after = Self::load_quote(&txn, keep_id).await?;
+
+        Self::record_audit(
+            &txn,
+            ctx,
+            "quote",
+            keep_id,
+            AuditAction::Update,
+            Some(&before),
+            after.as_ref(),
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        // only now, so a read in between can't cache what was rolled back
+        // or put back what was merged away
+        Self::forget_quote(&before);
+        for quote in merged.iter().chain(&after) {
+            Self::forget_quote(quote);
+        }
+        cache::forget(CacheKey::Counts);
+
+        Ok(after.map(UpdateOutcome::Updated))
+    }
+
+    /// Fingerprints quotes stored before duplicate detection existed, and
+    /// returns how many there were. This is a system backfill at startup and
+    /// isn't audited: it only fills in hashes derived from the text, which
+    /// no entry would show, and changes nothing anyone wrote.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn fingerprint_quotes(db: &DbConn) -> Result<usize, DbErr> {
+        let missing = Quote::find()
+            .filter(
+                Condition::any()
+                    .add(quote::Column::TextHash.is_null())
+                    .add(quote::Column::Minhash.is_null()),
+            )
+            .all(db)
+            .await?;
+
+        let count = missing.len();
+        for model in missing {
+            let text = model.quote.clone();
+            let mut model: quote::ActiveModel = model.into();
+            model.text_hash = Set(Some(duplicates::text_hash(&text)));
+            model.minhash = Set(Some(Signature::of(&text).to_hex()));
+            model.update(db).await?;
+        }
+
+        Ok(count)
+    }
+
+    // id, text hash and signature of every quote that isn't rejected
+    async fn get_fingerprints(db: &DbConn) -> Result<Vec<(i32, String, Signature)>, DbErr> {
+        let rows: Vec<(i32, Option<String>, Option<String>)> = Quote::find()
+            .select_only()
+            .column(quote::Column::Id)
+            .column(quote::Column::TextHash)
+            .column(quote::Column::Minhash)
+            .filter(quote::Column::Status.ne(QuoteStatus::Rejected.as_str()))
+            .order_by(quote::Column::Id, Order::Asc)
+            .into_tuple()
+            .all(db)
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(id, text_hash, minhash)| {
+                Some((id, text_hash?, Signature::from_hex(&minhash?)?))
+            })
+            .collect())
+    }
+
+    // MODERATION
+    // quotes submitted by anyone who can't moderate wait here until a
+    // moderator approves or rejects them
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_pending_quotes(db: &DbConn) -> Result<Vec<QuoteDTO>, DbErr> {
+        let quotes = Quote::find()
+            .filter(quote::Column::Status.eq(QuoteStatus::Pending.as_str()))
+            .order_by(quote::Column::CreatedAt, Order::Asc)
+            .order_by(quote::Column::Id, Order::Asc)
+            .all(db)
+            .await?;
+
+        let mut result: Vec<QuoteDTO> = Vec::new();
+
+        for quote in quotes {
+            result.push(Self::get_quote_with_related_tags_and_author(db, quote).await?);
+        }
+
+        Ok(result)
+    }
+
+    /// Applies any edits and publishes a pending quote, if it is still at
+    /// `expected_version`. Returns `None` when there is no pending quote with
+    /// this id.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn approve_quote(
+        db: &DbConn,
+        ctx: &AuditContext,
+        quote_id: i32,
+        moderator: Option<i32>,
+        edits: QuoteEditDTO,
+        expected_version: i32,
+    ) -> Result<Option<UpdateOutcome<QuoteDTO>>, DbErr> {
+        let Some(model) = Self::find_pending_quote(db, quote_id).await? else {
+            return Ok(None);
+        };
+
+        let before = Self::get_quote_with_related_tags_and_author(db, model.clone()).await?;
+        if expected_version != model.version {
+            return Ok(Some(UpdateOutcome::Conflict(before)));
+        }
+
+        let version = model.version;
+        let mut model: quote::ActiveModel = model.into();
+
+        if let Some(text) = edits.quote.filter(|text| !text.trim().is_empty()) {
+            model.text_hash = Set(Some(duplicates::text_hash(&text)));
+            model.minhash = Set(Some(Signature::of(&text).to_hex()));
+            model.quote = Set(text);
+        }
+        if let Some(author_name) = edits.author_name.filter(|name| !name.trim().is_empty()) {
+            let author = Self::get_or_create_author_model(db, ctx, author_name).await?;
+            model.author_id = Set(author.id);
+        }
+        if let Some(source) = edits.source {
+            model.source = Set(Some(source).filter(|source| !source.trim().is_empty()));
+        }
+
+        let now = chrono::Utc::now();
+        model.status = Set(QuoteStatus::Approved.as_str().to_owned());
+        model.rejection_reason = Set(None);
+        // a moderator has looked at it, so the spam flag has done its job
+        model.flagged_reason = Set(None);
+        model.moderated_by = Set(moderator);
+        model.moderated_at = Set(Some(now));
+        model.updated_at = Set(now);
+
+        if !Self::bump_quote_version(db, model, version).await? {
+            return Self::quote_conflict(db, quote_id).await;
+        }
+        Self::forget_quote(&before);
+        let Some(after) = Self::get_quote(db, quote_id).await? else {
+            return Ok(None);
+        };
+        // an edit may have moved it to another author
+        cache::forget_author_pages(after.author.id);
+
+        Self::record_audit(
+            db,
+            ctx,
+            "quote",
+            quote_id,
+            AuditAction::Update,
+            Some(&before),
+            Some(&after),
+        )
+        .await?;
+
+        Ok(Some(UpdateOutcome::Updated(after)))
+    }
+
+    /// Rejects a pending quote, if it is still at `expected_version`.
+    /// Returns `None` when there is no pending quote with this id.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn reject_quote(
+        db: &DbConn,
+        ctx: &AuditContext,
+        quote_id: i32,
+        moderator: Option<i32>,
+        reason: String,
+        expected_version: i32,
+    ) -> Result<Option<UpdateOutcome<QuoteDTO>>, DbErr> {
+        let Some(model) = Self::find_pending_quote(db, quote_id).await? else {
+            return Ok(None);
+        };
+
+        let before = Self::get_quote_with_related_tags_and_author(db, model.clone()).await?;
+        if expected_version != model.version {
+            return Ok(Some(UpdateOutcome::Conflict(before)));
+        }
+
+        let now = chrono::Utc::now();
+        let version = model.version;
+        let mut model: quote::ActiveModel = model.into();
+        model.status = Set(QuoteStatus::Rejected.as_str().to_owned());
+        model.rejection_reason = Set(Some(reason));
+        model.moderated_by = Set(moderator);
+        model.moderated_at = Set(Some(now));
+        model.updated_at = Set(now);
+
+        if !Self::bump_quote_version(db, model, version).await? {
+            return Self::quote_conflict(db, quote_id).await;
+        }
+        Self::forget_quote(&before);
+        let Some(after) = Self::get_quote(db, quote_id).await? else {
+            return Ok(None);
+        };
+
+        Self::record_audit(
+            db,
+            ctx,
+            "quote",
+            quote_id,
+            AuditAction::Update,
+            Some(&before),
+            Some(&after),
+        )
+        .await?;
+
+        Ok(Some(UpdateOutcome::Updated(after)))
+    }
+
+    // writes the changes and the next version, unless someone else has
+    // written a newer version since `version` was read
+    async fn bump_quote_version<C: ConnectionTrait>(
+        db: &C,
+        mut model: quote::ActiveModel,
+        version: i32,
+    ) -> Result<bool, DbErr> {
+        model.version = Set(version + 1);
+
+        match Quote::update(model)
+            .filter(quote::Column::Version.eq(version))
+            .exec(db)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(DbErr::RecordNotUpdated) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn quote_conflict(
+        db: &DbConn,
+        quote_id: i32,
+    ) -> Result<Option<UpdateOutcome<QuoteDTO>>, DbErr> {
+        // the cached copy may be the one that lost
+        cache::forget(CacheKey::Quote(quote_id));
+
+        Ok(Self::get_quote(db, quote_id)
+            .await?
+            .map(UpdateOutcome::Conflict))
+    }
+
+    async fn find_pending_quote(db: &DbConn, quote_id: i32) -> Result<Option<quote::Model>, DbErr> {
+        Quote::find_by_id(quote_id)
+            .filter(quote::Column::Status.eq(QuoteStatus::Pending.as_str()))
+            .one(db)
+            .await
+    }
+
+    // API KEYS
+    // keys are only ever shown once, when they are created; the table keeps a
+    // sha256 of the key plus a short prefix so a key can be recognised in listings
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn create_api_key(
+        db: &DbConn,
+        ctx: &AuditContext,
+        name: String,
+        scopes: Vec<Scope>,
+    ) -> Result<(ApiKeyDTO, String), DbErr> {
+        let key = format!("qs_{}", Self::random_token());
+
+        let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+
+        let model = api_key::ActiveModel {
+            name: Set(name),
+            prefix: Set(key[..11].to_owned()),
+            key_hash: Set(Self::hash_secret(&key)),
+            scopes: Set(scopes.join(",")),
+            created_at: Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+
+        let api_key: ApiKeyDTO = model.into();
+        Self::record_audit(
+            db,
+            ctx,
+            "api_key",
+            api_key.id,
+            AuditAction::Create,
+            None,
+            Some(&api_key),
+        )
+        .await?;
+
+        Ok((api_key, key))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_api_keys(db: &DbConn) -> Result<Vec<ApiKeyDTO>, DbErr> {
+        let keys = ApiKey::find()
+            .order_by(api_key::Column::Id, Order::Asc)
+            .all(db)
+            .await?;
+
+        Ok(keys.into_iter().map(ApiKeyDTO::from).collect())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn revoke_api_key(
+        db: &DbConn,
+        ctx: &AuditContext,
+        api_key_id: i32,
+    ) -> Result<Option<ApiKeyDTO>, DbErr> {
+        let Some(model) = ApiKey::find_by_id(api_key_id).one(db).await? else {
+            return Ok(None);
+        };
+
+        let before = ApiKeyDTO::from(model.clone());
+
+        let mut model: api_key::ActiveModel = model.into();
+        model.revoked_at = Set(Some(chrono::Utc::now()));
+
+        let after: ApiKeyDTO = model.update(db).await?.into();
+        Self::record_audit(
+            db,
+            ctx,
+            "api_key",
+            api_key_id,
+            AuditAction::Update,
+            Some(&before),
+            Some(&after),
+        )
+        .await?;
+
+        Ok(Some(after))
+    }
+
+    /// Looks up an unrevoked key and records that it was used.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn authenticate_api_key(db: &DbConn, key: &str) -> Result<Option<ApiKeyDTO>, DbErr> {
+        let model = ApiKey::find()
+            .filter(api_key::Column::KeyHash.eq(Self::hash_secret(key)))
+            .filter(api_key::Column::RevokedAt.is_null())
+            .one(db)
+            .await?;
+
+        let Some(model) = model else {
+            return Ok(None);
+        };
+
+        let mut model: api_key::ActiveModel = model.into();
+        model.last_used_at = Set(Some(chrono::Utc::now()));
+
+        Ok(Some(model.update(db).await?.into()))
+    }
+
+    // IDEMPOTENCY
+    // keys are scoped to whoever sent them, so two clients can't see each
+    // other's responses by picking the same key. Rows older than the window
+    // are dropped the next time a key is claimed
+
+    /// Claims `key` for a request, or returns what an earlier request with the
+    /// same key left behind. A claim still without a response after `lease`
+    /// was abandoned, e.g. by a client that disconnected, and is taken over.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn claim_idempotency_key(
+        db: &DbConn,
+        actor: &str,
+        key: &str,
+        request_hash: &str,
+        window: chrono::Duration,
+        lease: chrono::Duration,
+    ) -> Result<IdempotencyClaim, DbErr> {
+        let now = chrono::Utc::now();
+
+        IdempotencyKey::delete_many()
+            .filter(idempotency_key::Column::CreatedAt.lt(now - window))
+            .exec(db)
+            .await?;
+
+        let inserted = idempotency_key::ActiveModel {
+            actor: Set(actor.to_owned()),
+            key: Set(key.to_owned()),
+            request_hash: Set(request_hash.to_owned()),
+            created_at: Set(now),
+            ..Default::default()
+        }
+        .insert(db)
+        .await;
+
+        match inserted {
+            Ok(_) => Ok(IdempotencyClaim::Claimed),
+            Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+                // one statement, so of two retries only one takes it over
+                let taken_over = IdempotencyKey::update_many()
+                    .col_expr(
+                        idempotency_key::Column::CreatedAt,
+                        sea_query::Expr::value(now),
+                    )
+                    .filter(idempotency_key::Column::Actor.eq(actor))
+                    .filter(idempotency_key::Column::Key.eq(key))
+                    .filter(idempotency_key::Column::RequestHash.eq(request_hash))
+                    .filter(idempotency_key::Column::Status.is_null())
+                    .filter(idempotency_key::Column::CreatedAt.lt(now - lease))
+                    .exec(db)
+                    .await?;
+
+                if taken_over.rows_affected > 0 {
+                    return Ok(IdempotencyClaim::Claimed);
+                }
+
+                let existing = IdempotencyKey::find()
+                    .filter(idempotency_key::Column::Actor.eq(actor))
+                    .filter(idempotency_key::Column::Key.eq(key))
+                    .one(db)
+                    .await?
+                    .ok_or(e)?;
+
+                Ok(IdempotencyClaim::Existing(existing.into()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores the response to the request that claimed `key`.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn store_idempotent_response(
+        db: &DbConn,
+        actor: &str,
+        key: &str,
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+    ) -> Result<(), DbErr> {
+        let model = IdempotencyKey::find()
+            .filter(idempotency_key::Column::Actor.eq(actor))
+            .filter(idempotency_key::Column::Key.eq(key))
+            .one(db)
+            .await?;
+
+        // purged already, when the request outlived the window
+        let Some(model) = model else {
+            return Ok(());
+        };
+
+        let mut model: idempotency_key::ActiveModel = model.into();
+        model.status = Set(Some(i32::from(status)));
+        model.content_type = Set(content_type);
+        model.body = Set(Some(body));
+        model.update(db).await?;
+
+        Ok(())
+    }
+
+    /// Gives up a claim without a response, so the request can be retried
+    /// with the same key.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn release_idempotency_key(db: &DbConn, actor: &str, key: &str) -> Result<(), DbErr> {
+        IdempotencyKey::delete_many()
+            .filter(idempotency_key::Column::Actor.eq(actor))
+            .filter(idempotency_key::Column::Key.eq(key))
+            .exec(db)
+            .await?;
+
+        Ok(())
+    }
+
+    // USERS
+    // passwords are stored as argon2 phc strings
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn register_user(
+        db: &DbConn,
+        ctx: &AuditContext,
+        username: String,
+        password: String,
+    ) -> Result<UserDTO, AccountError> {
+        accounts::validate(&username, &password)?;
+
+        let taken = User::find()
+            .filter(user::Column::Username.eq(username.as_str()))
+            .one(db)
+            .await?
+            .is_some();
+
+        if taken {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        let model = user::ActiveModel {
+            username: Set(username),
+            password_hash: Set(accounts::hash_password(&password)?),
+            role: Set(Role::Contributor.as_str().to_owned()),
+            created_at: Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .map_err(|e| match e.sql_err() {
+            // another registration took the name since the check above
+            Some(SqlErr::UniqueConstraintViolation(_)) => AccountError::UsernameTaken,
+            _ => e.into(),
+        })?;
+
+        let user: UserDTO = model.into();
+        Self::record_audit(
+            db,
+            ctx,
+            "user",
+            user.id,
+            AuditAction::Create,
+            None,
+            Some(&user),
+        )
+        .await?;
+
+        Ok(user)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn authenticate_user(
+        db: &DbConn,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserDTO>, DbErr> {
+        let model = User::find()
+            .filter(user::Column::Username.eq(username))
+            .one(db)
+            .await?;
+
+        Ok(model
+            .filter(|model| accounts::verify_password(password, &model.password_hash))
+            .map(UserDTO::from))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_users(db: &DbConn) -> Result<Vec<UserDTO>, DbErr> {
+        let users = User::find()
+            .order_by(user::Column::Username, Order::Asc)
+            .all(db)
+            .await?;
+
+        Ok(users.into_iter().map(UserDTO::from).collect())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn set_user_role(
+        db: &DbConn,
+        ctx: &AuditContext,
+        user_id: i32,
+        role: Role,
+    ) -> Result<Option<UserDTO>, DbErr> {
+        let Some(model) = User::find_by_id(user_id).one(db).await? else {
+            return Ok(None);
+        };
+
+        let before = UserDTO::from(model.clone());
+
+        let mut model: user::ActiveModel = model.into();
+        model.role = Set(role.as_str().to_owned());
+
+        let after: UserDTO = model.update(db).await?.into();
+        Self::record_audit(
+            db,
+            ctx,
+            "user",
+            user_id,
+            AuditAction::Update,
+            Some(&before),
+            Some(&after),
+        )
+        .await?;
+
+        Ok(Some(after))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_user_by_username(
+        db: &DbConn,
+        username: &str,
+    ) -> Result<Option<UserDTO>, DbErr> {
+        let model = User::find()
+            .filter(user::Column::Username.eq(username))
+            .one(db)
+            .await?;
+
+        Ok(model.map(UserDTO::from))
+    }
+
+    // STATS
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_counts(db: &DbConn) -> Result<CountsDTO, DbErr> {
+        cache::get_or_load(CacheKey::Counts, Self::load_counts(db)).await
+    }
+
+    async fn load_counts(db: &DbConn) -> Result<CountsDTO, DbErr> {
+        let quotes_with_status = |status: QuoteStatus| {
+            Quote::find()
+                .filter(quote::Column::Status.eq(status.as_str()))
+                .count(db)
+        };
+
+        Ok(CountsDTO {
+            approved_quotes: quotes_with_status(QuoteStatus::Approved).await?,
+            pending_quotes: quotes_with_status(QuoteStatus::Pending).await?,
+            rejected_quotes: quotes_with_status(QuoteStatus::Rejected).await?,
+            authors: Author::find().count(db).await?,
+            tags: Tag::find().count(db).await?,
+        })
+    }
+
+    // AUDIT LOG
+    // every create, update and delete above is appended here, and logged as
+    // one `mutation` event with the same fields. Sessions and api key
+    // last-used times are bookkeeping and are left out
+
+    async fn record_audit<C: ConnectionTrait, T: Serialize>(
+        db: &C,
+        ctx: &AuditContext,
+        entity: &str,
+        entity_id: i32,
+        action: AuditAction,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) -> Result<(), DbErr> {
+        tracing::info!(
+            entity,
+            entity_id,
+            action = action.as_str(),
+            actor = %ctx.actor,
+            request_id = ctx.request_id.as_deref(),
+            "mutation"
+        );
+
+        let to_json = |dto: Option<&T>| dto.and_then(|dto| serde_json::to_string(dto).ok());
+
+        audit_log::ActiveModel {
+            entity: Set(entity.to_owned()),
+            entity_id: Set(entity_id),
+            action: Set(action.as_str().to_owned()),
+            actor: Set(ctx.actor.clone()),
+            before: Set(to_json(before)),
+            after: Set(to_json(after)),
+            request_id: Set(ctx.request_id.clone()),
+            created_at: Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Audit entries, oldest first, optionally narrowed to one kind of entity,
+    /// one actor or entries recorded at or after `since`.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_audit_log(
+        db: &DbConn,
+        entity: Option<String>,
+        actor: Option<String>,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<AuditEntryDTO>, DbErr> {
+        let mut query = AuditLog::find().order_by(audit_log::Column::Id, Order::Asc);
+
+        if let Some(entity) = entity {
+            query = query.filter(audit_log::Column::Entity.eq(entity));
+        }
+
+        if let Some(actor) = actor {
+            query = query.filter(audit_log::Column::Actor.eq(actor));
+        }
+
+        if let Some(since) = since {
+            query = query.filter(audit_log::Column::CreatedAt.gte(since));
+        }
+
+        let entries = query.limit(limit).all(db).await?;
+
+        Ok(entries.into_iter().map(AuditEntryDTO::from).collect())
+    }
+
+    // SESSIONS
+    // the cookie holds a random token; the table is keyed by its sha256
+
+    /// Starts a session for the user and returns the token for the cookie.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn create_session(db: &DbConn, user_id: i32) -> Result<String, DbErr> {
+        let now = chrono::Utc::now();
+
+        Session::delete_many()
+            .filter(session::Column::ExpiresAt.lt(now))
+            .exec(db)
+            .await?;
+
+        let token = Self::random_token();
+
+        session::ActiveModel {
+            id: Set(Self::hash_secret(&token)),
+            user_id: Set(user_id),
+            created_at: Set(now),
+            expires_at: Set(now + SESSION_LIFETIME),
+        }
+        .insert(db)
+        .await?;
+
+        Ok(token)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn get_session_user(db: &DbConn, token: &str) -> Result<Option<UserDTO>, DbErr> {
+        let found = Session::find_by_id(Self::hash_secret(token))
+            .filter(session::Column::ExpiresAt.gt(chrono::Utc::now()))
+            .find_also_related(User)
+            .one(db)
+            .await?;
+
+        Ok(found.and_then(|(_, user)| user).map(UserDTO::from))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn delete_session(db: &DbConn, token: &str) -> Result<(), DbErr> {
+        Session::delete_by_id(Self::hash_secret(token))
+            .exec(db)
+            .await?;
+
+        Ok(())
+    }
+
+    // 32 random bytes as hex
+    fn random_token() -> String {
+        let secret: [u8; 32] = rand::random();
+        secret.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn hash_secret(secret: &str) -> String {
+        format!("{:x}", Sha256::digest(secret.as_bytes()))
+    }
+
+    // drops the cached reads a quote appears in
+    fn forget_quote(quote: &QuoteDTO) {
+        let tag_ids: Vec<i32> = quote.related_tags.iter().map(|tag| tag.id).collect();
+        cache::forget_quote(quote.id, &tag_ids, quote.author.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_transfer_objects::TagCreateDTO;
+    use migration::MigratorTrait;
+
+    async fn db() -> DbConn {
+        let db = Database::connect("sqlite::memory:")
+            .await
+            .expect("in-memory database");
+        migration::Migrator::up(&db, None)
+            .await
+            .expect("migrations");
+        db
+    }
+
+    async fn quote(db: &DbConn) -> QuoteDTO {
+        DataAccess::create_quote(
+            db,
+            &AuditContext::cli(),
+            QuoteCreateDTO {
+                quote: "Simplicity is the ultimate sophistication.".to_owned(),
+                related_tags: Vec::new(),
+                author_name: "Leonardo da Vinci".to_owned(),
+                source: None,
+                submitted_by: None,
+                status: QuoteStatus::Approved,
+                flagged_reason: None,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_the_first_of_two_writers_bumps_a_version() {
+        let db = db().await;
+        let created = quote(&db).await;
+
+        // both read the quote before either writes
+        let model = Quote::find_by_id(created.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        let first: quote::ActiveModel = model.clone().into();
+        let second: quote::ActiveModel = model.clone().into();
+
+        assert!(DataAccess::bump_quote_version(&db, first, model.version)
+            .await
+            .unwrap());
+        assert!(!DataAccess::bump_quote_version(&db, second, model.version)
+            .await
+            .unwrap());
+
+        let stored = Quote::find_by_id(created.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.version, model.version + 1);
+    }
+
+    #[tokio::test]
+    async fn an_edit_from_an_old_version_gets_the_current_quote_back() {
+        let db = db().await;
+        let ctx = AuditContext::cli();
+        let created = quote(&db).await;
+
+        let edited = DataAccess::update_quote_with_new_tag(
+            &db,
+            &ctx,
+            created.id,
+            "art".to_owned(),
+            created.version,
+        )
+        .await
+        .unwrap();
+        let Some(UpdateOutcome::Updated(edited)) = edited else {
+            panic!("the first edit should apply");
+        };
+        assert_eq!(edited.version, created.version + 1);
+
+        let stale = DataAccess::update_quote_with_new_tag(
+            &db,
+            &ctx,
+            created.id,
+            "design".to_owned(),
+            created.version,
+        )
+        .await
+        .unwrap();
+        let Some(UpdateOutcome::Conflict(current)) = stale else {
+            panic!("an edit from the old version should conflict");
+        };
+        assert_eq!(current.version, edited.version);
+        let tags: Vec<&str> = current
+            .related_tags
+            .iter()
+            .map(|tag| tag.tag.as_str())
+            .collect();
+        assert_eq!(tags, ["art"]);
+    }
+
+    async fn tagged(db: &DbConn, text: &str, tags: &[&str], source: Option<&str>) -> QuoteDTO {
+        DataAccess::create_quote(
+            db,
+            &AuditContext::cli(),
+            QuoteCreateDTO {
+                quote: text.to_owned(),
+                related_tags: tags
+                    .iter()
+                    .map(|tag| TagCreateDTO {
+                        tag: tag.to_string(),
+                    })
+                    .collect(),
+                author_name: "Leonardo da Vinci".to_owned(),
+                source: source.map(str::to_owned),
+                submitted_by: None,
+                status: QuoteStatus::Approved,
+                flagged_reason: None,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn merging_a_cluster_keeps_one_quote_with_all_the_tags() {
+        let db = db().await;
+        let ctx = AuditContext::new("user:mod", None);
+        let keep = tagged(&db, "Simplicity is sophistication.", &["art"], None).await;
+        let second = tagged(
+            &db,
+            "Simplicity is sophistication!",
+            &["art", "design"],
+            None,
+        )
+        .await;
+        let third = tagged(
+            &db,
+            "Simplicity: sophistication.",
+            &["wit"],
+            Some("Notebooks"),
+        )
+        .await;
+
+        let outcome = DataAccess::merge_quotes(
+            &db,
+            &ctx,
+            keep.id,
+            &[keep.id, second.id, third.id],
+            keep.version,
+        )
+        .await
+        .unwrap();
+        let Some(UpdateOutcome::Updated(merged)) = outcome else {
+            panic!("the merge should apply");
+        };
+
+        let mut tags: Vec<&str> = merged
+            .related_tags
+            .iter()
+            .map(|tag| tag.tag.as_str())
+            .collect();
+        tags.sort();
+        assert_eq!(tags, ["art", "design", "wit"]);
+        assert_eq!(merged.source.as_deref(), Some("Notebooks"));
+        assert_eq!(merged.version, keep.version + 1);
+
+        assert!(DataAccess::get_quote(&db, second.id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(DataAccess::get_quote(&db, third.id)
+            .await
+            .unwrap()
+            .is_none());
+        let associations = QuoteTagAssociation::find()
+            .filter(quote_tag_association::Column::QuoteId.is_in([second.id, third.id]))
+            .count(&db)
+            .await
+            .unwrap();
+        assert_eq!(associations, 0);
+
+        let entries: Vec<(i32, AuditAction)> =
+            DataAccess::get_audit_log(&db, None, Some("user:mod".to_owned()), None, None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.entity_id, entry.action))
+                .collect();
+        assert_eq!(
+            entries,
+            [
+                (second.id, AuditAction::Delete),
+                (third.id, AuditAction::Delete),
+                (keep.id, AuditAction::Update),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_merge_against_an_old_version_changes_nothing() {
+        let db = db().await;
+        let ctx = AuditContext::cli();
+        let keep = tagged(&db, "Simplicity is sophistication.", &["art"], None).await;
+        let other = tagged(&db, "Simplicity is sophistication!", &["design"], None).await;
+
+        let outcome = DataAccess::merge_quotes(&db, &ctx, keep.id, &[other.id], keep.version - 1)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Some(UpdateOutcome::Conflict(_))));
+
+        assert!(DataAccess::get_quote(&db, other.id)
+            .await
+            .unwrap()
+            .is_some());
+        let current = DataAccess::get_quote(&db, keep.id).await.unwrap().unwrap();
+        assert_eq!(current.version, keep.version);
+        assert_eq!(current.related_tags.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_one_of_two_registrations_gets_a_name() {
+        let db = db().await;
+        let ctx = AuditContext::cli();
+        let register =
+            || DataAccess::register_user(&db, &ctx, "ada".to_owned(), "correct horse".to_owned());
+
+        let (first, second) = tokio::join!(register(), register());
+
+        let taken = [&first, &second]
+            .into_iter()
+            .filter(|result| matches!(result, Err(AccountError::UsernameTaken)))
+            .count();
+        assert!(first.is_ok() || second.is_ok());
+        assert_eq!(taken, 1);
+    }
 }
@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use utoipa::ToSchema;
 
 #[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
@@ -6,6 +8,17 @@ pub struct QuoteCreateDTO {
     pub quote: String,
     pub related_tags: Vec<TagCreateDTO>,
     pub author_name: String,
+    #[serde(default)]
+    pub source: Option<String>,
+    /// set by the server from whoever is signed in, never by the client
+    #[serde(skip)]
+    pub submitted_by: Option<i32>,
+    /// set by the server: pending unless the submitter can moderate
+    #[serde(skip)]
+    pub status: QuoteStatus,
+    /// set by the server when the spam checks sent the quote to moderation
+    #[serde(skip)]
+    pub flagged_reason: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
@@ -14,6 +27,85 @@ pub struct QuoteDTO {
     pub quote: String,
     pub related_tags: Vec<TagDTO>,
     pub author: AuthorDTO,
+    pub source: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// id of the user who added the quote
+    pub submitted_by: Option<i32>,
+    pub status: QuoteStatus,
+    pub rejection_reason: Option<String>,
+    /// why the spam checks held the quote for moderation, or the quote it
+    /// may duplicate
+    pub flagged_reason: Option<String>,
+    /// goes up by one with every change; send it back with an edit
+    pub version: i32,
+}
+
+impl QuoteDTO {
+    /// Contributors may only edit the quotes they submitted themselves.
+    pub fn editable_by(&self, role: Role, user_id: Option<i32>) -> bool {
+        role.allows(Permission::EditAnyQuote)
+            || (role.allows(Permission::EditOwnQuotes) && self.submitted_by_user(user_id))
+    }
+
+    /// Quotes still waiting for (or refused by) moderation are only visible
+    /// to moderators and to whoever submitted them.
+    pub fn visible_to(&self, role: Role, user_id: Option<i32>) -> bool {
+        self.status == QuoteStatus::Approved
+            || role.allows(Permission::ModerateQuotes)
+            || self.submitted_by_user(user_id)
+    }
+
+    fn submitted_by_user(&self, user_id: Option<i32>) -> bool {
+        self.submitted_by.is_some() && self.submitted_by == user_id
+    }
+}
+
+/// Changes a moderator makes to a quote while approving it. Fields left out
+/// are kept as submitted.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct QuoteEditDTO {
+    pub quote: Option<String>,
+    pub author_name: Option<String>,
+    pub source: Option<String>,
+    /// the version the moderator saw; the approval is refused if the quote
+    /// has changed since. Needed unless the request has an `If-Match` header
+    pub version: Option<i32>,
+}
+
+/// Only approved quotes are listed, searched, exported or put in feeds. New
+/// quotes are pending unless whoever creates them says otherwise, so a path
+/// that forgets to decide doesn't publish anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteStatus {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl QuoteStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuoteStatus::Pending => "pending",
+            QuoteStatus::Approved => "approved",
+            QuoteStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl FromStr for QuoteStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "pending" => Ok(QuoteStatus::Pending),
+            "approved" => Ok(QuoteStatus::Approved),
+            "rejected" => Ok(QuoteStatus::Rejected),
+            other => Err(format!("unknown quote status '{}'", other)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
@@ -25,6 +117,7 @@ pub struct TagCreateDTO {
 pub struct TagDTO {
     pub id: i32,
     pub tag: String,
+    pub version: i32,
 }
 
 impl From<entity::tag::Model> for TagDTO {
@@ -32,6 +125,7 @@ impl From<entity::tag::Model> for TagDTO {
         TagDTO {
             id: item.id,
             tag: item.tag,
+            version: item.version,
         }
     }
 }
@@ -40,6 +134,7 @@ impl From<entity::tag::Model> for TagDTO {
 pub struct AuthorDTO {
     pub id: i32,
     pub name: String,
+    pub version: i32,
 }
 
 impl From<entity::author::Model> for AuthorDTO {
@@ -47,6 +142,317 @@ impl From<entity::author::Model> for AuthorDTO {
         AuthorDTO {
             id: item.id,
             name: item.name,
+            version: item.version,
+        }
+    }
+}
+
+/// What an API key may do. Scopes are ordered, and a higher scope implies the
+/// ones below it: admin keys can also moderate, moderate keys can also write,
+/// and write keys can also read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Moderate,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Moderate => "moderate",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope.trim().to_lowercase().as_str() {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "moderate" => Ok(Scope::Moderate),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyDTO {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKeyDTO {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| *granted >= scope)
+    }
+}
+
+impl From<entity::api_key::Model> for ApiKeyDTO {
+    fn from(item: entity::api_key::Model) -> Self {
+        ApiKeyDTO {
+            id: item.id,
+            name: item.name,
+            prefix: item.prefix,
+            scopes: item
+                .scopes
+                .split(',')
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            created_at: item.created_at,
+            last_used_at: item.last_used_at,
+            revoked_at: item.revoked_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserDTO {
+    pub id: i32,
+    pub username: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<entity::user::Model> for UserDTO {
+    fn from(item: entity::user::Model) -> Self {
+        UserDTO {
+            id: item.id,
+            username: item.username,
+            role: item.role.parse().unwrap_or(Role::Viewer),
+            created_at: item.created_at,
+        }
+    }
+}
+
+/// What a user may do. Like scopes, roles are ordered and each one can do
+/// everything the roles below it can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Contributor,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [
+        Role::Viewer,
+        Role::Contributor,
+        Role::Moderator,
+        Role::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Contributor => "contributor",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        *self >= permission.minimum_role()
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role.trim().to_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "contributor" => Ok(Role::Contributor),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role '{}'", other)),
+        }
+    }
+}
+
+/// API keys act with the role matching their highest scope. A write key only
+/// contributes, so what it submits waits for moderation like anyone else's.
+impl From<Scope> for Role {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Read => Role::Viewer,
+            Scope::Write => Role::Contributor,
+            Scope::Moderate => Role::Moderator,
+            Scope::Admin => Role::Admin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    SubmitQuotes,
+    EditOwnQuotes,
+    EditAnyQuote,
+    ModerateQuotes,
+    DeleteTags,
+    DeleteAuthors,
+    ManageUsers,
+    ViewAuditLog,
+}
+
+impl Permission {
+    pub fn minimum_role(&self) -> Role {
+        match self {
+            Permission::SubmitQuotes | Permission::EditOwnQuotes => Role::Contributor,
+            Permission::EditAnyQuote
+            | Permission::ModerateQuotes
+            | Permission::DeleteTags
+            | Permission::DeleteAuthors => Role::Moderator,
+            Permission::ManageUsers | Permission::ViewAuditLog => Role::Admin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+        }
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "create" => Ok(AuditAction::Create),
+            "update" => Ok(AuditAction::Update),
+            "delete" => Ok(AuditAction::Delete),
+            other => Err(format!("unknown audit action '{}'", other)),
         }
     }
 }
+
+/// One change to a quote, author, tag, user or API key. `before` is empty for
+/// creates and `after` for deletes.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntryDTO {
+    pub id: i32,
+    pub entity: String,
+    pub entity_id: i32,
+    pub action: AuditAction,
+    pub actor: String,
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<serde_json::Value>,
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<entity::audit_log::Model> for AuditEntryDTO {
+    fn from(item: entity::audit_log::Model) -> Self {
+        let parse = |json: Option<String>| json.and_then(|json| serde_json::from_str(&json).ok());
+
+        AuditEntryDTO {
+            id: item.id,
+            entity: item.entity,
+            entity_id: item.entity_id,
+            action: item.action.parse().unwrap_or(AuditAction::Update),
+            actor: item.actor,
+            before: parse(item.before),
+            after: parse(item.after),
+            request_id: item.request_id,
+            created_at: item.created_at,
+        }
+    }
+}
+
+/// How many of each thing the database holds, for monitoring.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct CountsDTO {
+    pub approved_quotes: u64,
+    pub pending_quotes: u64,
+    pub rejected_quotes: u64,
+    pub authors: u64,
+    pub tags: u64,
+}
+
+/// A request made with an `Idempotency-Key`, and its response once it has
+/// one. `status` is empty while the first attempt is still running.
+#[derive(Clone, Debug)]
+pub struct IdempotencyRecordDTO {
+    pub request_hash: String,
+    pub status: Option<u16>,
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<entity::idempotency_key::Model> for IdempotencyRecordDTO {
+    fn from(item: entity::idempotency_key::Model) -> Self {
+        IdempotencyRecordDTO {
+            request_hash: item.request_hash,
+            status: item.status.and_then(|status| u16::try_from(status).ok()),
+            content_type: item.content_type,
+            body: item.body,
+            created_at: item.created_at,
+        }
+    }
+}
+
+/// A stored quote that a new one resembles.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct DuplicateMatchDTO {
+    pub quote_id: i32,
+    /// estimated share of the text the two have in common, 0 to 1
+    pub similarity: f64,
+    /// the same text once case, punctuation and spacing are ignored
+    pub exact: bool,
+}
+
+/// Quotes that are all duplicates of one another, directly or through other
+/// members of the cluster.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct DuplicateClusterDTO {
+    pub exact: bool,
+    /// the lowest similarity among the pairs that joined the cluster
+    pub similarity: f64,
+    pub quotes: Vec<QuoteDTO>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialized_quotes_start_out_pending() {
+        let quote: QuoteCreateDTO = serde_json::from_str(
+            r#"{"quote": "Hi", "related_tags": [], "author_name": "Ann", "status": "approved"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(quote.status, QuoteStatus::Pending);
+    }
+}
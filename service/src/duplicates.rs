@@ -0,0 +1,392 @@
+use crate::data_transfer_objects::DuplicateMatchDTO;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/*
+Duplicate detection. Every quote is stored with two fingerprints of its text:
+
+- `text_hash`, a sha256 of the normalized text: case folded, curly quotes and
+  apostrophes straightened, punctuation dropped and whitespace collapsed. Two
+  quotes with the same hash are the same quote typed differently.
+- `minhash`, a MinHash signature of the 5 character shingles of the
+  normalized text. The share of slots two signatures agree on estimates how
+  many shingles the texts share (their Jaccard similarity), so a dropped word
+  or a typo still scores close to 1.
+
+To find clusters among all quotes without comparing every pair, signatures
+are cut into bands and only quotes that agree on a whole band are compared.
+*/
+
+const SIGNATURE_LENGTH: usize = 64;
+const BANDS: usize = 16;
+const ROWS_PER_BAND: usize = SIGNATURE_LENGTH / BANDS;
+const SHINGLE_LENGTH: usize = 5;
+
+/// What to do when a new quote matches one already stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAction {
+    /// save it without looking
+    Allow,
+    /// save it, flagged with the quote it resembles
+    Warn,
+    /// refuse it
+    Reject,
+}
+
+impl FromStr for DuplicateAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action.trim().to_lowercase().as_str() {
+            "allow" => Ok(DuplicateAction::Allow),
+            "warn" => Ok(DuplicateAction::Warn),
+            "reject" => Ok(DuplicateAction::Reject),
+            other => Err(format!("unknown duplicate action '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuplicatesConfig {
+    /// for quotes whose normalized text is already stored
+    pub exact: DuplicateAction,
+    /// for quotes at least `threshold` similar to a stored one
+    pub near: DuplicateAction,
+    /// estimated Jaccard similarity, 0 to 1, from which quotes count as near
+    /// duplicates
+    pub threshold: f64,
+}
+
+impl Default for DuplicatesConfig {
+    fn default() -> Self {
+        DuplicatesConfig {
+            exact: DuplicateAction::Reject,
+            near: DuplicateAction::Warn,
+            threshold: 0.8,
+        }
+    }
+}
+
+/// What the duplicate checks make of a new quote.
+#[derive(Clone, Debug)]
+pub enum DuplicateVerdict {
+    Unique,
+    /// save it, with this as its flagged reason
+    Flag(String),
+    /// refuse it; these are the quotes it duplicates, closest first
+    Reject(Vec<DuplicateMatchDTO>),
+}
+
+impl DuplicatesConfig {
+    /// Applies the configured actions to the quotes a new one matched,
+    /// closest first.
+    pub fn verdict(&self, matches: Vec<DuplicateMatchDTO>) -> DuplicateVerdict {
+        let Some(closest) = matches.first() else {
+            return DuplicateVerdict::Unique;
+        };
+
+        let action = if closest.exact { self.exact } else { self.near };
+
+        match action {
+            DuplicateAction::Allow => DuplicateVerdict::Unique,
+            DuplicateAction::Warn if closest.exact => {
+                DuplicateVerdict::Flag(format!("same text as quote {}", closest.quote_id))
+            }
+            DuplicateAction::Warn => DuplicateVerdict::Flag(format!(
+                "{:.0}% similar to quote {}",
+                closest.similarity * 100.0,
+                closest.quote_id
+            )),
+            DuplicateAction::Reject => DuplicateVerdict::Reject(matches),
+        }
+    }
+
+    /// Whether new quotes are checked at all.
+    pub fn enabled(&self) -> bool {
+        self.exact != DuplicateAction::Allow || self.near != DuplicateAction::Allow
+    }
+}
+
+/// The text with everything that doesn't change its meaning taken out.
+pub fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut pending_space = false;
+
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            // apostrophes join words: "don’t" and "dont" are the same
+            '\'' | '‘' | '’' | '‚' | '‛' | '`' | '´' => {}
+            c if c.is_alphanumeric() => {
+                if pending_space && !normalized.is_empty() {
+                    normalized.push(' ');
+                }
+                pending_space = false;
+                normalized.push(c);
+            }
+            // punctuation, curly double quotes, dashes and whitespace
+            _ => pending_space = true,
+        }
+    }
+
+    normalized
+}
+
+/// Hash of the normalized text; equal for exact duplicates.
+pub fn text_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(normalize(text).as_bytes()))
+}
+
+/// A MinHash signature of the text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature(Vec<u64>);
+
+impl Signature {
+    pub fn of(text: &str) -> Self {
+        let chars: Vec<char> = normalize(text).chars().collect();
+
+        let shingles: Vec<u64> = if chars.len() <= SHINGLE_LENGTH {
+            vec![fnv1a(&chars)]
+        } else {
+            chars.windows(SHINGLE_LENGTH).map(fnv1a).collect()
+        };
+
+        Signature(
+            (0..SIGNATURE_LENGTH as u64)
+                .map(|slot| {
+                    let seed = mix(slot.wrapping_add(1));
+                    shingles
+                        .iter()
+                        .map(|shingle| mix(shingle ^ seed))
+                        .min()
+                        .unwrap_or(u64::MAX)
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads a signature stored with `to_hex`.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != SIGNATURE_LENGTH * 16 {
+            return None;
+        }
+
+        (0..SIGNATURE_LENGTH)
+            .map(|slot| u64::from_str_radix(hex.get(slot * 16..(slot + 1) * 16)?, 16).ok())
+            .collect::<Option<Vec<_>>>()
+            .map(Signature)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0
+            .iter()
+            .map(|value| format!("{:016x}", value))
+            .collect()
+    }
+
+    /// Estimated Jaccard similarity of the two texts, 0 to 1.
+    pub fn similarity(&self, other: &Signature) -> f64 {
+        let agreeing = self.0.iter().zip(&other.0).filter(|(a, b)| a == b).count();
+        agreeing as f64 / SIGNATURE_LENGTH as f64
+    }
+
+    fn bands(&self) -> impl Iterator<Item = (usize, &[u64])> {
+        self.0.chunks(ROWS_PER_BAND).enumerate()
+    }
+}
+
+/// A group of quotes that are all duplicates of one another, directly or
+/// through other members.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// ids, lowest first
+    pub ids: Vec<i32>,
+    /// every member has the same normalized text
+    pub exact: bool,
+    /// the lowest similarity among the pairs that joined the cluster
+    pub similarity: f64,
+}
+
+/// Groups quotes, given as id, text hash and signature, into clusters of
+/// duplicates. Quotes without a match are left out.
+pub fn clusters(quotes: &[(i32, String, Signature)], threshold: f64) -> Vec<Cluster> {
+    let mut parent: Vec<usize> = (0..quotes.len()).collect();
+    let mut lowest = vec![1.0_f64; quotes.len()];
+
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut buckets: HashMap<(usize, &[u64]), Vec<usize>> = HashMap::new();
+    for (i, (_, _, signature)) in quotes.iter().enumerate() {
+        for band in signature.bands() {
+            buckets.entry(band).or_default().push(i);
+        }
+    }
+
+    let mut by_hash: HashMap<&str, usize> = HashMap::new();
+    let mut candidates: Vec<(usize, usize)> = Vec::new();
+    for (i, (_, hash, _)) in quotes.iter().enumerate() {
+        if let Some(&first) = by_hash.get(hash.as_str()) {
+            candidates.push((first, i));
+        } else {
+            by_hash.insert(hash, i);
+        }
+    }
+    for members in buckets.values().filter(|members| members.len() > 1) {
+        for (n, &a) in members.iter().enumerate() {
+            candidates.extend(members[n + 1..].iter().map(|&b| (a, b)));
+        }
+    }
+
+    for (a, b) in candidates {
+        let similarity = if quotes[a].1 == quotes[b].1 {
+            1.0
+        } else {
+            quotes[a].2.similarity(&quotes[b].2)
+        };
+        if similarity < threshold {
+            continue;
+        }
+
+        let (root_a, root_b) = (root(&mut parent, a), root(&mut parent, b));
+        if root_a == root_b {
+            continue;
+        }
+        let joined = lowest[root_a].min(lowest[root_b]).min(similarity);
+        parent[root_b] = root_a;
+        lowest[root_a] = joined;
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..quotes.len() {
+        let root = root(&mut parent, i);
+        members.entry(root).or_default().push(i);
+    }
+
+    let mut clusters: Vec<Cluster> = members
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| {
+            let exact = members.iter().all(|&i| quotes[i].1 == quotes[members[0]].1);
+            let mut ids: Vec<i32> = members.iter().map(|&i| quotes[i].0).collect();
+            ids.sort_unstable();
+
+            Cluster {
+                ids,
+                exact,
+                similarity: lowest[root],
+            }
+        })
+        .collect();
+
+    clusters.sort_by_key(|cluster| cluster.ids[0]);
+    clusters
+}
+
+// 64 bit FNV-1a; stable across builds, unlike std's hasher, so stored
+// signatures stay comparable
+fn fnv1a(chars: &[char]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for c in chars {
+        for byte in (*c as u32).to_le_bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// the splitmix64 finalizer, to make one hash function per slot
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTE: &str = "The only way to do great work is to love what you do. If you haven't found it yet, keep looking.";
+
+    fn quote(id: i32, text: &str) -> (i32, String, Signature) {
+        (id, text_hash(text), Signature::of(text))
+    }
+
+    #[test]
+    fn normalize_drops_case_punctuation_and_apostrophes() {
+        assert_eq!(
+            normalize("  “Don’t  PANIC!”—said   the\tGuide. "),
+            "dont panic said the guide"
+        );
+        assert_eq!(normalize("don't"), normalize("dont"));
+        assert_eq!(normalize("Größe"), "größe");
+        assert_eq!(normalize("?!"), "");
+    }
+
+    #[test]
+    fn exact_duplicates_share_a_hash() {
+        assert_eq!(text_hash(QUOTE), text_hash(&QUOTE.to_uppercase()));
+        assert_ne!(text_hash(QUOTE), text_hash("Keep looking."));
+    }
+
+    #[test]
+    fn signatures_estimate_similarity() {
+        let signature = Signature::of(QUOTE);
+        let typo = Signature::of(&QUOTE.replace("great", "grate"));
+        let other = Signature::of("Stay hungry, stay foolish, said the back of the catalogue.");
+
+        assert_eq!(signature.similarity(&signature), 1.0);
+        assert!(signature.similarity(&typo) >= 0.8);
+        assert!(signature.similarity(&other) < 0.2);
+    }
+
+    #[test]
+    fn signatures_survive_hex() {
+        let signature = Signature::of(QUOTE);
+
+        assert_eq!(Signature::from_hex(&signature.to_hex()), Some(signature));
+        assert_eq!(Signature::from_hex("00ff"), None);
+        assert_eq!(
+            Signature::from_hex(&"zz".repeat(SIGNATURE_LENGTH * 8)),
+            None
+        );
+    }
+
+    #[test]
+    fn clusters_join_duplicates_through_each_other() {
+        let quotes = [
+            quote(4, QUOTE),
+            quote(1, "Stay hungry, stay foolish."),
+            quote(2, &QUOTE.replace("great", "grate")),
+            quote(3, "Simplicity is the ultimate sophistication."),
+            quote(5, "stay hungry stay foolish"),
+            quote(6, &QUOTE.replace("great", "grate").replace("keep", "kept")),
+        ];
+
+        let clusters = clusters(&quotes, 0.8);
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].ids, [1, 5]);
+        assert!(clusters[0].exact);
+        assert_eq!(clusters[0].similarity, 1.0);
+        assert_eq!(clusters[1].ids, [2, 4, 6]);
+        assert!(!clusters[1].exact);
+        assert!(clusters[1].similarity >= 0.8 && clusters[1].similarity < 1.0);
+    }
+
+    #[test]
+    fn clusters_respect_the_threshold() {
+        let quotes = [quote(1, QUOTE), quote(2, &QUOTE.replace("great", "grate"))];
+
+        assert!(clusters(&quotes, 1.0).is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use super::data_transfer_objects::{QuoteCreateDTO, QuoteDTO, QuoteStatus, TagCreateDTO};
+
+/*
+source: https://github.com/shlomif/fortune-mod/blob/master/fortune-mod/util/strfile.h
+
+A fortune file is a list of entries separated by lines that only contain the
+delimiter character. strfile(1) writes a `.dat` index next to it so fortune(6)
+can seek straight to a random entry.
+
+The format has no escapes, so a quote line of nothing but `%` is written with
+one more `%` and read back with one less; a bare `%` would end the entry.
+*/
+
+pub const DELIMITER: char = '%';
+
+const STRFILE_VERSION: u32 = 2;
+
+const ANONYMOUS: &str = "anonymous";
+
+/// Parses the text of a fortune file into quotes.
+///
+/// The attribution is taken from the last line of an entry when it starts
+/// with `--` (or an em dash), e.g. `\t\t-- Mark Twain`. Entries without one
+/// are attributed to "anonymous".
+pub fn parse_fortunes(input: &str, related_tags: &[String]) -> Vec<QuoteCreateDTO> {
+    let mut quotes = Vec::new();
+    let mut entry: Vec<&str> = Vec::new();
+
+    // the trailing delimiter flushes the last entry when the file doesn't end with one
+    for line in input.lines().chain(std::iter::once("%")) {
+        if is_delimiter(line) {
+            if let Some(quote) = parse_entry(&entry, related_tags) {
+                quotes.push(quote);
+            }
+            entry.clear();
+        } else if is_escaped(line) {
+            entry.push(&line[DELIMITER.len_utf8()..]);
+        } else {
+            entry.push(line);
+        }
+    }
+
+    quotes
+}
+
+fn is_delimiter(line: &str) -> bool {
+    let mut chars = line.trim_end().chars();
+    chars.next() == Some(DELIMITER) && chars.next().is_none()
+}
+
+// `%%`, `%%%`, ...: a quote line of delimiters with one added
+fn is_escaped(line: &str) -> bool {
+    let line = line.trim_end();
+    line.len() > 1 && line.chars().all(|c| c == DELIMITER)
+}
+
+fn parse_entry(lines: &[&str], related_tags: &[String]) -> Option<QuoteCreateDTO> {
+    let mut lines: Vec<&str> = lines.to_vec();
+
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let author_name = match lines.last().map(|line| line.trim()) {
+        Some(last) if last.starts_with("--") || last.starts_with('\u{2014}') => {
+            let name = last.trim_start_matches(['-', '\u{2014}']).trim().to_owned();
+            lines.pop();
+            name
+        }
+        _ => String::new(),
+    };
+
+    let quote = lines.join("\n").trim().to_owned();
+
+    if quote.is_empty() {
+        return None;
+    }
+
+    Some(QuoteCreateDTO {
+        quote,
+        related_tags: related_tags
+            .iter()
+            .map(|tag| TagCreateDTO { tag: tag.clone() })
+            .collect(),
+        author_name: if author_name.is_empty() {
+            ANONYMOUS.to_owned()
+        } else {
+            author_name
+        },
+        source: None,
+        submitted_by: None,
+        status: QuoteStatus::Approved,
+        flagged_reason: None,
+    })
+}
+
+/// Renders quotes as a fortune file, one `%`-terminated entry per quote.
+pub fn render_fortunes(quotes: &[QuoteDTO]) -> String {
+    let mut output = String::new();
+
+    for quote in quotes {
+        for line in quote.quote.trim().lines() {
+            if is_delimiter(line) || is_escaped(line) {
+                output.push(DELIMITER);
+            }
+            output.push_str(line);
+            output.push('\n');
+        }
+        output.push_str("\t\t-- ");
+        output.push_str(&quote.author.name);
+        output.push('\n');
+        output.push(DELIMITER);
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Builds the `.dat` index that strfile(1) would generate for `fortunes`.
+///
+/// The header and the offset table are big-endian 32 bit integers, matching
+/// the layout read by fortune-mod.
+pub fn strfile_index(fortunes: &str) -> Vec<u8> {
+    let delimiter_line = format!("{}\n", DELIMITER);
+
+    let mut offsets: Vec<u32> = vec![0];
+    let mut longest: u32 = 0;
+    let mut shortest: u32 = u32::MAX;
+    let mut position: usize = 0;
+    let mut entry_start: usize = 0;
+
+    for line in fortunes.split_inclusive('\n') {
+        position += line.len();
+
+        if line == delimiter_line {
+            let length = (position - line.len() - entry_start) as u32;
+            longest = longest.max(length);
+            shortest = shortest.min(length);
+
+            offsets.push(position as u32);
+            entry_start = position;
+        }
+    }
+
+    let count = offsets.len() as u32 - 1;
+    if count == 0 {
+        shortest = 0;
+    }
+
+    let mut index = Vec::with_capacity(24 + offsets.len() * 4);
+    index.extend_from_slice(&STRFILE_VERSION.to_be_bytes());
+    index.extend_from_slice(&count.to_be_bytes());
+    index.extend_from_slice(&longest.to_be_bytes());
+    index.extend_from_slice(&shortest.to_be_bytes());
+    // flags: not randomized, not ordered, not rotated
+    index.extend_from_slice(&0u32.to_be_bytes());
+    index.extend_from_slice(&[DELIMITER as u8, 0, 0, 0]);
+
+    for offset in offsets {
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entries_with_and_without_attribution() {
+        let input = "Be yourself.\n\t\t-- Oscar Wilde\n%\nNo author here.\n%\nTwo\nlines\n\u{2014} Someone\n";
+        let quotes = parse_fortunes(input, &["wit".to_owned()]);
+
+        assert_eq!(quotes.len(), 3);
+        assert_eq!(quotes[0].quote, "Be yourself.");
+        assert_eq!(quotes[0].author_name, "Oscar Wilde");
+        assert_eq!(quotes[0].related_tags[0].tag, "wit");
+        assert_eq!(quotes[1].author_name, ANONYMOUS);
+        assert_eq!(quotes[2].quote, "Two\nlines");
+        assert_eq!(quotes[2].author_name, "Someone");
+    }
+
+    #[test]
+    fn skips_empty_entries_and_trailing_whitespace_on_delimiters() {
+        let input = "%\n\n%  \nOnly one.\n%\n\n";
+        let quotes = parse_fortunes(input, &[]);
+
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(quotes[0].quote, "Only one.");
+    }
+
+    #[test]
+    fn does_not_treat_percent_text_as_a_delimiter() {
+        let quotes = parse_fortunes("100% sure\n%%\nstill the same entry\n", &[]);
+
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(quotes[0].quote, "100% sure\n%\nstill the same entry");
+    }
+
+    fn exported(quote: &str, author: &str) -> QuoteDTO {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "quote": quote,
+            "related_tags": [],
+            "author": { "id": 1, "name": author, "version": 1 },
+            "source": null,
+            "created_at": "2026-10-19T00:00:00Z",
+            "updated_at": "2026-10-19T00:00:00Z",
+            "submitted_by": null,
+            "status": "approved",
+            "rejection_reason": null,
+            "flagged_reason": null,
+            "version": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn percent_only_lines_survive_a_round_trip() {
+        let quotes = [
+            exported("Before\n%\nafter", "Ann"),
+            exported("%%\n% \n100%", "Bob"),
+        ];
+        let fortunes = render_fortunes(&quotes);
+
+        let parsed = parse_fortunes(&fortunes, &[]);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].quote, "Before\n%\nafter");
+        assert_eq!(parsed[0].author_name, "Ann");
+        assert_eq!(parsed[1].quote, "%%\n% \n100%");
+        assert_eq!(parsed[1].author_name, "Bob");
+
+        // strfile sees the same two entries
+        assert_eq!(header(&strfile_index(&fortunes))[1], 2);
+    }
+
+    fn header(index: &[u8]) -> Vec<u32> {
+        index[..20]
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn indexes_entries_like_strfile() {
+        let fortunes = "ab\n%\nlonger\n%\n";
+        let index = strfile_index(fortunes);
+
+        // version, count, longest, shortest, flags
+        assert_eq!(header(&index), vec![STRFILE_VERSION, 2, 7, 3, 0]);
+        assert_eq!(&index[20..24], &[b'%', 0, 0, 0]);
+
+        let offsets: Vec<u32> = index[24..]
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, vec![0, 5, 14]);
+        assert_eq!(&fortunes[5..11], "longer");
+    }
+
+    #[test]
+    fn indexes_an_empty_file() {
+        let index = strfile_index("");
+
+        assert_eq!(header(&index), vec![STRFILE_VERSION, 0, 0, 0, 0]);
+        assert_eq!(index.len(), 28);
+    }
+}
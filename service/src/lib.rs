@@ -1,2 +1,9 @@
+pub mod accounts;
+pub mod anki;
+pub mod audit;
+pub mod cache;
 pub mod data_access;
 pub mod data_transfer_objects;
+pub mod duplicates;
+pub mod fortune;
+pub mod obsidian;
@@ -0,0 +1,391 @@
+use super::data_transfer_objects::QuoteDTO;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+Layout of an exported vault:
+
+    Quotes/quote-<id>.md   one note per quote with YAML front matter
+    Authors/<name>.md      index note linking every quote by the author
+    Tags/<tag>.md          index note linking every quote with the tag
+
+The paths written by the last export are kept in a manifest so that a
+re-export can remove notes for quotes, authors and tags that no longer exist
+without touching anything else in the vault. Each filter gets its own
+manifest, and a note listed by another filter's manifest is never removed, so
+exporting `--tag a` and then `--tag b` into one vault keeps both sets. Author
+and tag index notes are shared, so the manifest also records which quotes each
+filter linked from them and every export rebuilds them from all manifests.
+*/
+
+const QUOTES_FOLDER: &str = "Quotes";
+const AUTHORS_FOLDER: &str = "Authors";
+const TAGS_FOLDER: &str = "Tags";
+const MANIFEST: &str = ".quote-server-export";
+
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub written: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Writes `quotes` into the vault at `vault`, only touching files whose
+/// contents changed since the last export with the same `filter` (empty for
+/// an unfiltered export).
+pub fn export_vault(vault: &Path, filter: &str, quotes: &[QuoteDTO]) -> io::Result<ExportSummary> {
+    let mut notes: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let mut manifest = Manifest::default();
+
+    for quote in quotes {
+        let path = quote_path(quote);
+        notes.insert(path.clone(), quote_note(quote));
+        manifest.notes.insert(path);
+
+        let author_path =
+            Path::new(AUTHORS_FOLDER).join(format!("{}.md", file_name(&quote.author.name)));
+        manifest.add_to_index(author_path, &quote.author.name, quote);
+        for tag in &quote.related_tags {
+            let tag_path = Path::new(TAGS_FOLDER).join(format!("{}.md", file_name(&tag.tag)));
+            manifest.add_to_index(tag_path, &tag.tag, quote);
+        }
+    }
+
+    fs::create_dir_all(vault)?;
+
+    let manifest_name = manifest_name(filter);
+    let manifest_path = vault.join(&manifest_name);
+    let previous = read_manifest(&manifest_path);
+    let mut others: Vec<Manifest> = Vec::new();
+
+    for entry in fs::read_dir(vault)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if name.starts_with(MANIFEST) && name != manifest_name {
+            others.push(read_manifest(&entry.path()));
+        }
+    }
+
+    // index notes are shared between filters, so each one lists the quotes
+    // of every manifest that links to it, including ones this export dropped
+    let mut index_paths: BTreeSet<&PathBuf> = manifest.indexes.keys().collect();
+    index_paths.extend(
+        previous
+            .indexes
+            .keys()
+            .filter(|path| others.iter().any(|other| other.indexes.contains_key(*path))),
+    );
+
+    for path in index_paths {
+        let mut merged: Option<Index> = None;
+
+        for index in others
+            .iter()
+            .chain(std::iter::once(&manifest))
+            .filter_map(|m| m.indexes.get(path))
+        {
+            let merged = merged.get_or_insert_with(|| Index {
+                title: index.title.clone(),
+                quotes: BTreeMap::new(),
+            });
+            merged.quotes.extend(index.quotes.clone());
+        }
+
+        if let Some(merged) = merged {
+            notes.insert(path.clone(), index_note(&merged));
+        }
+    }
+
+    let mut summary = ExportSummary::default();
+
+    for (path, contents) in &notes {
+        let full_path = vault.join(path);
+
+        if fs::read_to_string(&full_path).is_ok_and(|existing| existing == *contents) {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&full_path, contents)?;
+        summary.written += 1;
+    }
+
+    for stale in previous.paths().filter(|path| {
+        !notes.contains_key(*path) && !others.iter().any(|other| other.contains(path))
+    }) {
+        match fs::remove_file(vault.join(stale)) {
+            Ok(()) => summary.removed += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    fs::write(manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+
+    Ok(summary)
+}
+
+/// What one filter's export wrote: its quote notes and, for every index
+/// note, the quotes it contributed so other filters can rebuild the note.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    notes: BTreeSet<PathBuf>,
+    indexes: BTreeMap<PathBuf, Index>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Index {
+    title: String,
+    // quote id to link text
+    quotes: BTreeMap<i32, String>,
+}
+
+impl Manifest {
+    fn add_to_index(&mut self, path: PathBuf, title: &str, quote: &QuoteDTO) {
+        self.indexes
+            .entry(path)
+            .or_insert_with(|| Index {
+                title: title.to_owned(),
+                quotes: BTreeMap::new(),
+            })
+            .quotes
+            .insert(quote.id, excerpt(&quote.quote));
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.notes.iter().chain(self.indexes.keys())
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.notes.contains(path) || self.indexes.contains_key(path)
+    }
+}
+
+fn manifest_name(filter: &str) -> String {
+    if filter.is_empty() {
+        MANIFEST.to_owned()
+    } else {
+        format!("{}.{}", MANIFEST, file_name(filter))
+    }
+}
+
+// manifests from older exports are a bare list of paths
+fn read_manifest(path: &Path) -> Manifest {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Manifest::default();
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|_| Manifest {
+        notes: contents.lines().map(PathBuf::from).collect(),
+        indexes: BTreeMap::new(),
+    })
+}
+
+fn quote_path(quote: &QuoteDTO) -> PathBuf {
+    Path::new(QUOTES_FOLDER).join(format!("quote-{}.md", quote.id))
+}
+
+fn quote_note(quote: &QuoteDTO) -> String {
+    let mut note = String::from("---\n");
+    note.push_str(&format!("id: {}\n", quote.id));
+    note.push_str(&format!("author: {}\n", yaml_string(&quote.author.name)));
+
+    if quote.related_tags.is_empty() {
+        note.push_str("tags: []\n");
+    } else {
+        note.push_str("tags:\n");
+        for tag in &quote.related_tags {
+            note.push_str(&format!("  - {}\n", yaml_string(&obsidian_tag(&tag.tag))));
+        }
+    }
+
+    match &quote.source {
+        Some(source) => note.push_str(&format!("source: {}\n", yaml_string(source))),
+        None => note.push_str("source: null\n"),
+    }
+    note.push_str(&format!("created: {}\n", quote.created_at.to_rfc3339()));
+    note.push_str(&format!("updated: {}\n", quote.updated_at.to_rfc3339()));
+    note.push_str("---\n\n");
+
+    for line in quote.quote.lines() {
+        note.push_str(&format!("> {}\n", line));
+    }
+    note.push_str(&format!(
+        ">\n> — [[{}/{}|{}]]\n",
+        AUTHORS_FOLDER,
+        file_name(&quote.author.name),
+        quote.author.name
+    ));
+
+    if !quote.related_tags.is_empty() {
+        let links: Vec<String> = quote
+            .related_tags
+            .iter()
+            .map(|tag| format!("[[{}/{}|{}]]", TAGS_FOLDER, file_name(&tag.tag), tag.tag))
+            .collect();
+        note.push_str(&format!("\nTags: {}\n", links.join(", ")));
+    }
+
+    note
+}
+
+fn index_note(index: &Index) -> String {
+    let mut note = format!("# {}\n\n", index.title);
+
+    for (id, excerpt) in &index.quotes {
+        note.push_str(&format!(
+            "- [[{}/quote-{}|{}]]\n",
+            QUOTES_FOLDER, id, excerpt
+        ));
+    }
+
+    note
+}
+
+fn excerpt(quote: &str) -> String {
+    let first_line = quote.lines().next().unwrap_or_default();
+    let mut excerpt: String = first_line
+        .chars()
+        .filter(|c| !matches!(c, '[' | ']' | '|'))
+        .take(60)
+        .collect();
+
+    if first_line.chars().count() > 60 || quote.lines().count() > 1 {
+        excerpt.push('…');
+    }
+
+    excerpt
+}
+
+// characters obsidian refuses in note names or treats specially inside links
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '*' | '"' | '\\' | '/' | '<' | '>' | ':' | '|' | '?' | '#' | '^' | '[' | ']' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn obsidian_tag(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join("-")
+}
+
+// a json string is also a valid double-quoted yaml scalar
+fn yaml_string(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| String::from("\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_transfer_objects::{AuthorDTO, QuoteStatus, TagDTO};
+    use chrono::Utc;
+
+    fn quote(id: i32, author: &str, tags: &[&str]) -> QuoteDTO {
+        QuoteDTO {
+            id,
+            quote: format!("Quote number {}", id),
+            related_tags: tags
+                .iter()
+                .enumerate()
+                .map(|(i, tag)| TagDTO {
+                    id: i as i32 + 1,
+                    tag: tag.to_string(),
+                    version: 1,
+                })
+                .collect(),
+            author: AuthorDTO {
+                id: 1,
+                name: author.to_owned(),
+                version: 1,
+            },
+            source: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            submitted_by: None,
+            status: QuoteStatus::Approved,
+            rejection_reason: None,
+            flagged_reason: None,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn re_export_only_rewrites_changed_notes_and_removes_stale_ones() {
+        let vault = tempfile::tempdir().unwrap();
+        let quotes = vec![quote(1, "Ann", &["wit"]), quote(2, "Bob", &[])];
+
+        let first = export_vault(vault.path(), "", &quotes).unwrap();
+        assert_eq!((first.written, first.unchanged, first.removed), (5, 0, 0));
+
+        let second = export_vault(vault.path(), "", &quotes[..1]).unwrap();
+        assert_eq!(
+            (second.written, second.unchanged, second.removed),
+            (0, 3, 2)
+        );
+        assert!(!vault.path().join("Quotes/quote-2.md").exists());
+        assert!(!vault.path().join("Authors/Bob.md").exists());
+    }
+
+    #[test]
+    fn exports_with_different_filters_keep_each_others_notes() {
+        let vault = tempfile::tempdir().unwrap();
+        let wit = quote(1, "Ann", &["wit"]);
+        let love = quote(2, "Ann", &["love"]);
+
+        export_vault(vault.path(), "tag=wit", std::slice::from_ref(&wit)).unwrap();
+        let summary = export_vault(vault.path(), "tag=love", std::slice::from_ref(&love)).unwrap();
+
+        assert_eq!(summary.removed, 0);
+        assert!(vault.path().join("Quotes/quote-1.md").exists());
+        assert!(vault.path().join("Quotes/quote-2.md").exists());
+
+        // the author note is shared, so dropping it from one filter keeps it
+        let summary = export_vault(vault.path(), "tag=love", &[]).unwrap();
+        assert_eq!(summary.removed, 2);
+        assert!(vault.path().join("Authors/Ann.md").exists());
+        assert!(vault.path().join("Quotes/quote-1.md").exists());
+    }
+
+    #[test]
+    fn shared_index_notes_link_the_quotes_of_every_filter() {
+        let vault = tempfile::tempdir().unwrap();
+        let wit = quote(1, "Ann", &["wit"]);
+        let love = quote(2, "Ann", &["love"]);
+        let author_note = vault.path().join("Authors/Ann.md");
+
+        export_vault(vault.path(), "tag=wit", std::slice::from_ref(&wit)).unwrap();
+        export_vault(vault.path(), "tag=love", std::slice::from_ref(&love)).unwrap();
+
+        let note = fs::read_to_string(&author_note).unwrap();
+        assert!(note.contains("Quotes/quote-1|") && note.contains("Quotes/quote-2|"));
+
+        // neither filter rewrites the other's view of the shared note
+        let again = export_vault(vault.path(), "tag=wit", std::slice::from_ref(&wit)).unwrap();
+        assert_eq!((again.written, again.unchanged, again.removed), (0, 3, 0));
+
+        export_vault(vault.path(), "tag=love", &[]).unwrap();
+        let note = fs::read_to_string(&author_note).unwrap();
+        assert!(note.contains("Quotes/quote-1|") && !note.contains("Quotes/quote-2|"));
+    }
+
+    #[test]
+    fn file_names_replace_characters_obsidian_rejects() {
+        assert_eq!(file_name("What? / Why: #1"), "What_ _ Why_ _1");
+        assert_eq!(obsidian_tag("  deep   thoughts "), "deep-thoughts");
+    }
+
+    #[test]
+    fn excerpts_are_cut_at_the_first_line() {
+        assert_eq!(excerpt("one [two]|\nthree"), "one two…");
+        assert_eq!(excerpt("short"), "short");
+    }
+}
@@ -0,0 +1,477 @@
+use api::idempotency::Idempotency;
+use api::rate_limit::{Budget, RateLimits};
+use api::spam::SpamAction;
+use api::{Features, Pagination};
+use serde::{Deserialize, Serialize};
+use service::cache::CacheConfig;
+use service::duplicates::DuplicatesConfig;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/*
+The server's settings, read in layers where each one overrides the last:
+
+1. the defaults below
+2. a TOML file: `--config`, else `$QUOTE_SERVER_CONFIG`, else
+   `quote-server.toml` in the working directory if there is one
+3. environment variables named after the setting's path, e.g.
+   `QUOTE_SERVER_SERVER_LISTEN` or `QUOTE_SERVER_RATE_LIMIT_API_WRITES`;
+   lists are comma separated
+4. command line flags, applied in main.rs
+
+`--print-config` prints the result as TOML.
+*/
+
+const ENV_PREFIX: &str = "QUOTE_SERVER";
+const CONFIG_ENV: &str = "QUOTE_SERVER_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "quote-server.toml";
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ConfigError {
+    /// could not read config file {0}: {1}
+    Read(PathBuf, std::io::Error),
+    /// could not parse config file {0}: {1}
+    Parse(PathBuf, toml::de::Error),
+    /// environment variable {0} is not a valid value: {1}
+    Env(String, String),
+    /// invalid configuration: {0}
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cors: CorsConfig,
+    pub log: LogConfig,
+    pub pagination: Pagination,
+    pub features: Features,
+    pub rate_limit: RateLimitConfig,
+    pub spam: SpamConfig,
+    pub telemetry: TelemetryConfig,
+    pub cache: CacheConfig,
+    pub idempotency: Idempotency,
+    pub duplicates: DuplicatesConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// address and port to listen on
+    pub listen: String,
+    /// quotes loaded into a new database by `--init`
+    pub seed_path: String,
+    /// seconds to let in-flight requests finish after SIGTERM or Ctrl-C
+    pub drain_timeout: u64,
+    /// the url the server is reached at, e.g. `https://quotes.example.com`,
+    /// for absolute links in feeds; empty takes the host from each request
+    pub public_url: String,
+    /// only send cookies over https; always on when `public_url` is https
+    pub secure_cookies: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:3000".to_owned(),
+            seed_path: "./static/assets/quotes.json".to_owned(),
+            drain_timeout: 30,
+            public_url: String::new(),
+            secure_cookies: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "sqlite:quote_server.db".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// origins allowed to call the server from a browser: exact
+    /// (`https://quotes.example.com`), any subdomain (`https://*.example.com`)
+    /// or `*` for any
+    pub allowed_origins: Vec<String>,
+    /// methods, or `*` for any
+    pub allowed_methods: Vec<String>,
+    /// request headers, or `*` for any
+    pub allowed_headers: Vec<String>,
+    /// let browsers send cookies; can't be combined with the `*` origin
+    pub allow_credentials: bool,
+    /// seconds a browser may cache a preflight answer; 0 leaves it to the
+    /// browser
+    pub max_age: u64,
+    /// a stricter policy for requests that change something (everything but
+    /// GET and HEAD); settings left out here come from above
+    pub writes: CorsOverrides,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_methods: vec!["*".to_owned()],
+            allowed_headers: vec!["*".to_owned()],
+            allow_credentials: false,
+            max_age: 0,
+            writes: CorsOverrides::default(),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The policy for writes: this one with the `writes` settings on top.
+    pub fn for_writes(&self) -> CorsConfig {
+        let writes = &self.writes;
+
+        CorsConfig {
+            allowed_origins: writes
+                .allowed_origins
+                .clone()
+                .unwrap_or_else(|| self.allowed_origins.clone()),
+            allowed_methods: writes
+                .allowed_methods
+                .clone()
+                .unwrap_or_else(|| self.allowed_methods.clone()),
+            allowed_headers: writes
+                .allowed_headers
+                .clone()
+                .unwrap_or_else(|| self.allowed_headers.clone()),
+            allow_credentials: writes.allow_credentials.unwrap_or(self.allow_credentials),
+            max_age: writes.max_age.unwrap_or(self.max_age),
+            writes: CorsOverrides::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_methods: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_headers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_credentials: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// a level (`info`) or `tracing` filter directives (`info,sqlx=warn`)
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            format: LogFormat::Full,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    /// one JSON object per line, with the request's span fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_lowercase().as_str() {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub html: RateLimits,
+    pub api: RateLimits,
+    /// reverse proxies whose `X-Forwarded-For` header is believed
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let budget = |requests| Budget {
+            requests,
+            period: Duration::from_secs(60),
+        };
+
+        RateLimitConfig {
+            html: RateLimits {
+                reads: budget(300),
+                writes: budget(20),
+            },
+            api: RateLimits {
+                reads: budget(600),
+                writes: budget(120),
+            },
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamConfig {
+    pub action: SpamAction,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`;
+    /// empty turns export off
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    /// which spans to export, as `tracing` filter directives
+    pub filter: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            otlp_endpoint: String::new(),
+            service_name: "quote-server".to_owned(),
+            filter: "info,service=debug,sqlx=warn".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the defaults, config file and environment. Command line flags
+    /// are applied on top by the caller.
+    pub fn load(config_file: Option<&Path>) -> Result<Config, ConfigError> {
+        let defaults = toml::Table::try_from(Config::default())
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        let mut table = defaults.clone();
+
+        let explicit = config_file
+            .map(Path::to_path_buf)
+            .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+
+        let path = match explicit {
+            Some(path) => Some(path),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+        };
+
+        if let Some(path) = path {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+            let file: toml::Table =
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+            merge(&mut table, file);
+        }
+
+        apply_env(&mut table, &defaults, ENV_PREFIX)?;
+
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.message().to_owned()))?;
+
+        if !(0.0..=1.0).contains(&config.duplicates.threshold) {
+            return Err(ConfigError::Invalid(
+                "duplicates: threshold must be between 0 and 1".to_owned(),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string_pretty(self).map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+}
+
+// file values replace defaults key by key, so a file only needs the settings
+// it changes
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// only settings that exist get a variable, and the default's type says how to
+// read the value
+fn apply_env(
+    table: &mut toml::Table,
+    defaults: &toml::Table,
+    prefix: &str,
+) -> Result<(), ConfigError> {
+    for (key, default) in defaults {
+        let name = format!("{}_{}", prefix, key.to_uppercase());
+
+        if let toml::Value::Table(defaults) = default {
+            if let Some(toml::Value::Table(table)) = table.get_mut(key) {
+                apply_env(table, defaults, &name)?;
+            }
+            continue;
+        }
+
+        let Ok(raw) = std::env::var(&name) else {
+            continue;
+        };
+
+        let invalid =
+            |expected: &str| ConfigError::Env(name.clone(), format!("expected {}", expected));
+
+        let value = match default {
+            toml::Value::Boolean(_) => match raw.trim().to_lowercase().as_str() {
+                "true" | "1" | "yes" | "on" => toml::Value::Boolean(true),
+                "false" | "0" | "no" | "off" => toml::Value::Boolean(false),
+                _ => return Err(invalid("true or false")),
+            },
+            toml::Value::Integer(_) => {
+                toml::Value::Integer(raw.trim().parse().map_err(|_| invalid("a number"))?)
+            }
+            toml::Value::Float(_) => {
+                toml::Value::Float(raw.trim().parse().map_err(|_| invalid("a number"))?)
+            }
+            toml::Value::Array(_) => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| toml::Value::String(item.to_owned()))
+                    .collect(),
+            ),
+            _ => toml::Value::String(raw),
+        };
+
+        table.insert(key.clone(), value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> toml::Table {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn merge_replaces_only_the_keys_the_overlay_sets() {
+        let mut base = table(
+            r#"
+            [server]
+            listen = "0.0.0.0:3000"
+            drain_timeout = 30
+            "#,
+        );
+
+        merge(&mut base, table("[server]\nlisten = \"127.0.0.1:8080\""));
+
+        assert_eq!(
+            base,
+            table(
+                r#"
+                [server]
+                listen = "127.0.0.1:8080"
+                drain_timeout = 30
+                "#,
+            )
+        );
+    }
+
+    // each test uses its own prefix, since tests share the environment
+    #[test]
+    fn env_values_are_read_as_the_default_type() {
+        let defaults = table(
+            r#"
+            [limits]
+            enabled = true
+            size = 10
+            threshold = 0.5
+            origins = ["*"]
+            name = "quotes"
+            "#,
+        );
+        let mut values = defaults.clone();
+
+        std::env::set_var("QS_TEST_TYPES_LIMITS_ENABLED", "off");
+        std::env::set_var("QS_TEST_TYPES_LIMITS_SIZE", " 20 ");
+        std::env::set_var("QS_TEST_TYPES_LIMITS_THRESHOLD", "0.9");
+        std::env::set_var(
+            "QS_TEST_TYPES_LIMITS_ORIGINS",
+            "https://a.example, https://b.example,",
+        );
+        apply_env(&mut values, &defaults, "QS_TEST_TYPES").unwrap();
+
+        assert_eq!(
+            values,
+            table(
+                r#"
+                [limits]
+                enabled = false
+                size = 20
+                threshold = 0.9
+                origins = ["https://a.example", "https://b.example"]
+                name = "quotes"
+                "#,
+            )
+        );
+    }
+
+    #[test]
+    fn env_values_of_the_wrong_type_name_the_variable() {
+        let defaults = table("[duplicates]\nthreshold = 0.5");
+        let mut values = defaults.clone();
+
+        std::env::set_var("QS_TEST_INVALID_DUPLICATES_THRESHOLD", "high");
+        let error = apply_env(&mut values, &defaults, "QS_TEST_INVALID").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "environment variable QS_TEST_INVALID_DUPLICATES_THRESHOLD is not a valid value: expected a number"
+        );
+    }
+
+    #[test]
+    fn the_defaults_survive_a_round_trip() {
+        let config = Config::default();
+        let parsed: Config = toml::from_str(&config.to_toml().unwrap()).unwrap();
+
+        assert_eq!(parsed.server.listen, config.server.listen);
+        assert_eq!(parsed.duplicates.threshold, config.duplicates.threshold);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::config::{ConfigError, CorsConfig};
+use axum::extract::Request;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use axum::response::Response;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, Cors, CorsLayer};
+
+/*
+CORS from the `[cors]` configuration. Reads and writes can have different
+policies, so a site can be allowed to show quotes without being allowed to
+post them: each request goes to one of two `CorsLayer`s by its method, or for
+a preflight by the method it asks about.
+
+Browsers still send simple cross-origin form posts without asking first;
+the write policy only stops the page from reading the answer. The CSRF check
+is what keeps those from changing anything.
+*/
+
+/// Builds the CORS layer for one policy.
+pub fn layer(config: &CorsConfig) -> Result<CorsLayer, ConfigError> {
+    let wildcard = |items: &[String]| items.iter().any(|item| item == "*");
+
+    let allow_origin = if wildcard(&config.allowed_origins) {
+        if config.allow_credentials {
+            return Err(ConfigError::Invalid(
+                "cors: credentials can't be allowed for every origin".to_owned(),
+            ));
+        }
+        AllowOrigin::from(Any)
+    } else {
+        let patterns = config
+            .allowed_origins
+            .iter()
+            .map(|origin| OriginPattern::parse(origin))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            let Ok(origin) = origin.to_str() else {
+                return false;
+            };
+            patterns.iter().any(|pattern| pattern.matches(origin))
+        })
+    };
+
+    // browsers don't accept `*` on a response with credentials, so echo
+    // what was asked for instead
+    let allow_methods = match (wildcard(&config.allowed_methods), config.allow_credentials) {
+        (true, false) => AllowMethods::from(Any),
+        (true, true) => AllowMethods::mirror_request(),
+        (false, _) => AllowMethods::list(
+            config
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    method.trim().to_uppercase().parse::<Method>().map_err(|_| {
+                        ConfigError::Invalid(format!("cors: '{}' isn't a method", method))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    let allow_headers = match (wildcard(&config.allowed_headers), config.allow_credentials) {
+        (true, false) => AllowHeaders::from(Any),
+        (true, true) => AllowHeaders::mirror_request(),
+        (false, _) => AllowHeaders::list(
+            config
+                .allowed_headers
+                .iter()
+                .map(|name| {
+                    name.trim().parse::<HeaderName>().map_err(|_| {
+                        ConfigError::Invalid(format!("cors: '{}' isn't a header name", name))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers)
+        .allow_credentials(config.allow_credentials);
+
+    if config.max_age > 0 {
+        layer = layer.max_age(Duration::from_secs(config.max_age));
+    }
+
+    Ok(layer)
+}
+
+/// Wraps `service` in the read policy for GET and HEAD requests and the
+/// write policy for everything else.
+pub fn by_method<S: Clone>(reads: CorsLayer, writes: CorsLayer, service: S) -> ByMethod<S> {
+    ByMethod {
+        reads: reads.layer(service.clone()),
+        writes: writes.layer(service),
+    }
+}
+
+/// The service `by_method` builds.
+#[derive(Clone)]
+pub struct ByMethod<S> {
+    reads: Cors<S>,
+    writes: Cors<S>,
+}
+
+impl<S, B> Service<Request> for ByMethod<S>
+where
+    S: Service<Request, Response = Response<B>>,
+    B: Default,
+{
+    type Response = <Cors<S> as Service<Request>>::Response;
+    type Error = S::Error;
+    type Future = <Cors<S> as Service<Request>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.reads.poll_ready(cx))?;
+        self.writes.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if is_read(&request) {
+            self.reads.call(request)
+        } else {
+            self.writes.call(request)
+        }
+    }
+}
+
+fn is_read(request: &Request) -> bool {
+    let preflight_method = request
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .filter(|_| request.method() == Method::OPTIONS)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+
+    matches!(
+        preflight_method.as_ref().unwrap_or(request.method()),
+        &Method::GET | &Method::HEAD | &Method::OPTIONS
+    )
+}
+
+/// An allowed origin: exact, or with `*.` standing for any subdomains.
+enum OriginPattern {
+    Exact(String),
+    Subdomains { scheme: String, domain: String },
+}
+
+impl OriginPattern {
+    fn parse(origin: &str) -> Result<Self, ConfigError> {
+        let origin = origin.trim().trim_end_matches('/').to_lowercase();
+
+        let (scheme, host) = origin.split_once("://").ok_or_else(|| {
+            ConfigError::Invalid(format!(
+                "cors: origin '{}' needs a scheme, like https://",
+                origin
+            ))
+        })?;
+
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                Ok(OriginPattern::Subdomains {
+                    scheme: scheme.to_owned(),
+                    domain: domain.to_owned(),
+                })
+            }
+            None if !host.is_empty() && !host.contains('*') => Ok(OriginPattern::Exact(origin)),
+            _ => Err(ConfigError::Invalid(format!(
+                "cors: '{}' isn't an origin or a `scheme://*.domain` pattern",
+                origin
+            ))),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Exact(allowed) => origin.eq_ignore_ascii_case(allowed),
+            OriginPattern::Subdomains { scheme, domain } => {
+                let origin = origin.to_lowercase();
+                let Some(host) = origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|rest| rest.strip_prefix("://"))
+                else {
+                    return false;
+                };
+                // `https://*.example.com` takes `https://a.b.example.com` but
+                // not `https://example.com` or `https://badexample.com`
+                host.strip_suffix(domain.as_str())
+                    .and_then(|subdomain| subdomain.strip_suffix('.'))
+                    .is_some_and(|subdomain| {
+                        !subdomain.is_empty()
+                            && subdomain
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                    })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(origin: &str) -> OriginPattern {
+        OriginPattern::parse(origin).unwrap()
+    }
+
+    #[test]
+    fn subdomain_patterns_take_only_real_subdomains() {
+        let pattern = pattern("https://*.example.com");
+
+        assert!(pattern.matches("https://a.example.com"));
+        assert!(pattern.matches("https://a.b.example.com"));
+        assert!(pattern.matches("HTTPS://A.Example.COM"));
+
+        assert!(!pattern.matches("https://example.com"));
+        assert!(!pattern.matches("https://badexample.com"));
+        assert!(!pattern.matches("https://a.badexample.com"));
+        assert!(!pattern.matches("https://example.com.evil.org"));
+        assert!(!pattern.matches("https://evil.org/.example.com"));
+        assert!(!pattern.matches("http://a.example.com"));
+    }
+
+    #[test]
+    fn exact_origins_ignore_case_and_a_trailing_slash_in_the_config() {
+        let pattern = pattern("https://Quotes.Example.com/");
+
+        assert!(pattern.matches("https://quotes.example.com"));
+        assert!(!pattern.matches("https://quotes.example.com:8443"));
+        assert!(!pattern.matches("https://www.quotes.example.com"));
+    }
+
+    #[test]
+    fn malformed_origins_are_refused() {
+        for origin in [
+            "example.com",
+            "https://",
+            "https://*.",
+            "https://a.*.example.com",
+            "https://*example.com",
+        ] {
+            assert!(OriginPattern::parse(origin).is_err(), "{}", origin);
+        }
+    }
+}
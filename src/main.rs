@@ -1,24 +1,147 @@
+use api::feeds::PublicUrl;
+use api::rate_limit::{Budget, RateLimiter};
+use api::spam::{SpamAction, SpamGuard};
+use axum::extract::Request;
+use axum::ServiceExt;
 use clap::Parser;
+use config::{Config, LogFormat};
 use migration::MigratorTrait;
-use sea_orm::Database;
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbBackend};
+use service::audit::AuditContext;
 use service::data_access::DataAccess;
-use service::data_transfer_objects::QuoteCreateDTO;
+use service::data_transfer_objects::{QuoteCreateDTO, QuoteDTO, QuoteStatus, Role, Scope};
+use service::duplicates::{DuplicateVerdict, DuplicatesConfig};
+use service::{fortune, obsidian};
 use std::fs::File;
+use std::future::IntoFuture;
 use std::io::BufReader;
-use tower_http::cors::{Any, CorsLayer};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tower::Layer;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+mod config;
+mod cors;
+mod telemetry;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// The path to the database file
-    #[arg(short, long, default_value = "quote_server.db")]
-    db_path: String,
+    /// Configuration file to read (default: `quote-server.toml` if it exists)
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// The path to the SQLite database file
+    #[arg(short, long, conflicts_with = "database_url")]
+    db_path: Option<String>,
+
+    /// Database URL, e.g. `sqlite:quote_server.db`
+    #[arg(long, value_name = "URL")]
+    database_url: Option<String>,
+
+    /// Address and port to listen on, e.g. `127.0.0.1:8080`
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<String>,
+
+    /// Seconds to let in-flight requests finish when shutting down
+    #[arg(long, value_name = "SECONDS")]
+    drain_timeout: Option<u64>,
+
+    /// Origin allowed to call the server from a browser, or `*` for any
+    #[arg(long, value_name = "ORIGIN", value_delimiter = ',')]
+    cors_origin: Vec<String>,
+
+    /// Log level or filter directives, e.g. `debug` or `info,sqlx=warn`
+    #[arg(long, value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// Log output format: `full`, `compact`, `pretty` or `json`
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+
+    /// Export traces to this OTLP/HTTP endpoint, e.g. `http://localhost:4318/v1/traces`
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
 
     /// Whether to initialize the database
     #[arg(short, long)]
     init: bool,
+
+    /// Import quotes from a fortune(6) file and exit
+    #[arg(long, value_name = "FILE")]
+    import_fortune: Option<String>,
+
+    /// Export quotes to a fortune(6) file (plus its strfile `.dat` index) and exit
+    #[arg(long, value_name = "FILE")]
+    export_fortune: Option<String>,
+
+    /// Export quotes into an Obsidian vault directory and exit
+    #[arg(long, value_name = "DIR")]
+    export_obsidian: Option<String>,
+
+    /// Create an API key with this name, print it and exit
+    #[arg(long, value_name = "NAME")]
+    create_api_key: Option<String>,
+
+    /// Comma separated scopes (read, write, moderate, admin) for the created API key
+    #[arg(long, value_delimiter = ',', default_value = "write")]
+    scopes: Vec<Scope>,
+
+    /// Revoke the API key with this id and exit
+    #[arg(long, value_name = "ID")]
+    revoke_api_key: Option<i32>,
+
+    /// List API keys and exit
+    #[arg(long)]
+    list_api_keys: bool,
+
+    /// Give the user with this username the role from `--role` and exit
+    #[arg(long, value_name = "USERNAME")]
+    set_role: Option<String>,
+
+    /// Role (viewer, contributor, moderator, admin) for `--set-role`
+    #[arg(long, default_value = "admin")]
+    role: Role,
+
+    /// Tag to filter exports by, or to add to imported quotes
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// Author to filter exports by
+    #[arg(long)]
+    author: Option<String>,
+
+    /// Reads each client may make from the html pages, e.g. `300/min`
+    #[arg(long)]
+    html_read_limit: Option<Budget>,
+
+    /// Form submissions each client may make, e.g. `20/min`
+    #[arg(long)]
+    html_write_limit: Option<Budget>,
+
+    /// Reads each API key or client may make from `/api`
+    #[arg(long)]
+    api_read_limit: Option<Budget>,
+
+    /// Writes each API key or client may make to `/api`
+    #[arg(long)]
+    api_write_limit: Option<Budget>,
+
+    /// What to do with quote form submissions that look like spam: `moderate` or `reject`
+    #[arg(long)]
+    spam_action: Option<SpamAction>,
+
+    /// Address of a reverse proxy whose `X-Forwarded-For` header is trusted
+    #[arg(long, value_name = "IP", value_delimiter = ',')]
+    trusted_proxy: Vec<IpAddr>,
 }
 
 fn read_quotes_from_file(
@@ -26,59 +149,338 @@ fn read_quotes_from_file(
 ) -> Result<Vec<QuoteCreateDTO>, Box<dyn std::error::Error>> {
     let file = File::open(file_path)?;
     let reader = BufReader::new(file);
-    let quotes: Vec<QuoteCreateDTO> = serde_json::from_reader(reader)?;
+    let mut quotes: Vec<QuoteCreateDTO> = serde_json::from_reader(reader)?;
+    // whoever runs the server vouches for its seed quotes
+    for quote in &mut quotes {
+        quote.status = QuoteStatus::Approved;
+    }
     Ok(quotes)
 }
 
+// the command line imports skip the quotes the duplicate checks refuse, so
+// running `--init` twice doesn't store everything twice
+async fn import_quote(
+    db: &DatabaseConnection,
+    audit: &AuditContext,
+    duplicates: &DuplicatesConfig,
+    mut quote: QuoteCreateDTO,
+) -> Result<Option<QuoteDTO>, sea_orm::DbErr> {
+    match DataAccess::check_duplicates(db, duplicates, &quote.quote).await? {
+        DuplicateVerdict::Unique => {}
+        DuplicateVerdict::Flag(reason) => quote.flagged_reason = Some(reason),
+        DuplicateVerdict::Reject(matches) => {
+            let ids: Vec<String> = matches.iter().map(|m| m.quote_id.to_string()).collect();
+            println!(
+                "Skipped duplicate of quote {}: {}",
+                ids.join(", "),
+                quote.quote
+            );
+            return Ok(None);
+        }
+    }
+
+    DataAccess::create_quote(db, audit, quote).await.map(Some)
+}
+
+// resolves on Ctrl-C, or SIGTERM from `docker stop` and friends
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("could not listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Fold the write-ahead log back into the database file, so nothing is left
+// in `-wal` for the next start to replay, then close the pool.
+async fn close_database(db: DatabaseConnection) -> Result<(), sea_orm::DbErr> {
+    if db.get_database_backend() == DbBackend::Sqlite {
+        db.execute_unprepared("PRAGMA wal_checkpoint(TRUNCATE)")
+            .await?;
+    }
+    db.close().await
+}
+
+// the config file and environment, with any flags given on top
+fn load_config(args: &Args) -> Result<Config, config::ConfigError> {
+    let mut config = Config::load(args.config.as_deref())?;
+
+    if let Some(path) = &args.db_path {
+        config.database.url = format!("sqlite:{}", path);
+    }
+    if let Some(url) = &args.database_url {
+        config.database.url = url.clone();
+    }
+    if let Some(listen) = &args.listen {
+        config.server.listen = listen.clone();
+    }
+    if let Some(seconds) = args.drain_timeout {
+        config.server.drain_timeout = seconds;
+    }
+    if !args.cors_origin.is_empty() {
+        config.cors.allowed_origins = args.cors_origin.clone();
+    }
+    if let Some(level) = &args.log_level {
+        config.log.level = level.clone();
+    }
+    if let Some(format) = args.log_format {
+        config.log.format = format;
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        config.telemetry.otlp_endpoint = endpoint.clone();
+    }
+    if let Some(budget) = args.html_read_limit {
+        config.rate_limit.html.reads = budget;
+    }
+    if let Some(budget) = args.html_write_limit {
+        config.rate_limit.html.writes = budget;
+    }
+    if let Some(budget) = args.api_read_limit {
+        config.rate_limit.api.reads = budget;
+    }
+    if let Some(budget) = args.api_write_limit {
+        config.rate_limit.api.writes = budget;
+    }
+    if !args.trusted_proxy.is_empty() {
+        config.rate_limit.trusted_proxies = args.trusted_proxy.clone();
+    }
+    if let Some(action) = args.spam_action {
+        config.spam.action = action;
+    }
+
+    Ok(config)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Deal with the Arguments
     let args = Args::parse();
+    let config = load_config(&args)?;
+
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
 
     // Deal with Database Connection
-    let db = Database::connect(format!("sqlite:{}", args.db_path)).await?;
+    let db = Database::connect(&config.database.url).await?;
+    let audit = AuditContext::cli();
 
     if args.init {
         migration::Migrator::up(&db, None).await?;
         println!("Database migration completed successfully.");
-        let quotes = read_quotes_from_file("./static/assets/quotes.json")?;
+        DataAccess::fingerprint_quotes(&db).await?;
+        let quotes = read_quotes_from_file(&config.server.seed_path)?;
         for quote in quotes {
-            let quote_dto = DataAccess::create_quote(&db, quote).await?;
-            println!("Created quote: {:?}", quote_dto);
+            if let Some(quote_dto) = import_quote(&db, &audit, &config.duplicates, quote).await? {
+                println!("Created quote: {:?}", quote_dto);
+            }
         }
     }
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any) // allow all origins (good for dev only!)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    if let Some(name) = &args.create_api_key {
+        let (api_key, key) =
+            DataAccess::create_api_key(&db, &audit, name.clone(), args.scopes).await?;
+        println!("Created API key {} ({}): {}", api_key.id, api_key.name, key);
+        println!("Store it now, it can't be shown again.");
+        return Ok(());
+    }
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_target(false) // optional: cleaner output
-        .with_thread_ids(true) // optional: extra context
-        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE) // to log spans on exit
-        .init();
+    if let Some(id) = args.revoke_api_key {
+        match DataAccess::revoke_api_key(&db, &audit, id).await? {
+            Some(api_key) => println!("Revoked API key {} ({})", api_key.id, api_key.name),
+            None => println!("No API key with id {}", id),
+        }
+        return Ok(());
+    }
 
-    let state = api::AppState::new(db);
+    if args.list_api_keys {
+        for api_key in DataAccess::get_api_keys(&db).await? {
+            let scopes: Vec<&str> = api_key.scopes.iter().map(Scope::as_str).collect();
+            println!(
+                "{}\t{}\t{}…\t{}\tlast used: {}{}",
+                api_key.id,
+                api_key.name,
+                api_key.prefix,
+                scopes.join(","),
+                api_key
+                    .last_used_at
+                    .map_or("never".to_owned(), |used| used.to_rfc3339()),
+                if api_key.revoked_at.is_some() {
+                    "\trevoked"
+                } else {
+                    ""
+                }
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(username) = &args.set_role {
+        let updated = match DataAccess::get_user_by_username(&db, username).await? {
+            Some(user) => DataAccess::set_user_role(&db, &audit, user.id, args.role).await?,
+            None => None,
+        };
+        match updated {
+            Some(user) => println!("{} is now {}", user.username, user.role.as_str()),
+            None => println!("No user named {}", username),
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &args.import_fortune {
+        let contents = std::fs::read_to_string(path)?;
+        let tags: Vec<String> = args.tag.iter().cloned().collect();
+        DataAccess::fingerprint_quotes(&db).await?;
+        for quote in fortune::parse_fortunes(&contents, &tags) {
+            if let Some(quote_dto) = import_quote(&db, &audit, &config.duplicates, quote).await? {
+                println!("Imported quote: {:?}", quote_dto);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &args.export_fortune {
+        let quotes =
+            DataAccess::get_quotes_filtered(&db, args.tag.clone(), args.author.clone()).await?;
+        let contents = fortune::render_fortunes(&quotes);
+        std::fs::write(path, &contents)?;
+        std::fs::write(format!("{}.dat", path), fortune::strfile_index(&contents))?;
+        println!("Exported {} quotes to {}", quotes.len(), path);
+        return Ok(());
+    }
+
+    if let Some(path) = &args.export_obsidian {
+        let quotes =
+            DataAccess::get_quotes_filtered(&db, args.tag.clone(), args.author.clone()).await?;
+        let filter = [("tag", &args.tag), ("author", &args.author)]
+            .iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let summary = obsidian::export_vault(std::path::Path::new(path), &filter, &quotes)?;
+        println!(
+            "Exported {} quotes to {}: {} files written, {} unchanged, {} removed",
+            quotes.len(),
+            path,
+            summary.written,
+            summary.unchanged,
+            summary.removed
+        );
+        return Ok(());
+    }
+
+    let read_cors = cors::layer(&config.cors)?;
+    let write_cors = cors::layer(&config.cors.for_writes())?;
+
+    let tracer_provider = telemetry::init(&config.log, &config.telemetry)?;
+
+    // only the server caches reads; the commands above run once and exit
+    service::cache::configure(&config.cache);
+
+    let fingerprinted = DataAccess::fingerprint_quotes(&db).await?;
+    if fingerprinted > 0 {
+        tracing::info!(
+            fingerprinted,
+            "fingerprinted quotes for duplicate detection"
+        );
+    }
+
+    let state = api::AppState::new(
+        db.clone(),
+        SpamGuard::new(config.spam.action),
+        config.pagination,
+        config.features,
+        config.idempotency,
+        config.duplicates,
+        PublicUrl::new(
+            &config.server.public_url,
+            config.rate_limit.trusted_proxies.clone(),
+            config.server.secure_cookies,
+        ),
+    );
 
     let doc = api::json::ApiDoc::openapi();
 
-    let json_router = api::json_router();
-    let template_router = api::template_router();
-    let app = axum::Router::new()
+    let trusted_proxies = config.rate_limit.trusted_proxies.clone();
+    let html_limiter = RateLimiter::new(config.rate_limit.html, trusted_proxies.clone());
+    let api_limiter = RateLimiter::new(config.rate_limit.api, trusted_proxies);
+
+    let json_router = api::json_router(state.clone(), api_limiter);
+    let template_router = api::template_router(state.clone(), html_limiter);
+    let mut app = axum::Router::new()
         .merge(template_router)
         .nest("/api", json_router)
-        .merge(SwaggerUi::new("/swagger-ui").url("/api/openapi.json", doc))
+        .merge(api::health_router());
+    if config.features.swagger_ui {
+        app = app.merge(SwaggerUi::new("/swagger-ui").url("/api/openapi.json", doc));
+    }
+    let app: axum::Router = app
         .with_state(state)
-        .layer(cors);
-
-    let addr = "0.0.0.0:3000";
+        .layer(axum::middleware::from_fn(api::logging::assign_request_id));
+    let app = cors::by_method(read_cors, write_cors, app);
+    // outside the router, so the overridden method is the one that gets routed
+    let app = axum::middleware::from_fn(api::csrf::method_override).layer(app);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let listener = tokio::net::TcpListener::bind(&config.server.listen).await?;
     println!("Listening on {}", listener.local_addr()?);
 
-    axum::serve(listener, app).await?;
+    // the rate limiter keys anonymous clients by their address
+    let draining = Arc::new(Notify::new());
+    let server = axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+    )
+    .with_graceful_shutdown({
+        let draining = draining.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::info!("shutting down, waiting for in-flight requests");
+            draining.notify_one();
+        }
+    });
+
+    // stop accepting connections on a signal, then give open ones
+    // `drain_timeout` to finish before closing the database under them
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout);
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => tracing::warn!("requests still open after {}s, closing anyway", drain_timeout.as_secs()),
+    }
+
+    close_database(db).await?;
+    if let Some(provider) = tracer_provider {
+        // flushes spans still waiting in the batch
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("could not flush traces: {}", e);
+        }
+    }
+    tracing::info!("shut down cleanly");
 
     Ok(())
 }
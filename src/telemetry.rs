@@ -0,0 +1,198 @@
+use crate::config::{LogConfig, LogFormat, TelemetryConfig};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/*
+Sets up logging and, when an OTLP endpoint is configured, trace export. The
+two have their own filters: the log level keeps the logs readable, while the
+exported traces also take the debug level `DataAccess` spans.
+*/
+
+/// Installs the global subscriber. The returned provider has to be shut down
+/// before exiting to send the last spans.
+pub fn init(
+    log: &LogConfig,
+    telemetry: &TelemetryConfig,
+) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_target(false) // optional: cleaner output
+        .with_thread_ids(true) // optional: extra context
+        .with_span_events(FmtSpan::CLOSE); // to log spans on exit
+    let fmt = match log.format {
+        LogFormat::Full => fmt.boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Json => fmt
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    let fmt = fmt.with_filter(EnvFilter::try_new(&log.level)?);
+
+    // read from incoming requests even when nothing is exported, so the
+    // trace id can still be passed on
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    if telemetry.otlp_endpoint.is_empty() {
+        tracing_subscriber::registry().with(fmt).init();
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&telemetry.otlp_endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(telemetry.service_name.clone())
+                .build(),
+        )
+        .build();
+    opentelemetry::global::set_tracer_provider(provider.clone());
+
+    let otel = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("quote-server"))
+        .with_filter(EnvFilter::try_new(&telemetry.filter)?);
+
+    tracing_subscriber::registry().with(fmt).with(otel).init();
+
+    Ok(Some(provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogFormat;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use opentelemetry_proto::tonic::trace::v1::Span;
+    use prost::Message;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+    use tower_http::trace::MakeSpan;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    // an OTLP/HTTP endpoint that answers every export with 200 and hands
+    // over the spans in it
+    fn collector() -> (String, Receiver<Vec<Span>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+
+                let export = ExportTraceServiceRequest::decode(body.as_slice()).unwrap();
+                let spans = export
+                    .resource_spans
+                    .into_iter()
+                    .flat_map(|resource| resource.scope_spans)
+                    .flat_map(|scope| scope.spans)
+                    .collect();
+                if sender.send(spans).is_err() {
+                    return;
+                }
+            }
+        });
+
+        (endpoint, receiver)
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // the subscriber is global, so this is the one test that installs it
+    #[test]
+    fn request_spans_reach_the_collector_under_the_callers_trace() {
+        let (endpoint, exports) = collector();
+        let provider = init(
+            &LogConfig {
+                level: "off".to_owned(),
+                format: LogFormat::Compact,
+            },
+            &TelemetryConfig {
+                otlp_endpoint: endpoint,
+                service_name: "quote-server-test".to_owned(),
+                filter: "info".to_owned(),
+            },
+        )
+        .unwrap()
+        .expect("a provider when an endpoint is set");
+
+        let requests = [
+            ("/joined", format!("00-{}-{}-01", TRACE_ID, PARENT_ID)),
+            (
+                "/malformed",
+                format!("00-{}-{}-01", &TRACE_ID[..30], PARENT_ID),
+            ),
+        ];
+        for (path, traceparent) in &requests {
+            let request = Request::get(*path)
+                .header("traceparent", traceparent)
+                .body(Body::empty())
+                .unwrap();
+            // closed right away, which ends it
+            api::logging::RequestSpan.make_span(&request);
+        }
+        provider.force_flush().unwrap();
+
+        let mut spans = Vec::new();
+        while spans.len() < requests.len() {
+            spans.extend(exports.recv_timeout(Duration::from_secs(10)).unwrap());
+        }
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("no span named {}", name))
+        };
+
+        let joined = span("GET /joined");
+        assert_eq!(hex(&joined.trace_id), TRACE_ID);
+        assert_eq!(hex(&joined.parent_span_id), PARENT_ID);
+
+        let malformed = span("GET /malformed");
+        assert_ne!(hex(&malformed.trace_id), TRACE_ID);
+        assert!(malformed.parent_span_id.is_empty());
+
+        provider.shutdown().unwrap();
+    }
+}